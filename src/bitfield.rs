@@ -0,0 +1,87 @@
+// Set of piece indices, laid out the way the `bitfield` message sends it:
+// the high bit of the first byte is piece 0.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bitfield {
+    bytes: Vec<u8>,
+    len: u32,
+}
+
+impl Bitfield {
+    pub fn new(len: u32) -> Bitfield {
+        Bitfield {
+            bytes: vec![0; ((len + 7) / 8) as usize],
+            len: len,
+        }
+    }
+
+    pub fn full(len: u32) -> Bitfield {
+        let mut bf = Bitfield::new(len);
+        for i in 0..len {
+            bf.set(i);
+        }
+        bf
+    }
+
+    // Errors if `bytes` is the wrong length or has spare bits set
+    pub fn from_bytes(bytes: &[u8], len: u32) -> Result<Bitfield, String> {
+        let bf = Bitfield {
+            bytes: bytes.to_vec(),
+            len: len,
+        };
+
+        if bytes.len() != ((len + 7) / 8) as usize {
+            return Err(format!("Bitfield has {} bytes, expected {}",
+                               bytes.len(), (len + 7) / 8));
+        }
+
+        for i in len..(bytes.len() as u32 * 8) {
+            if bf.bit(i) {
+                return Err(String::from("Bitfield has spare bits set"));
+            }
+        }
+
+        Ok(bf)
+    }
+
+    pub fn len(&self) -> u32 {
+        self.len
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..]
+    }
+
+    fn bit(&self, i: u32) -> bool {
+        self.bytes[(i / 8) as usize] & (0x80 >> (i % 8)) != 0
+    }
+
+    pub fn has(&self, i: u32) -> bool {
+        i < self.len && self.bit(i)
+    }
+
+    pub fn set(&mut self, i: u32) {
+        assert!(i < self.len);
+        self.bytes[(i / 8) as usize] |= 0x80 >> (i % 8);
+    }
+
+    pub fn unset(&mut self, i: u32) {
+        assert!(i < self.len);
+        self.bytes[(i / 8) as usize] &= !(0x80 >> (i % 8));
+    }
+
+    pub fn count(&self) -> u32 {
+        self.bytes.iter().map(|b| b.count_ones()).fold(0, |a, b| a + b)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.iter().all(|&b| b == 0)
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.count() == self.len
+    }
+
+    pub fn iter_set<'a>(&'a self) -> Box<Iterator<Item=u32> + 'a> {
+        Box::new((0..self.len).filter(move |&i| self.bit(i)))
+    }
+}
@@ -0,0 +1,144 @@
+// Making .torrent files from files on disk.
+
+use extension;
+use util;
+
+use bencode::Bencode;
+use openssl::crypto::hash as openssl_hash;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use time;
+
+pub const DEFAULT_PIECE_LENGTH: u32 = 1 << 18;
+
+// What goes in a new torrent besides the files themselves
+pub struct TorrentOptions {
+    pub piece_length: u32,
+
+    // each tracker gets its own tier; the first is also `announce`
+    pub trackers: Vec<String>,
+
+    // BEP 19 web seeds
+    pub web_seeds: Vec<String>,
+
+    pub private: bool,
+}
+
+impl TorrentOptions {
+    pub fn new() -> TorrentOptions {
+        TorrentOptions {
+            piece_length: DEFAULT_PIECE_LENGTH,
+            trackers: Vec::new(),
+            web_seeds: Vec::new(),
+            private: false,
+        }
+    }
+}
+
+fn to_io_error<E: ::std::fmt::Debug>(e: E) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, format!("{:?}", e))
+}
+
+fn bytes(s: &str) -> Bencode {
+    Bencode::ByteString(s.as_bytes().to_vec())
+}
+
+// Every file under `dir`, sorted so that the layout doesn't depend on the
+// order the filesystem lists them in
+fn walk(dir: &Path, files: &mut Vec<PathBuf>) -> Result<(), io::Error> {
+    let mut entries = Vec::new();
+    for entry in try!(fs::read_dir(dir)) {
+        entries.push(try!(entry).path());
+    }
+    entries.sort();
+    for path in entries {
+        if try!(fs::metadata(&path)).is_dir() {
+            try!(walk(&path, files));
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+// Hashes `files` as one stream, split into pieces
+fn hash_pieces(files: &[PathBuf], piece_length: u32) -> Result<Vec<u8>, io::Error> {
+    let mut pieces = Vec::new();
+    let mut buf = Vec::with_capacity(piece_length as usize);
+    for path in files.iter() {
+        let mut file = try!(File::open(path));
+        loop {
+            let want = piece_length as u64 - buf.len() as u64;
+            let n = try!(file.by_ref().take(want).read_to_end(&mut buf));
+            if buf.len() == piece_length as usize {
+                pieces.extend(openssl_hash::hash(openssl_hash::Type::SHA1, &buf[..]));
+                buf.clear();
+            }
+            if n == 0 {
+                break;
+            }
+        }
+    }
+    if !buf.is_empty() {
+        pieces.extend(openssl_hash::hash(openssl_hash::Type::SHA1, &buf[..]));
+    }
+    Ok(pieces)
+}
+
+// Makes a bencoded torrent for the file or directory at `path`
+pub fn create_torrent(path: &Path, options: &TorrentOptions) -> Result<Vec<u8>, io::Error> {
+    let name = match path.file_name() {
+        Some(name) => name.to_string_lossy().into_owned(),
+        None => return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                          "can't make a torrent without a name")),
+    };
+
+    let mut info = vec![("name", bytes(&name)),
+                        ("piece length", Bencode::Number(options.piece_length as i64))];
+
+    if try!(fs::metadata(path)).is_dir() {
+        let mut files = Vec::new();
+        try!(walk(path, &mut files));
+
+        let mut entries = Vec::new();
+        for f in files.iter() {
+            let length = try!(fs::metadata(f)).len();
+            let relative = f.strip_prefix(path).unwrap();
+            let components = relative.components()
+                                     .map(|c| bytes(&c.as_os_str().to_string_lossy()))
+                                     .collect();
+            entries.push(util::bencode_dict(vec![("length", Bencode::Number(length as i64)),
+                                                 ("path", Bencode::List(components))]));
+        }
+        info.push(("files", Bencode::List(entries)));
+        info.push(("pieces", Bencode::ByteString(try!(hash_pieces(&files,
+                                                                  options.piece_length)))));
+    } else {
+        let length = try!(fs::metadata(path)).len();
+        info.push(("length", Bencode::Number(length as i64)));
+        info.push(("pieces", Bencode::ByteString(try!(hash_pieces(&[path.to_path_buf()],
+                                                                  options.piece_length)))));
+    }
+
+    if options.private {
+        info.push(("private", Bencode::Number(1)));
+    }
+
+    let mut torrent = vec![("info", util::bencode_dict(info)),
+                           ("created by", bytes(&extension::client_version())),
+                           ("creation date", Bencode::Number(time::get_time().sec))];
+    if let Some(first) = options.trackers.first() {
+        torrent.push(("announce", bytes(first)));
+    }
+    if options.trackers.len() > 1 {
+        let tiers = options.trackers.iter().map(|t| Bencode::List(vec![bytes(t)])).collect();
+        torrent.push(("announce-list", Bencode::List(tiers)));
+    }
+    if !options.web_seeds.is_empty() {
+        let seeds = options.web_seeds.iter().map(|s| bytes(s)).collect();
+        torrent.push(("url-list", Bencode::List(seeds)));
+    }
+
+    util::bencode_dict(torrent).to_bytes().map_err(to_io_error)
+}
@@ -0,0 +1,571 @@
+// Disk I/O, done on a pool of threads shared by a session's torrents so
+// that peer connections don't stall on writes and hashing.
+//
+// Blocks from peers go into a write-back cache. A piece's blocks stay
+// there until the piece is hashed, unless the cache fills up, in which
+// case contiguous runs of blocks are written out as single writes and read
+// back for hashing. Verified pieces are kept in a small read cache, since
+// the pieces we've just finished are the ones other peers want next.
+
+use metainfo::MetaInfo;
+use storage::{MoveConflict, Storage};
+use util;
+
+use openssl::crypto::hash as openssl_hash;
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::mpsc::{self, Sender};
+use std::thread;
+
+pub const DISK_THREADS: usize = 4;
+
+// bytes of unwritten blocks a torrent may have before connections have to
+// wait for the disk to catch up
+pub const WRITE_CACHE_SIZE: usize = 16 << 20;
+
+// whole pieces kept in memory for serving requests
+pub const READ_CACHE_PIECES: usize = 16;
+
+// Told whether a piece matched its hash (and was stored), or why it
+// couldn't be checked
+pub type HashCallback = Box<Fn(u32, Result<bool, io::Error>) + Send>;
+
+enum Job {
+    Hash(Arc<DiskInner>, u32, HashCallback),
+
+    // write out blocks until the cache is back under its limit
+    Flush(Arc<DiskInner>),
+
+    Read(Arc<DiskInner>, u32, Sender<Result<Arc<Vec<u8>>, io::Error>>),
+}
+
+struct Queue {
+    jobs: VecDeque<Job>,
+    shutdown: bool,
+}
+
+// The threads that do every torrent's disk I/O
+pub struct DiskPool {
+    queue: Mutex<Queue>,
+    ready: Condvar,
+}
+
+impl DiskPool {
+    pub fn new(threads: usize) -> Arc<DiskPool> {
+        let pool = Arc::new(DiskPool {
+            queue: Mutex::new(Queue {
+                jobs: VecDeque::new(),
+                shutdown: false,
+            }),
+            ready: Condvar::new(),
+        });
+        for _ in 0..threads {
+            let pool = pool.clone();
+            thread::spawn(move || pool.work());
+        }
+        pool
+    }
+
+    // Lets the threads exit once the jobs already queued are done
+    pub fn shutdown(&self) {
+        self.queue.lock().unwrap().shutdown = true;
+        self.ready.notify_all();
+    }
+
+    // Jobs submitted after shutdown are dropped, which wakes anyone
+    // waiting on a read with an error. False if the job was dropped.
+    fn submit(&self, job: Job) -> bool {
+        let mut queue = self.queue.lock().unwrap();
+        if queue.shutdown {
+            return false;
+        }
+        queue.jobs.push_back(job);
+        self.ready.notify_one();
+        true
+    }
+
+    fn work(&self) {
+        loop {
+            let mut queue = self.queue.lock().unwrap();
+            while queue.jobs.is_empty() && !queue.shutdown {
+                queue = self.ready.wait(queue).unwrap();
+            }
+            let job = match queue.jobs.pop_front() {
+                Some(job) => job,
+                None => return,
+            };
+            drop(queue);
+
+            match job {
+                Job::Hash(disk, index, done) => {
+                    let result = disk.hash(index);
+                    done(index, result);
+                },
+                Job::Flush(disk) => disk.flush(),
+                Job::Read(disk, index, reply) => {
+                    let _ = reply.send(disk.read_piece(index));
+                },
+            }
+        }
+    }
+}
+
+struct Cache {
+    // blocks not written yet, by piece and then offset in the piece
+    write: HashMap<u32, BTreeMap<u32, Vec<u8>>>,
+    write_size: usize,
+    flush_queued: bool,
+
+    // pieces some of whose blocks couldn't be written out, so that hashing
+    // them reports the error rather than reading back what's on disk
+    failed: HashMap<u32, io::Error>,
+
+    // verified pieces, least recently used first
+    read: VecDeque<(u32, Arc<Vec<u8>>)>,
+}
+
+struct DiskInner {
+    info: Arc<MetaInfo>,
+
+    // always locked before `cache` when both are needed, so that a piece
+    // can't be hashed while its blocks are on their way to disk
+    storage: Mutex<Storage>,
+    cache: Mutex<Cache>,
+
+    // signalled when there's room in the write cache
+    room: Condvar,
+}
+
+// One torrent's view of the disk
+#[derive(Clone)]
+pub struct Disk {
+    inner: Arc<DiskInner>,
+    pool: Arc<DiskPool>,
+}
+
+impl Disk {
+    pub fn new(info: Arc<MetaInfo>, storage: Storage, pool: Arc<DiskPool>) -> Disk {
+        Disk {
+            inner: Arc::new(DiskInner {
+                info: info,
+                storage: Mutex::new(storage),
+                cache: Mutex::new(Cache {
+                    write: HashMap::new(),
+                    write_size: 0,
+                    flush_queued: false,
+                    failed: HashMap::new(),
+                    read: VecDeque::new(),
+                }),
+                room: Condvar::new(),
+            }),
+            pool: pool,
+        }
+    }
+
+    // Caches a block of a piece we're downloading. It's written once the
+    // piece has been hashed, or sooner if the cache fills up.
+    pub fn write_block(&self, index: u32, begin: u32, data: Vec<u8>) {
+        let mut cache = self.inner.cache.lock().unwrap();
+        cache.write_size += data.len();
+        let old = cache.write.entry(index).or_insert(BTreeMap::new()).insert(begin, data);
+        if let Some(old) = old {
+            cache.write_size -= old.len();
+        }
+        if cache.write_size > WRITE_CACHE_SIZE && !cache.flush_queued {
+            cache.flush_queued = true;
+            drop(cache);
+            // once the pool has shut down there's no thread to do it, and
+            // connections waiting for room would wait forever
+            if !self.pool.submit(Job::Flush(self.inner.clone())) {
+                self.inner.flush();
+            }
+        }
+    }
+
+    // Blocks while the write cache is full. Connections call this after
+    // each block, so that a peer sending faster than we can write is
+    // slowed down by its TCP window rather than filling our memory.
+    pub fn wait_for_room(&self) {
+        let mut cache = self.inner.cache.lock().unwrap();
+        while cache.write_size > WRITE_CACHE_SIZE {
+            cache = self.inner.room.wait(cache).unwrap();
+        }
+    }
+
+    // Forgets the cached blocks of a piece we've given up on
+    pub fn discard(&self, index: u32) {
+        let mut cache = self.inner.cache.lock().unwrap();
+        cache.failed.remove(&index);
+        let blocks = cache.write.remove(&index);
+        if let Some(blocks) = blocks {
+            cache.write_size -= blocks.values().fold(0, |n, b| n + b.len());
+            self.inner.room.notify_all();
+        }
+    }
+
+    // Hashes a piece once all its blocks have been written, storing it if
+    // it matches. `done` is called on a disk thread.
+    pub fn hash_piece(&self, index: u32, done: HashCallback) {
+        self.pool.submit(Job::Hash(self.inner.clone(), index, done));
+    }
+
+    // Reads part of a verified piece, from the read cache if it's there
+    pub fn read_block(&self, index: u32, begin: u32, length: u32) -> Result<Vec<u8>, io::Error> {
+        if begin as u64 + length as u64 > self.inner.info.piece_size(index) as u64 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "block past end of piece"));
+        }
+
+        // Mapped files are copied from straight away: they're already in
+        // the page cache, so neither a disk thread nor our own cache helps
+        {
+            let storage = self.inner.storage.lock().unwrap();
+            let offset = self.inner.info.piece_offset(index) + begin as u64;
+            if let Some(slices) = storage.mapped_range(offset, length as u64) {
+                let mut block = Vec::with_capacity(length as usize);
+                for slice in slices.into_iter() {
+                    block.extend_from_slice(slice);
+                }
+                return Ok(block);
+            }
+        }
+
+        let piece = match self.inner.cached_piece(index) {
+            Some(piece) => piece,
+            None => {
+                let (tx, rx) = mpsc::channel();
+                self.pool.submit(Job::Read(self.inner.clone(), index, tx));
+                match rx.recv() {
+                    Ok(result) => try!(result),
+                    Err(_) => return Err(io::Error::new(io::ErrorKind::Other,
+                                                        "disk threads have stopped")),
+                }
+            },
+        };
+        let begin = begin as usize;
+        Ok(piece[begin..begin + length as usize].to_vec())
+    }
+
+    pub fn set_skipped(&self, skip: &[bool]) -> Result<(), io::Error> {
+        self.inner.storage.lock().unwrap().set_skipped(skip)
+    }
+
+    // Moves the files to `root`. Disk jobs wait until it's done, and
+    // blocks still in the cache are written to the new place. True if the
+    // torrent needs rechecking because files already there were kept.
+    pub fn move_storage(&self, root: &Path, conflict: MoveConflict) -> Result<bool, io::Error> {
+        self.inner.storage.lock().unwrap().move_to(root, conflict)
+    }
+
+    // Renames files on disk, returning where they all are now
+    pub fn rename_files(&self, renames: &[(usize, PathBuf)]) -> Result<Vec<PathBuf>, io::Error> {
+        let mut storage = self.inner.storage.lock().unwrap();
+        try!(storage.rename(renames));
+        Ok(storage.paths().to_vec())
+    }
+}
+
+impl DiskInner {
+    fn cached_piece(&self, index: u32) -> Option<Arc<Vec<u8>>> {
+        let mut cache = self.cache.lock().unwrap();
+        let pos = match cache.read.iter().position(|&(i, _)| i == index) {
+            Some(pos) => pos,
+            None => return None,
+        };
+        // move it to the back, as the most recently used
+        let entry = cache.read.remove(pos).unwrap();
+        let piece = entry.1.clone();
+        cache.read.push_back(entry);
+        Some(piece)
+    }
+
+    fn cache_piece(&self, index: u32, piece: Arc<Vec<u8>>) {
+        let mut cache = self.cache.lock().unwrap();
+        if cache.read.iter().any(|&(i, _)| i == index) {
+            return;
+        }
+        if cache.read.len() >= READ_CACHE_PIECES {
+            cache.read.pop_front();
+        }
+        cache.read.push_back((index, piece));
+    }
+
+    fn read_piece(&self, index: u32) -> Result<Arc<Vec<u8>>, io::Error> {
+        if let Some(piece) = self.cached_piece(index) {
+            return Ok(piece);
+        }
+        let size = self.info.piece_size(index);
+        let piece = Arc::new(try!(self.storage.lock().unwrap().read_block(index, 0, size)));
+        self.cache_piece(index, piece.clone());
+        Ok(piece)
+    }
+
+    // Takes a piece's blocks out of the write cache
+    fn take_blocks(&self, index: u32) -> BTreeMap<u32, Vec<u8>> {
+        let mut cache = self.cache.lock().unwrap();
+        let blocks = cache.write.remove(&index).unwrap_or(BTreeMap::new());
+        cache.write_size -= blocks.values().fold(0, |n, b| n + b.len());
+        self.room.notify_all();
+        blocks
+    }
+
+    // Why some of the piece's blocks couldn't be written out, if they
+    // couldn't. Only called with `storage` locked, so no flush is under way.
+    fn flush_error(&self, index: u32) -> Option<io::Error> {
+        self.cache.lock().unwrap().failed.remove(&index)
+    }
+
+    fn hash(&self, index: u32) -> Result<bool, io::Error> {
+        let mapped = self.storage.lock().unwrap().mapped_piece(index).is_some();
+        if mapped {
+            return self.hash_mapped(index);
+        }
+
+        let size = self.info.piece_size(index) as usize;
+        let mut data = vec![0; size];
+        {
+            let mut storage = self.storage.lock().unwrap();
+            let blocks = self.take_blocks(index);
+            if let Some(e) = self.flush_error(index) {
+                return Err(e);
+            }
+
+            // anything not in the cache was flushed to disk
+            let mut pos = 0;
+            for (begin, block) in blocks.into_iter() {
+                let begin = begin as usize;
+                if begin > pos {
+                    let gap = try!(storage.read_block(index, pos as u32, (begin - pos) as u32));
+                    util::copy_into(&mut data[pos..begin], &gap);
+                }
+                let end = begin + block.len();
+                util::copy_into(&mut data[begin..end], &block);
+                pos = end;
+            }
+            if pos < size {
+                let gap = try!(storage.read_block(index, pos as u32, (size - pos) as u32));
+                util::copy_into(&mut data[pos..], &gap);
+            }
+        }
+
+        let hash = openssl_hash::hash(openssl_hash::Type::SHA1, &data);
+        if hash != self.info.info.pieces()[index as usize] {
+            return Ok(false);
+        }
+        try!(self.storage.lock().unwrap().write_piece(index, &data));
+        self.cache_piece(index, Arc::new(data));
+        Ok(true)
+    }
+
+    // A piece in mapped files is written into them before it's checked,
+    // and hashed where it is. If it fails, what's left there is just
+    // overwritten when it's downloaded again.
+    fn hash_mapped(&self, index: u32) -> Result<bool, io::Error> {
+        let mut storage = self.storage.lock().unwrap();
+        let offset = self.info.piece_offset(index);
+        let blocks = self.take_blocks(index);
+        if let Some(e) = self.flush_error(index) {
+            return Err(e);
+        }
+        for (begin, run) in coalesce(blocks) {
+            try!(storage.write_at(offset + begin as u64, &run));
+        }
+
+        let hash = match storage.mapped_piece(index) {
+            Some(slices) => {
+                let mut hasher = openssl_hash::Hasher::new(openssl_hash::Type::SHA1);
+                for slice in slices.into_iter() {
+                    try!(hasher.write_all(slice));
+                }
+                Some(hasher.finish())
+            },
+            None => None,
+        };
+        let hash = match hash {
+            Some(hash) => hash,
+            None => {
+                // the files have moved since, and couldn't be mapped again
+                let data = try!(storage.read_block(index, 0, self.info.piece_size(index)));
+                openssl_hash::hash(openssl_hash::Type::SHA1, &data)
+            },
+        };
+        Ok(hash == self.info.info.pieces()[index as usize])
+    }
+
+    // Writes out the pieces with the most cached blocks until the cache is
+    // under its limit. Each run of contiguous blocks is one write.
+    fn flush(&self) {
+        let mut storage = self.storage.lock().unwrap();
+        loop {
+            let (index, blocks) = {
+                let mut cache = self.cache.lock().unwrap();
+                let mut biggest: Option<(u32, usize)> = None;
+                for (&index, blocks) in cache.write.iter() {
+                    if biggest.map_or(true, |(_, n)| blocks.len() > n) {
+                        biggest = Some((index, blocks.len()));
+                    }
+                }
+                match biggest.map(|(index, _)| index) {
+                    Some(index) if cache.write_size > WRITE_CACHE_SIZE => {
+                        let blocks = cache.write.remove(&index).unwrap();
+                        cache.write_size -= blocks.values().fold(0, |n, b| n + b.len());
+                        (index, blocks)
+                    },
+                    _ => {
+                        cache.flush_queued = false;
+                        self.room.notify_all();
+                        return;
+                    },
+                }
+            };
+
+            for (begin, run) in coalesce(blocks) {
+                let offset = self.info.piece_offset(index) + begin as u64;
+                if let Err(e) = storage.write_at(offset, &run) {
+                    log!("error writing blocks of piece {}: {:?}", index, e);
+                    // the piece's hash passes it on to the torrent
+                    self.cache.lock().unwrap().failed.insert(index, e);
+                    break;
+                }
+            }
+            self.room.notify_all();
+        }
+    }
+}
+
+// Joins blocks that follow on from each other into (offset, data) runs
+fn coalesce(blocks: BTreeMap<u32, Vec<u8>>) -> Vec<(u32, Vec<u8>)> {
+    let mut runs: Vec<(u32, Vec<u8>)> = Vec::new();
+    for (begin, block) in blocks.into_iter() {
+        if let Some(last) = runs.last_mut() {
+            if last.0 + last.1.len() as u32 == begin {
+                last.1.extend(block);
+                continue;
+            }
+        }
+        runs.push((begin, block));
+    }
+    runs
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{coalesce, Disk, DiskPool, WRITE_CACHE_SIZE};
+    use create::{self, TorrentOptions};
+    use metainfo::{self, MetaInfo};
+    use storage::{self, Allocation, Storage, StorageMode};
+
+    use std::collections::BTreeMap;
+    use std::env;
+    use std::fs::{self, File};
+    use std::io::{self, Write};
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use std::sync::mpsc;
+    use time;
+
+    const DATA: &'static [u8] = b"0123456789abcdefghijklmnopqrstuv";
+
+    // A torrent of DATA in two 16 byte pieces, with its storage opened
+    // empty (or skipped). Returns the directory it's in.
+    fn make_disk(name: &str, skip: bool, pool: Arc<DiskPool>) -> (PathBuf, Arc<MetaInfo>, Disk) {
+        let root = env::temp_dir().join(format!("deluge-{}-{}", name, time::precise_time_ns()));
+        fs::create_dir_all(&root).unwrap();
+        let path = root.join("t");
+        File::create(&path).unwrap().write_all(DATA).unwrap();
+
+        let mut options = TorrentOptions::new();
+        options.piece_length = 16;
+        let torrent = create::create_torrent(&path, &options).unwrap();
+        let info = Arc::new(metainfo::from_bytes(&torrent).unwrap());
+        fs::remove_file(&path).unwrap();
+
+        let storage = Storage::new(&info, &root, storage::default_paths(&info), &[skip],
+                                   Allocation::Sparse, StorageMode::Files).unwrap();
+        (root, info.clone(), Disk::new(info, storage, pool))
+    }
+
+    // Hashes piece `index`, waiting for the result
+    fn hash(disk: &Disk, index: u32) -> Result<bool, io::Error> {
+        let (tx, rx) = mpsc::channel();
+        disk.hash_piece(index, Box::new(move |_, result| { let _ = tx.send(result); }));
+        rx.recv().unwrap()
+    }
+
+    #[test]
+    fn coalesce_joins_adjacent_blocks() {
+        let mut blocks = BTreeMap::new();
+        blocks.insert(8, b"89".to_vec());
+        blocks.insert(0, b"0123".to_vec());
+        blocks.insert(4, b"4567".to_vec());
+        blocks.insert(12, b"cd".to_vec());
+        assert_eq!(coalesce(blocks), vec![(0, b"0123456789".to_vec()), (12, b"cd".to_vec())]);
+        assert_eq!(coalesce(BTreeMap::new()), vec![]);
+    }
+
+    #[test]
+    fn hash_reads_flushed_blocks_back() {
+        let pool = DiskPool::new(1);
+        let (root, _, disk) = make_disk("disk-flushed", false, pool.clone());
+
+        // 0..4 and 8..12 of piece 1 were flushed; the rest is still cached
+        {
+            let mut storage = disk.inner.storage.lock().unwrap();
+            storage.write_at(16, &DATA[16..20]).unwrap();
+            storage.write_at(24, &DATA[24..28]).unwrap();
+        }
+        disk.write_block(1, 4, DATA[20..24].to_vec());
+        disk.write_block(1, 12, DATA[28..32].to_vec());
+        assert!(hash(&disk, 1).unwrap());
+        assert_eq!(disk.read_block(1, 0, 16).unwrap(), &DATA[16..32]);
+
+        // a block that never arrived leaves zeros, which don't match
+        disk.write_block(0, 0, DATA[0..12].to_vec());
+        assert!(!hash(&disk, 0).unwrap());
+        assert_eq!(disk.inner.cache.lock().unwrap().write_size, 0);
+
+        pool.shutdown();
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn failed_flush_fails_hash() {
+        let pool = DiskPool::new(1);
+        let (root, info, disk) = make_disk("disk-failed", true, pool.clone());
+        // the skipped file's blocks go to the part file, which can't be
+        // opened if there's a directory in the way
+        fs::create_dir(storage::part_file_path(&info, &root)).unwrap();
+
+        // pretend the cache is full, so that the block is flushed
+        disk.inner.cache.lock().unwrap().write_size += WRITE_CACHE_SIZE;
+        disk.write_block(0, 0, DATA[0..16].to_vec());
+        disk.inner.flush();
+        disk.inner.cache.lock().unwrap().write_size -= WRITE_CACHE_SIZE;
+        assert!(hash(&disk, 0).is_err());
+
+        pool.shutdown();
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn flush_after_shutdown() {
+        let pool = DiskPool::new(1);
+        let (root, _, disk) = make_disk("disk-shutdown", false, pool.clone());
+        pool.shutdown();
+
+        // with no threads left, the block is written out straight away
+        disk.inner.cache.lock().unwrap().write_size += WRITE_CACHE_SIZE;
+        disk.write_block(0, 0, DATA[0..16].to_vec());
+        disk.wait_for_room();
+        {
+            let cache = disk.inner.cache.lock().unwrap();
+            assert!(!cache.flush_queued);
+            assert_eq!(cache.write_size, WRITE_CACHE_SIZE);
+        }
+        let mut storage = disk.inner.storage.lock().unwrap();
+        assert_eq!(storage.read_block(0, 0, 16).unwrap(), &DATA[0..16]);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -1,8 +1,155 @@
-use std::net::TcpStream;
+use std::collections::HashMap;
+use std::net;
+use std::path::{Path, PathBuf};
 use std::io::{self, Read, Write};
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use std::time::Duration;
+use time;
 
+use bitfield::Bitfield;
+use disk::{Disk, DiskPool, HashCallback};
+use extension::{self, Extensions};
+use fast;
+use message::{self, Message, MessageError};
+use metadata::MetadataExchange;
 use metainfo::MetaInfo;
-use tracker::Peer;
+use mse::{self, EncryptionPolicy, MseError, PeerStream};
+use peer_id;
+use peerlist::{PeerInfo, PeerList, PeerSource};
+use pex::{self, PeerExchange};
+use picker::{self, FilePriority, Picker};
+use ratelimit::{self, RateLimits, Throttled};
+use session::{ConnectionLimit, TorrentHandle, TorrentSettings, TorrentState, Torrents};
+use resume::{self, ResumeData};
+use storage::{self, MoveConflict, Storage};
+use stream::VerifiedPieces;
+use tracker::{self, EventType, Peer};
+use transport::Transport;
+use verify;
+use httpseed::HttpSeed;
+use webseed::{Seed, WebSeed, WebSeedError};
+
+// size of the blocks we request pieces in
+const BLOCK_SIZE: u32 = 1 << 14;
+
+// largest block we'll serve to a peer
+const MAX_REQUEST_LEN: u32 = 1 << 17;
+
+// number of block requests we keep outstanding with each peer
+const PIPELINE_DEPTH: usize = 5;
+
+const READ_TIMEOUT_SECS: u64 = 120;
+
+// how often an established connection wakes up when the peer is quiet, so
+// that timers like PEX still run
+const TICK_SECS: u64 = 5;
+
+// how much we read from a peer at a time
+const READ_CHUNK: usize = 1 << 14;
+
+// how long a web seed waits when there's nothing for it to download
+const WEB_SEED_IDLE_SECS: u64 = 5;
+
+// how often we ask the trackers for more peers
+const REANNOUNCE_SECS: u64 = 30 * 60;
+
+// State shared by all the peer connections of a download
+struct Shared {
+    picker: Picker,
+
+    // pieces in the order they were verified, so that every connection
+    // can send `have` messages for them
+    completed: Vec<u32>,
+
+    // what the session reports for this torrent
+    state: Arc<Mutex<TorrentState>>,
+
+    // for readers of the torrent's files
+    verified: Arc<VerifiedPieces>,
+}
+
+impl Shared {
+    // Called once the disk has hashed a downloaded piece and, if it
+    // matched, stored it. A piece that didn't match, or couldn't be
+    // stored, goes back to the picker.
+    fn piece_checked(&mut self, info: &MetaInfo, index: u32, result: Result<bool, io::Error>) {
+        match result {
+            Ok(true) => {},
+            Ok(false) => {
+                self.picker.abort(index);
+                return;
+            },
+            Err(e) => {
+                self.picker.abort(index);
+                *self.state.lock().unwrap() = TorrentState::Error(format!("{:?}", e));
+                return;
+            },
+        }
+
+        self.picker.complete(index);
+        self.completed.push(index);
+        self.verified.add(index);
+        log!("piece {} verified ({}/{})", index,
+             self.picker.have().count(), info.num_pieces());
+
+        if self.picker.is_finished() {
+            let mut state = self.state.lock().unwrap();
+            if *state == TorrentState::Downloading {
+                *state = TorrentState::Seeding;
+            }
+        }
+    }
+}
+
+// What to do when a piece downloaded from `from` has been hashed
+fn on_hashed(shared: &Arc<Mutex<Shared>>, info: &Arc<MetaInfo>, from: String) -> HashCallback {
+    let shared = shared.clone();
+    let info = info.clone();
+    Box::new(move |index, result| {
+        if let Ok(false) = result {
+            log!("piece {} from {} failed hash check", index, from);
+        }
+        shared.lock().unwrap().piece_checked(&info, index, result);
+    })
+}
+
+// A piece we're currently downloading from a peer
+struct PieceDownload {
+    index: u32,
+
+    // blocks (begin, length) we haven't requested yet, last one first
+    unrequested: Vec<(u32, u32)>,
+
+    // blocks we've requested but haven't received
+    pending: Vec<(u32, u32)>,
+}
+
+impl PieceDownload {
+    fn new(index: u32, size: u32) -> PieceDownload {
+        let mut unrequested = Vec::new();
+        let mut begin = 0;
+        while begin < size {
+            let len = if size - begin < BLOCK_SIZE { size - begin } else { BLOCK_SIZE };
+            unrequested.push((begin, len));
+            begin += len;
+        }
+        unrequested.reverse();
+
+        PieceDownload {
+            index: index,
+            unrequested: unrequested,
+            pending: Vec::new(),
+        }
+    }
+
+    fn is_done(&self) -> bool {
+        self.unrequested.is_empty() && self.pending.is_empty()
+    }
+}
 
 struct PeerConnection {
     am_choking: bool,
@@ -10,21 +157,92 @@ struct PeerConnection {
     peer_choking: bool,
     peer_interested: bool,
     peer: Peer,
+
+    stream: Throttled<PeerStream<Transport>>,
+
+    // bytes of a message that hasn't fully arrived yet
+    inbuf: Vec<u8>,
+
+    // when we last heard from the peer, in seconds since the epoch
+    last_received: i64,
+
+    // whether both sides set the Fast extension bit in their handshakes
+    fast: bool,
+
+    // whether both sides support the extension protocol
+    extended: bool,
+    extensions: Extensions,
+
+    // pieces the peer has
+    peer_has: Bitfield,
+
+    // pieces the peer lets us request while it chokes us
+    allowed_fast: Vec<u32>,
+
+    // pieces the peer suggested we download from it
+    suggested: Vec<u32>,
+
+    // pieces we let the peer request while we choke it
+    our_allowed_fast: Vec<u32>,
+
+    current: Option<PieceDownload>,
+
+    // how much of `Shared::completed` we've sent `have`s for
+    haves_sent: usize,
+
+    info: Arc<MetaInfo>,
+    shared: Arc<Mutex<Shared>>,
+    disk: Disk,
+    transferred: Arc<Transferred>,
+
+    // the torrent's peers with their PEX flags, this one's included
+    connected: pex::ConnectedPeers,
+
+    // set when the torrent is paused or removed
+    stop: Arc<AtomicBool>,
 }
 
 const PROTOCOL: &'static str = "BitTorrent protocol";
 
+// reserved handshake bytes advertising the extensions we support
+fn reserved_bytes() -> [u8; 8] {
+    let mut reserved = [0; 8];
+    fast::set_reserved_bit(&mut reserved);
+    extension::set_reserved_bit(&mut reserved);
+    reserved
+}
+
+// The extension protocol handlers a connection to `addr` gets. Private
+// torrents don't get PEX, since their peers may only come from the tracker.
+fn extensions_for(ctx: &DownloadContext, addr: net::SocketAddr) -> Extensions {
+    let mut extensions = Extensions::new();
+    extensions.register(Box::new(MetadataExchange::new(Arc::new(ctx.info.info_bytes.clone()))));
+
+    if !ctx.info.info.is_private() {
+        let events = ctx.events.clone();
+        let on_discovered = Box::new(move |peers: Vec<Peer>| {
+            let _ = events.send(ManagerEvent::Discovered(PeerSource::Pex, peers));
+        });
+        extensions.register(Box::new(PeerExchange::new(addr, ctx.connected.clone(),
+                                                       on_discovered)));
+    }
+    extensions
+}
+
 fn create_handshake(info: &MetaInfo, peer_id: String) -> Vec<u8> {
+    let reserved = reserved_bytes();
+
     let mut handshake = Vec::new();
     handshake.push(PROTOCOL.len() as u8);
     handshake.extend(PROTOCOL.bytes());
-    handshake.extend(b"\x00\x00\x00\x00\x00\x00\x00\x00");
+    handshake.extend(reserved.iter());
     handshake.append(&mut info.info_hash.clone());
     let mut peer_id_bytes = peer_id.into_bytes();
     handshake.append(&mut peer_id_bytes);
     handshake
 }
 
+#[derive(Debug)]
 enum HandshakeError {
     IoError(io::Error),
     ProtocolError(String),
@@ -43,6 +261,16 @@ impl From<String> for HandshakeError {
     }
 }
 
+impl From<MseError> for HandshakeError {
+    fn from(e: MseError) -> HandshakeError {
+        match e {
+            MseError::IoError(e) => HandshakeError::IoError(e),
+            MseError::ProtocolError(e) => HandshakeError::ProtocolError(e),
+            MseError::Refused(e) => HandshakeError::ProtocolError(e),
+        }
+    }
+}
+
 impl From<ReadError> for HandshakeError {
     fn from(e: ReadError) -> HandshakeError {
         HandshakeError::IoError(
@@ -54,14 +282,21 @@ impl From<ReadError> for HandshakeError {
     }
 }
 
-fn receive_handshake(stream: &mut TcpStream, info: &MetaInfo) -> Result<(), HandshakeError> {
+// the parts of the remote peer's handshake we care about
+pub struct PeerHandshake {
+    reserved: [u8; 8],
+    info_hash: Vec<u8>,
+    peer_id: Vec<u8>,
+}
+
+fn receive_handshake<S: Read>(stream: &mut S) -> Result<PeerHandshake, HandshakeError> {
     let mut buf_pstrlen = [0; 1];
     try!(stream.read_exact(&mut buf_pstrlen));
 
     let pstrlen = buf_pstrlen[0];
     if (pstrlen as usize) != PROTOCOL.len() {
         return try!(Err(String::from("pstrlen isn't 19")));
-    } 
+    }
 
     // Ignore actual protocol string. TODO
     try!(read_n(stream, pstrlen as u64));
@@ -71,11 +306,14 @@ fn receive_handshake(stream: &mut TcpStream, info: &MetaInfo) -> Result<(), Hand
 
     let mut buf_infohash = [0; 20];
     try!(stream.read_exact(&mut buf_infohash));
-    if buf_infohash != info.info_hash[..] {
-        return try!(Err(String::from("Info hash doesn't match")));
-    }
-    Ok(())
 
+    let mut buf_peer_id = [0; 20];
+    try!(stream.read_exact(&mut buf_peer_id));
+    Ok(PeerHandshake {
+        reserved: buf_reserved,
+        info_hash: buf_infohash.to_vec(),
+        peer_id: buf_peer_id.to_vec(),
+    })
 }
 
 enum ReadError {
@@ -83,13 +321,13 @@ enum ReadError {
     IoError(io::Error),
 }
 
-fn read_n(stream: &mut TcpStream, n: u64) -> Result<Vec<u8>, ReadError> {
+fn read_n<S: Read>(stream: &mut S, n: u64) -> Result<Vec<u8>, ReadError> {
     let mut buf = Vec::new();
     try!(read_n_to_buf(stream, n, &mut buf));
     Ok(buf)
 }
 
-fn read_n_to_buf(stream: &mut TcpStream, n: u64, buf: &mut Vec<u8>) -> Result<(), ReadError> {
+fn read_n_to_buf<S: Read>(stream: &mut S, n: u64, buf: &mut Vec<u8>) -> Result<(), ReadError> {
     let read = stream.take(n).read_to_end(buf);
     match read {
         Ok(0) => Err(ReadError::SocketClosed),
@@ -99,21 +337,1220 @@ fn read_n_to_buf(stream: &mut TcpStream, n: u64, buf: &mut Vec<u8>) -> Result<()
     }
 }
 
-pub fn download(info: &MetaInfo, peers: &[Peer], peer_id: String) -> Result<(), io::Error> {
-    let handshake = create_handshake(info, peer_id);
+#[derive(Debug)]
+enum ConnectionError {
+    IoError(io::Error),
+    MessageError(MessageError),
+    ProtocolError(String),
+}
+
+impl From<io::Error> for ConnectionError {
+    fn from(e: io::Error) -> ConnectionError {
+        ConnectionError::IoError(e)
+    }
+}
+
+impl From<MessageError> for ConnectionError {
+    fn from(e: MessageError) -> ConnectionError {
+        ConnectionError::MessageError(e)
+    }
+}
+
+impl From<String> for ConnectionError {
+    fn from(e: String) -> ConnectionError {
+        ConnectionError::ProtocolError(e)
+    }
+}
+
+impl PeerConnection {
+    fn new(peer: Peer, stream: PeerStream<Transport>, handshake: &PeerHandshake, extensions: Extensions,
+           ctx: &DownloadContext) -> PeerConnection {
+        let info = ctx.info.clone();
+        let our_reserved = reserved_bytes();
+        let peer_rates = ctx.peer_rates.for_peer();
+        let (download, upload) = ratelimit::throttles(&[&peer_rates, &ctx.rates,
+                                                        &ctx.session_rates]);
+
+        PeerConnection {
+            am_choking: true,
+            am_interested: false,
+            peer_choking: true,
+            peer_interested: false,
+            peer: peer,
+            stream: Throttled::new(stream, download, upload),
+            inbuf: Vec::new(),
+            last_received: time::get_time().sec,
+            fast: fast::supported(&our_reserved) && fast::supported(&handshake.reserved),
+            extended: extension::supported(&our_reserved)
+                      && extension::supported(&handshake.reserved),
+            extensions: extensions,
+            peer_has: Bitfield::new(info.num_pieces()),
+            allowed_fast: Vec::new(),
+            suggested: Vec::new(),
+            our_allowed_fast: Vec::new(),
+            current: None,
+            haves_sent: 0,
+            info: info,
+            shared: ctx.shared.clone(),
+            disk: ctx.disk.clone(),
+            transferred: ctx.transferred.clone(),
+            connected: ctx.connected.clone(),
+            stop: ctx.stop.clone(),
+        }
+    }
+
+    fn send(&mut self, msg: Message) -> Result<(), ConnectionError> {
+        try!(message::write_message(&mut self.stream, &msg));
+        Ok(())
+    }
+
+    fn run(&mut self) -> Result<(), ConnectionError> {
+        try!(self.send_have_state());
+        try!(self.send_allowed_fast());
+        if self.extended {
+            let hs = self.extensions.handshake(&self.peer.addr.ip(), Some(tracker::LISTEN_PORT));
+            try!(self.send(hs));
+        }
+
+        loop {
+            if let Some(msg) = try!(self.read_message()) {
+                try!(self.handle(msg));
+            }
+            try!(self.send_new_haves());
+            try!(self.update_interest());
+            try!(self.request_blocks());
+            if self.extended {
+                for msg in self.extensions.tick() {
+                    try!(self.send(msg));
+                }
+            }
+
+            if self.stop.load(Ordering::SeqCst) {
+                return Ok(());
+            }
+            // two seeds have nothing to say to each other
+            if self.peer_has.is_complete() && self.shared.lock().unwrap().picker.is_complete() {
+                return Ok(());
+            }
+        }
+    }
+
+    // Reads the next message, or returns `None` if the peer has been quiet
+    // for a tick. The stream's read timeout is `TICK_SECS`, so a peer is
+    // only given up on here once it's been silent for `READ_TIMEOUT_SECS`.
+    fn read_message(&mut self) -> Result<Option<Message>, ConnectionError> {
+        loop {
+            let framed = try!(message::frame_len(&self.inbuf));
+            if let Some(len) = framed {
+                let msg = try!(message::read_message(&mut &self.inbuf[..len]));
+                self.inbuf.drain(..len);
+                return Ok(Some(msg));
+            }
+
+            let mut buf = [0; READ_CHUNK];
+            match self.stream.read(&mut buf) {
+                Ok(0) => {
+                    return Err(ConnectionError::from(
+                        io::Error::new(io::ErrorKind::UnexpectedEOF, "connection closed")));
+                },
+                Ok(n) => {
+                    self.inbuf.extend_from_slice(&buf[..n]);
+                    self.last_received = time::get_time().sec;
+                },
+                Err(ref e) if e.kind() == io::ErrorKind::WouldBlock
+                              || e.kind() == io::ErrorKind::TimedOut => {
+                    let silent = time::get_time().sec - self.last_received;
+                    if silent >= READ_TIMEOUT_SECS as i64 {
+                        return Err(ConnectionError::from(
+                            io::Error::new(io::ErrorKind::TimedOut, "peer went quiet")));
+                    }
+                    return Ok(None);
+                },
+                Err(e) => return Err(ConnectionError::from(e)),
+            }
+        }
+    }
+
+    // Tells the peer which pieces we have. With the Fast extension we
+    // always say something, using the compact forms where possible.
+    fn send_have_state(&mut self) -> Result<(), ConnectionError> {
+        let have = {
+            let shared = self.shared.lock().unwrap();
+            self.haves_sent = shared.completed.len();
+            shared.picker.have().clone()
+        };
+
+        if self.fast && have.is_empty() {
+            try!(self.send(Message::HaveNone));
+        } else if self.fast && have.is_complete() {
+            try!(self.send(Message::HaveAll));
+        } else if !have.is_empty() {
+            try!(self.send(Message::Bitfield(have.as_bytes().to_vec())));
+        }
+        Ok(())
+    }
+
+    fn send_allowed_fast(&mut self) -> Result<(), ConnectionError> {
+        if !self.fast {
+            return Ok(());
+        }
+
+        self.our_allowed_fast = fast::allowed_fast_set(&self.peer.addr.ip(),
+                                                       &self.info.info_hash,
+                                                       self.info.num_pieces(),
+                                                       fast::ALLOWED_FAST_COUNT);
+
+        let have = self.shared.lock().unwrap().picker.have().clone();
+        let allowed: Vec<u32> = self.our_allowed_fast.iter()
+                                                     .cloned()
+                                                     .filter(|&i| have.has(i))
+                                                     .collect();
+        for i in allowed {
+            try!(self.send(Message::AllowedFast(i)));
+        }
+        Ok(())
+    }
+
+    fn check_index(&self, index: u32) -> Result<(), ConnectionError> {
+        if index >= self.info.num_pieces() {
+            return Err(ConnectionError::ProtocolError(
+                format!("Piece index {} out of range", index)));
+        }
+        Ok(())
+    }
+
+    fn handle(&mut self, msg: Message) -> Result<(), ConnectionError> {
+        if msg.is_fast_extension() && !self.fast {
+            return Err(ConnectionError::ProtocolError(
+                format!("Got {:?} without negotiating the Fast extension", msg)));
+        }
+        if let Message::Extended(..) = msg {
+            if !self.extended {
+                return Err(ConnectionError::ProtocolError(
+                    String::from("Got an extended message without negotiating the extension protocol")));
+            }
+        }
+
+        match msg {
+            Message::KeepAlive => {},
+            Message::Choke => {
+                self.peer_choking = true;
+                // without the Fast extension a choke silently discards
+                // our requests; with it we'll get explicit rejects
+                if !self.fast {
+                    self.abandon_piece();
+                }
+            },
+            Message::Unchoke => self.peer_choking = false,
+            Message::Interested => {
+                self.peer_interested = true;
+                if self.am_choking {
+                    self.am_choking = false;
+                    try!(self.send(Message::Unchoke));
+                }
+            },
+            Message::NotInterested => self.peer_interested = false,
+            Message::Have(i) => {
+                try!(self.check_index(i));
+                if !self.peer_has.has(i) {
+                    self.peer_has.set(i);
+                    self.shared.lock().unwrap().picker.peer_has(i);
+                    self.update_seed_flag();
+                }
+            },
+            Message::Bitfield(bytes) => {
+                let bf = try!(Bitfield::from_bytes(&bytes, self.info.num_pieces()));
+                self.set_peer_has(bf);
+            },
+            Message::HaveAll => {
+                let bf = Bitfield::full(self.info.num_pieces());
+                self.set_peer_has(bf);
+            },
+            Message::HaveNone => {
+                let bf = Bitfield::new(self.info.num_pieces());
+                self.set_peer_has(bf);
+            },
+            Message::Request(index, begin, length) =>
+                try!(self.handle_request(index, begin, length)),
+            Message::Piece(index, begin, block) =>
+                try!(self.handle_block(index, begin, block)),
+            // requests are served as soon as they arrive, so there's
+            // never anything to cancel
+            Message::Cancel(..) => {},
+            Message::Port(_) => {},
+            Message::SuggestPiece(i) => {
+                try!(self.check_index(i));
+                if !self.suggested.contains(&i) {
+                    self.suggested.push(i);
+                }
+            },
+            Message::RejectRequest(index, begin, length) =>
+                self.handle_reject(index, begin, length),
+            Message::AllowedFast(i) => {
+                try!(self.check_index(i));
+                if !self.allowed_fast.contains(&i) {
+                    self.allowed_fast.push(i);
+                }
+            },
+            Message::Extended(id, payload) => {
+                let replies = try!(self.extensions.on_message(id, &payload));
+                if id == extension::HANDSHAKE_ID {
+                    if let Some(v) = self.extensions.remote().and_then(|hs| hs.v.clone()) {
+                        log!("{} reports client version {:?}", self.peer, v);
+                    }
+                }
+                for msg in replies {
+                    try!(self.send(msg));
+                }
+            },
+            // from an extension we don't support
+            Message::Unknown(..) => {},
+        }
+        Ok(())
+    }
+
+    // Don't queue more requests than the peer said it can handle
+    fn pipeline_depth(&self) -> usize {
+        match self.extensions.remote().and_then(|hs| hs.reqq) {
+            Some(reqq) if reqq > 0 && (reqq as usize) < PIPELINE_DEPTH => reqq as usize,
+            _ => PIPELINE_DEPTH,
+        }
+    }
+
+    fn set_peer_has(&mut self, bf: Bitfield) {
+        {
+            let mut shared = self.shared.lock().unwrap();
+            shared.picker.peer_gone(&self.peer_has);
+            shared.picker.peer_has_bitfield(&bf);
+        }
+        self.peer_has = bf;
+        self.update_seed_flag();
+    }
+
+    // Tells PEX whether the peer is a seed, which it is once it has every
+    // piece
+    fn update_seed_flag(&self) {
+        let seed = self.peer_has.count() == self.info.num_pieces();
+        let mut connected = self.connected.lock().unwrap();
+        if let Some(flags) = connected.get_mut(&self.peer.addr) {
+            if seed {
+                *flags |= pex::FLAG_SEED;
+            } else {
+                *flags &= !pex::FLAG_SEED;
+            }
+        }
+    }
+
+    fn handle_request(&mut self, index: u32, begin: u32, length: u32)
+            -> Result<(), ConnectionError> {
+        let allowed = !self.am_choking
+                      || (self.fast && self.our_allowed_fast.contains(&index));
+        let valid = index < self.info.num_pieces()
+                    && length <= MAX_REQUEST_LEN
+                    && begin.checked_add(length)
+                            .map_or(false, |end| end <= self.info.piece_size(index));
+
+        let block = if allowed && valid && self.shared.lock().unwrap().picker.have().has(index) {
+            Some(try!(self.disk.read_block(index, begin, length)))
+        } else {
+            None
+        };
+
+        match block {
+            Some(block) => {
+                self.transferred.uploaded.fetch_add(block.len(), Ordering::SeqCst);
+                self.send(Message::Piece(index, begin, block))
+            },
+            None if self.fast => self.send(Message::RejectRequest(index, begin, length)),
+            None => Ok(()),
+        }
+    }
+
+    fn handle_block(&mut self, index: u32, begin: u32, block: Vec<u8>)
+            -> Result<(), ConnectionError> {
+        let done = match self.current {
+            Some(ref mut pd) if pd.index == index => {
+                let len = block.len() as u32;
+                match pd.pending.iter().position(|&b| b == (begin, len)) {
+                    Some(pos) => { pd.pending.remove(pos); },
+                    // not something we asked for (or we already gave up on it)
+                    None => return Ok(()),
+                }
+                self.transferred.downloaded.fetch_add(block.len(), Ordering::SeqCst);
+                self.disk.write_block(index, begin, block);
+                pd.is_done()
+            },
+            _ => return Ok(()),
+        };
+
+        if done {
+            // the piece stays in progress in the picker until it's hashed
+            let pd = self.current.take().unwrap();
+            let done = on_hashed(&self.shared, &self.info, format!("{}", self.peer));
+            self.disk.hash_piece(pd.index, done);
+        }
+        self.disk.wait_for_room();
+        Ok(())
+    }
+
+    fn handle_reject(&mut self, index: u32, begin: u32, length: u32) {
+        let abandon = match self.current {
+            Some(ref mut pd) if pd.index == index => {
+                if let Some(pos) = pd.pending.iter().position(|&b| b == (begin, length)) {
+                    pd.pending.remove(pos);
+                    pd.unrequested.push((begin, length));
+                }
+                // we can't ask again until we're unchoked, so let someone
+                // else have the piece
+                self.peer_choking && !self.allowed_fast.contains(&index)
+            },
+            _ => false,
+        };
+
+        if abandon {
+            self.abandon_piece();
+        }
+    }
+
+    fn abandon_piece(&mut self) {
+        if let Some(pd) = self.current.take() {
+            self.disk.discard(pd.index);
+            self.shared.lock().unwrap().picker.abort(pd.index);
+        }
+    }
+
+    fn send_new_haves(&mut self) -> Result<(), ConnectionError> {
+        let new = {
+            let shared = self.shared.lock().unwrap();
+            let new = shared.completed[self.haves_sent..].to_vec();
+            self.haves_sent = shared.completed.len();
+            new
+        };
+
+        for i in new {
+            try!(self.send(Message::Have(i)));
+        }
+        Ok(())
+    }
+
+    fn update_interest(&mut self) -> Result<(), ConnectionError> {
+        let wants = self.shared.lock().unwrap().picker.wants_from(&self.peer_has);
+        if wants && !self.am_interested {
+            self.am_interested = true;
+            try!(self.send(Message::Interested));
+        } else if !wants && self.am_interested {
+            self.am_interested = false;
+            try!(self.send(Message::NotInterested));
+        }
+        Ok(())
+    }
+
+    // Keeps the request pipeline full. While choked we can still download
+    // pieces in the peer's allowed fast set.
+    fn request_blocks(&mut self) -> Result<(), ConnectionError> {
+        if self.current.is_none() {
+            if self.peer_choking && self.allowed_fast.is_empty() {
+                return Ok(());
+            }
+
+            let picked = {
+                let allowed = if self.peer_choking { Some(&self.allowed_fast[..]) } else { None };
+                let mut shared = self.shared.lock().unwrap();
+                shared.picker.pick(&self.peer_has, allowed, &self.suggested)
+            };
+            self.current = picked.map(|i| PieceDownload::new(i, self.info.piece_size(i)));
+        }
+
+        let depth = self.pipeline_depth();
+        let mut requests = Vec::new();
+        if let Some(ref mut pd) = self.current {
+            if self.peer_choking && !self.allowed_fast.contains(&pd.index) {
+                return Ok(());
+            }
+
+            while pd.pending.len() < depth {
+                match pd.unrequested.pop() {
+                    Some((begin, length)) => {
+                        pd.pending.push((begin, length));
+                        requests.push(Message::Request(pd.index, begin, length));
+                    },
+                    None => break,
+                }
+            }
+        }
+
+        for msg in requests {
+            try!(self.send(msg));
+        }
+        Ok(())
+    }
+
+    // give back everything this connection held in the shared state
+    fn close(&mut self) {
+        self.abandon_piece();
+        self.shared.lock().unwrap().picker.peer_gone(&self.peer_has);
+    }
+}
+
+fn connect_peer(peer: Peer, ctx: DownloadContext) {
+    let addr = peer.addr;
+    log!("trying to  connect to {:?}", addr);
+
+    let handshaken = match open_stream(addr, &ctx) {
+        Ok((stream, hs)) => {
+            run_session(peer, stream, hs, &ctx);
+            true
+        },
+        Err(HandshakeError::ProtocolError(e)) => {
+            log!("bad handshake from {:?}: {}", addr, e);
+            false
+        },
+        Err(HandshakeError::IoError(e)) => {
+            log!("error during handshake with {:?}: {:?}", addr, e);
+            false
+        },
+    };
+
+    let _ = ctx.events.send(ManagerEvent::Closed(addr, handshaken));
+}
+
+fn connect_transport(addr: net::SocketAddr) -> Result<Transport, io::Error> {
+    let mut stream = try!(Transport::connect(addr));
+    log!("successfully connected to {:?}{}", addr,
+         if stream.is_utp() { " over uTP" } else { "" });
+    try!(stream.set_read_timeout(Some(Duration::from_secs(READ_TIMEOUT_SECS))));
+    Ok(stream)
+}
+
+// Connects to `addr` and exchanges handshakes, encrypting the connection
+// as our policy asks. Under `PreferEncrypted`, a peer that won't do MSE
+// gets a second, plaintext connection.
+fn open_stream(addr: net::SocketAddr, ctx: &DownloadContext)
+        -> Result<(PeerStream<Transport>, PeerHandshake), HandshakeError> {
+    let stream = try!(connect_transport(addr));
+    log!("about to send handshake");
+    let result = mse::connect(stream, &ctx.info.info_hash, ctx.policy, &ctx.handshake);
+
+    let mut stream = match result {
+        Ok(stream) => stream,
+        Err(ref e) if ctx.policy == EncryptionPolicy::PreferEncrypted => {
+            log!("encrypted handshake with {:?} failed ({:?}), retrying in plaintext",
+                 addr, e);
+            let stream = try!(connect_transport(addr));
+            try!(mse::connect(stream, &ctx.info.info_hash,
+                              EncryptionPolicy::PlaintextOnly, &ctx.handshake))
+        },
+        Err(e) => return Err(HandshakeError::from(e)),
+    };
+
+    let hs = try!(receive_handshake(&mut stream));
+    if hs.info_hash != ctx.info.info_hash {
+        return Err(HandshakeError::ProtocolError(String::from("Info hash doesn't match")));
+    }
+    Ok((stream, hs))
+}
+
+// Handles a connection from one of the session's listeners: MSE (if any),
+// then the peer's handshake, which tells us which torrent it's for. The
+// torrent sends our handshake if it has room for the connection.
+pub fn accept_peer(stream: Transport, torrents: Torrents, policy: EncryptionPolicy) {
+    let addr = match stream.peer_addr() {
+        Ok(addr) => addr,
+        Err(_) => return,
+    };
+    log!("incoming connection from {:?}", addr);
+
+    let info_hashes: Vec<Vec<u8>> = torrents.lock().unwrap().keys().cloned().collect();
+    match accept_stream(stream, &info_hashes, policy) {
+        Ok((stream, hs)) => {
+            let torrents = torrents.lock().unwrap();
+            match torrents.get(&hs.info_hash) {
+                Some(torrent) => {
+                    let _ = torrent.events.send(ManagerEvent::Incoming(addr, stream, hs));
+                },
+                None => log!("{:?} asked for a torrent we don't have", addr),
+            }
+        },
+        Err(HandshakeError::ProtocolError(e)) =>
+            log!("bad handshake from {:?}: {}", addr, e),
+        Err(HandshakeError::IoError(e)) =>
+            log!("error during handshake with {:?}: {:?}", addr, e),
+    }
+}
+
+fn accept_stream(mut stream: Transport, info_hashes: &[Vec<u8>], policy: EncryptionPolicy)
+        -> Result<(PeerStream<Transport>, PeerHandshake), HandshakeError> {
+    try!(stream.set_read_timeout(Some(Duration::from_secs(READ_TIMEOUT_SECS))));
+    let (mut stream, skey) = try!(mse::accept(stream, info_hashes, policy));
+    let hs = try!(receive_handshake(&mut stream));
+    // an encrypted connection is keyed to one torrent, and the handshake
+    // inside it has to be for that one
+    if let Some(skey) = skey {
+        if hs.info_hash != skey {
+            return Err(HandshakeError::ProtocolError(
+                String::from("Info hash doesn't match the encryption key")));
+        }
+    }
+    Ok((stream, hs))
+}
+
+// Finishes a connection from `accept_peer` once the torrent has taken it
+fn run_inbound(addr: net::SocketAddr, mut stream: PeerStream<Transport>, hs: PeerHandshake,
+               ctx: DownloadContext) {
+    match stream.write_all(&ctx.handshake) {
+        Ok(()) => run_session(Peer::from_socketaddr(addr), stream, hs, &ctx),
+        Err(e) => log!("error during handshake with {:?}: {:?}", addr, e),
+    }
+    let _ = ctx.events.send(ManagerEvent::InboundClosed);
+}
+
+// Runs the peer wire protocol on an established connection
+fn run_session(mut peer: Peer, mut stream: PeerStream<Transport>, hs: PeerHandshake,
+               ctx: &DownloadContext) {
+    let addr = peer.addr;
+    log!("handshake from {:?}, client: {}{}", addr, peer_id::describe(&hs.peer_id),
+         if stream.is_encrypted() { ", encrypted" } else { "" });
+    peer.set_peer_id(&hs.peer_id);
+
+    // wake up every tick even if the peer has nothing to say
+    if let Err(e) = stream.get_mut().set_read_timeout(Some(Duration::from_secs(TICK_SECS))) {
+        log!("error setting up connection to {:?}: {:?}", addr, e);
+        return;
+    }
+
+    // we made the connection or were sent one, so this peer is reachable
+    let mut flags = pex::FLAG_REACHABLE;
+    if stream.is_encrypted() {
+        flags |= pex::FLAG_PREFERS_ENCRYPTION;
+    }
+    if stream.get_ref().is_utp() {
+        flags |= pex::FLAG_SUPPORTS_UTP;
+    }
+    ctx.connected.lock().unwrap().insert(addr, flags);
+
+    let extensions = extensions_for(ctx, addr);
+    let mut conn = PeerConnection::new(peer, stream, &hs, extensions, ctx);
+    match conn.run() {
+        Ok(()) => log!("done with {}", conn.peer),
+        Err(e) => log!("connection to {} closed: {:?}", conn.peer, e),
+    }
+    conn.close();
+
+    ctx.connected.lock().unwrap().remove(&addr);
+}
 
-    for peer in peers {
-        println!("trying to  connect to {:?}", peer.addr);
-        let mut stream = match TcpStream::connect(peer.addr) {
-            Ok(s) => { println!("successfully connected to {:?}", peer.addr); s },
-            Err(_) => continue,
+// Downloads pieces from a web or HTTP seed until the torrent is complete
+// or the seed has failed too many times in a row
+fn run_web_seed(mut seed: Box<Seed>, ctx: DownloadContext) {
+    // a web seed has every piece
+    let everything = Bitfield::full(ctx.info.num_pieces());
+
+    // and is held to the same limits as a peer
+    let seed_rates = ctx.peer_rates.for_peer();
+    let (throttle, _) = ratelimit::throttles(&[&seed_rates, &ctx.rates, &ctx.session_rates]);
+
+    while !seed.backoff().is_dead() && !ctx.stop.load(Ordering::SeqCst) {
+        let wait = seed.backoff().wait_secs();
+        if wait > 0 {
+            thread::sleep(Duration::from_secs(wait as u64));
+        }
+
+        let picked = {
+            let mut shared = ctx.shared.lock().unwrap();
+            if shared.picker.is_finished() {
+                break;
+            }
+            shared.picker.pick(&everything, None, &[])
+        };
+        let index = match picked {
+            Some(index) => index,
+            // everything left is being downloaded by someone else, but
+            // may come back if they give up on it
+            None => {
+                thread::sleep(Duration::from_secs(WEB_SEED_IDLE_SECS));
+                continue;
+            },
         };
 
-        println!("about to send handshake");
-        try!(stream.write_all(&handshake[..]));
-        println!("timeout: {:?}", stream.read_timeout());
-        receive_handshake(&mut stream, info);
+        let data = match seed.fetch_piece(&ctx.info, index, &throttle) {
+            Ok(data) => {
+                ctx.transferred.downloaded.fetch_add(data.len(), Ordering::SeqCst);
+                data
+            },
+            Err(WebSeedError::RetryAfter(secs)) => {
+                log!("web seed {} is busy, retrying in {}s", seed.url(), secs);
+                ctx.shared.lock().unwrap().picker.abort(index);
+                seed.backoff().retry_in(secs);
+                continue;
+            },
+            Err(WebSeedError::RangesUnsupported) => {
+                log!("web seed {} doesn't support ranges, dropping it", seed.url());
+                ctx.shared.lock().unwrap().picker.abort(index);
+                break;
+            },
+            Err(e) => {
+                log!("web seed {} failed on piece {}: {:?}", seed.url(), index, e);
+                ctx.shared.lock().unwrap().picker.abort(index);
+                seed.backoff().failed();
+                continue;
+            },
+        };
+
+        // wait for the hash, which decides whether the seed is any good
+        let (tx, rx) = mpsc::channel();
+        let done = on_hashed(&ctx.shared, &ctx.info, format!("web seed {}", seed.url()));
+        ctx.disk.write_block(index, 0, data);
+        ctx.disk.hash_piece(index, Box::new(move |index, result| {
+            let passed = result.as_ref().ok().cloned();
+            done(index, result);
+            let _ = tx.send(passed);
+        }));
+        match rx.recv() {
+            Ok(Some(true)) => seed.backoff().succeeded(),
+            Ok(Some(false)) => seed.backoff().failed(),
+            // the piece couldn't be stored, so the torrent has failed
+            _ => break,
+        }
     }
 
-    Ok(())
+    log!("done with web seed {}", seed.url());
+    let _ = ctx.events.send(ManagerEvent::WebSeedClosed);
+}
+
+// Events sent to a torrent's connection manager, by its connection threads
+// and by the session
+pub enum ManagerEvent {
+    // peers we learned about from a tracker, another peer or the local network
+    Discovered(PeerSource, Vec<Peer>),
+
+    // the connection we made to this address ended; true if it got as far
+    // as a handshake
+    Closed(net::SocketAddr, bool),
+
+    // a peer connected to us and sent a handshake for this torrent
+    Incoming(net::SocketAddr, PeerStream<Transport>, PeerHandshake),
+
+    // a connection from `Incoming` ended
+    InboundClosed,
+
+    // a web seed thread finished
+    WebSeedClosed,
+
+    // time to ask the trackers for peers again
+    Reannounce,
+
+    // hash the data on disk again
+    Recheck,
+
+    // new priorities for the torrent's files, in `MetaInfo::files()` order
+    SetFilePriorities(Vec<FilePriority>),
+
+    SetSequential(bool),
+
+    // pieces a reader needs, with when it needs them by (in
+    // `time::precise_time_ns` nanoseconds)
+    SetDeadlines(Vec<(u32, u64)>),
+
+    // move the torrent's files, replying once they've moved
+    MoveStorage(PathBuf, MoveConflict, Sender<Result<(), io::Error>>),
+
+    // give files new paths on disk, as (file index, path under the save
+    // path), replying once they've been renamed
+    RenameFiles(Vec<(usize, PathBuf)>, Sender<Result<(), io::Error>>),
+
+    // how many peers the torrent may be connected to at once
+    SetMaxConnections(usize),
+
+    // asks for the torrent's peer list
+    Peers(Sender<Vec<PeerInfo>>),
+
+    Pause,
+    Resume,
+    Remove,
+}
+
+// Everything a connection thread needs from its download
+#[derive(Clone)]
+struct DownloadContext {
+    info: Arc<MetaInfo>,
+    shared: Arc<Mutex<Shared>>,
+    disk: Disk,
+    handshake: Vec<u8>,
+    peer_id: String,
+    connected: pex::ConnectedPeers,
+    events: Sender<ManagerEvent>,
+    policy: EncryptionPolicy,
+
+    // set when the torrent is paused or removed, so connections wind down
+    stop: Arc<AtomicBool>,
+
+    // where the torrent's files are, shared with its session
+    save_path: Arc<Mutex<PathBuf>>,
+    file_paths: Arc<Mutex<Vec<PathBuf>>>,
+
+    // bandwidth limits for the torrent, for each of its peers and for the
+    // whole session
+    rates: RateLimits,
+    peer_rates: RateLimits,
+    session_rates: RateLimits,
+
+    transferred: Arc<Transferred>,
+}
+
+// Payload bytes sent and received since we last told the trackers we'd
+// started, which they're told with every announce
+struct Transferred {
+    uploaded: AtomicUsize,
+    downloaded: AtomicUsize,
+}
+
+// Decides which peers to connect to, as connection slots free up
+struct ConnectionManager {
+    peers: PeerList,
+
+    // number of connection threads running
+    active: usize,
+
+    // how many of them there may be
+    max_connections: usize,
+
+    // number of web seed threads running
+    web_seeds: usize,
+
+    // shared by all the torrents in the session
+    limit: Arc<ConnectionLimit>,
+}
+
+impl ConnectionManager {
+    fn new(max_connections: usize, limit: Arc<ConnectionLimit>) -> ConnectionManager {
+        ConnectionManager {
+            peers: PeerList::new(),
+            active: 0,
+            max_connections: max_connections,
+            web_seeds: 0,
+            limit: limit,
+        }
+    }
+
+    fn add(&mut self, info: &MetaInfo, source: PeerSource, peers: Vec<Peer>) {
+        if !source.allowed_for(info) {
+            log!("ignoring {} peers from {:?} for private torrent", peers.len(), source);
+            return;
+        }
+        self.peers.add(source, peers);
+    }
+
+    // takes a connection slot, both ours and the session's
+    fn acquire(&mut self) -> bool {
+        if self.active >= self.max_connections || !self.limit.acquire() {
+            return false;
+        }
+        self.active += 1;
+        true
+    }
+
+    // whether there's a free slot for a peer that connected to us
+    fn accept(&mut self) -> bool {
+        self.acquire()
+    }
+
+    // the best peer to connect to, if we have a free slot
+    fn next(&mut self) -> Option<Peer> {
+        let peer = match self.peers.best() {
+            Some(peer) => peer,
+            None => return None,
+        };
+        if !self.acquire() {
+            return None;
+        }
+        self.peers.connecting(&peer.addr);
+        Some(peer)
+    }
+
+    // a connection from `accept` ended
+    fn closed(&mut self) {
+        self.active -= 1;
+        self.limit.release();
+    }
+
+    // a connection from `next` ended
+    fn closed_outgoing(&mut self, addr: &net::SocketAddr, handshaken: bool) {
+        self.closed();
+        self.peers.closed(addr, handshaken);
+    }
+
+    fn is_idle(&self) -> bool {
+        self.active == 0 && self.web_seeds == 0
+    }
+}
+
+// Tells the trackers how we're doing and, unless we're stopping, asks them
+// for peers
+fn announce(ctx: DownloadContext, event: EventType) {
+    let left = {
+        let shared = ctx.shared.lock().unwrap();
+        if shared.picker.is_finished() {
+            0
+        } else {
+            let have = shared.picker.have().iter_set()
+                             .fold(0, |n, index| n + ctx.info.piece_size(index) as u64);
+            ctx.info.num_file_bytes() - have
+        }
+    };
+    let stats = tracker::Announce {
+        uploaded: ctx.transferred.uploaded.load(Ordering::SeqCst) as u64,
+        downloaded: ctx.transferred.downloaded.load(Ordering::SeqCst) as u64,
+        left: left,
+        event: event,
+    };
+    match tracker::get_tracker(&ctx.info, ctx.peer_id.clone(), &stats) {
+        Ok(peers) => if event != EventType::Stopped {
+            let _ = ctx.events.send(ManagerEvent::Discovered(PeerSource::Tracker, peers));
+        },
+        Err(e) => log!("couldn't announce to trackers: {:?}", e),
+    }
+}
+
+// Announces without holding up the connection manager. The counts start
+// over with each `Started`, as trackers expect.
+fn start_announce(ctx: &DownloadContext, event: EventType) {
+    if event == EventType::Started {
+        ctx.transferred.uploaded.store(0, Ordering::SeqCst);
+        ctx.transferred.downloaded.store(0, Ordering::SeqCst);
+    }
+    let ctx = ctx.clone();
+    thread::spawn(move || announce(ctx, event));
+}
+
+// Sends `Reannounce` now and every `REANNOUNCE_SECS`, until the torrent is gone
+fn reannounce_timer(events: Sender<ManagerEvent>) {
+    while events.send(ManagerEvent::Reannounce).is_ok() {
+        thread::sleep(Duration::from_secs(REANNOUNCE_SECS));
+    }
+}
+
+// Starts a thread for each web and HTTP seed, unless they're still running:
+// after a pause they only stop once they've finished what they were doing,
+// and carry on if the torrent's resumed by then
+fn start_web_seeds(ctx: &DownloadContext, manager: &mut ConnectionManager) {
+    if manager.web_seeds > 0 {
+        return;
+    }
+    let mut seeds: Vec<Box<Seed>> = Vec::new();
+    for url in ctx.info.url_list.iter() {
+        seeds.push(Box::new(WebSeed::new(url.clone())));
+    }
+    for url in ctx.info.http_seeds.iter() {
+        seeds.push(Box::new(HttpSeed::new(url.clone())));
+    }
+    for seed in seeds.into_iter() {
+        let ctx = ctx.clone();
+        manager.web_seeds += 1;
+        thread::spawn(move || run_web_seed(seed, ctx));
+    }
+}
+
+// Hashes the torrent's data, logging progress every 10%. Skipped files'
+// data comes from the part file, as it does for the torrent's storage.
+fn recheck(info: &MetaInfo, root: &Path, paths: &[PathBuf], skip: &[bool])
+        -> Result<Bitfield, io::Error> {
+    let name = info.info.name();
+    let mut last_logged = 0;
+    let paths: Vec<PathBuf> = paths.iter().map(|p| root.join(p)).collect();
+    let part_file = verify::part_file_path(info, root);
+    let result = try!(verify::recheck(info, &paths, skip, &part_file, verify::DEFAULT_THREADS,
+                                      &mut |done, total| {
+        let percent = done as u64 * 100 / total as u64;
+        if percent >= last_logged + 10 {
+            last_logged = percent - percent % 10;
+            log!("{}: checked {}% of pieces", name, last_logged);
+        }
+    }));
+    if !result.corrupt.is_empty() {
+        log!("{}: {} corrupt ranges found", name, result.corrupt.len());
+    }
+    Ok(result.have)
+}
+
+// Called when the pieces we want have changed. If there are new ones, and
+// we'd stopped looking for peers, starts again.
+fn wants_changed(ctx: &DownloadContext, manager: &mut ConnectionManager,
+                 state: &Arc<Mutex<TorrentState>>, was_complete: bool) {
+    let finished = ctx.shared.lock().unwrap().picker.is_finished();
+    set_state(state, if finished { TorrentState::Seeding } else { TorrentState::Downloading });
+    // web seeds stop once there's nothing left, so they need starting too
+    if was_complete && !finished {
+        start_announce(ctx, EventType::Empty);
+        start_web_seeds(ctx, manager);
+    }
+}
+
+fn save_resume(ctx: &DownloadContext) {
+    let data = ResumeData {
+        save_path: ctx.save_path.lock().unwrap().clone(),
+        file_paths: ctx.file_paths.lock().unwrap().clone(),
+        have: Some(ctx.shared.lock().unwrap().picker.have().clone()),
+    };
+    if let Err(e) = resume::save(&ctx.info, &data) {
+        log!("{}: couldn't save resume data: {:?}", ctx.info.info.name(), e);
+    }
+}
+
+fn skipped_files(priorities: &[FilePriority]) -> Vec<bool> {
+    priorities.iter().map(|&p| p == FilePriority::Skip).collect()
+}
+
+fn set_state(state: &Arc<Mutex<TorrentState>>, new: TorrentState) {
+    *state.lock().unwrap() = new;
+}
+
+// Runs one torrent of a session until it's removed: opens its storage,
+// finds peers and keeps connections going while it isn't paused.
+pub fn run_torrent(torrent: TorrentHandle, settings: TorrentSettings, peer_id: String,
+                   policy: EncryptionPolicy, limit: Arc<ConnectionLimit>,
+                   session_rates: RateLimits, disk_pool: Arc<DiskPool>,
+                   rx: Receiver<ManagerEvent>) {
+    let TorrentHandle { info, events, state, verified, save_path, file_paths,
+                        disk: open_disk, rates, peer_rates } = torrent;
+    set_state(&state, TorrentState::Checking);
+    let mut skipped = skipped_files(&settings.file_priorities);
+
+    // resume data is checked before the files are allocated, which would
+    // make missing ones look present
+    let resumed = resume::load(&info, &settings.save_path, &skipped);
+    let (paths, have) = match resumed {
+        Some(resumed) => (resumed.file_paths, resumed.have),
+        None => (storage::default_paths(&info), None),
+    };
+    *file_paths.lock().unwrap() = paths.clone();
+    let opened = Storage::new(&info, &settings.save_path, paths.clone(), &skipped,
+                              settings.allocation, settings.storage_mode)
+                     .and_then(|storage| match have {
+                         Some(have) => Ok((storage, have)),
+                         None => recheck(&info, &settings.save_path, &paths, &skipped)
+                                     .map(|have| (storage, have)),
+                     });
+    let (storage, have) = match opened {
+        Ok(opened) => opened,
+        Err(e) => {
+            set_state(&state, TorrentState::Error(format!("{:?}", e)));
+            verified.close();
+            // stay around until the session lets go of us
+            while let Ok(event) = rx.recv() {
+                if let ManagerEvent::Remove = event {
+                    break;
+                }
+            }
+            return;
+        },
+    };
+    log!("{}: {} of {} pieces already on disk", info.info.name(), have.count(),
+         info.num_pieces());
+
+    let mut picker = Picker::new(info.num_pieces());
+    picker.set_priorities(picker::piece_priorities(&info, &settings.file_priorities));
+    picker.set_sequential(settings.sequential);
+    for index in have.iter_set() {
+        picker.complete(index);
+    }
+    // readers go through the disk, so it's there before they can read
+    let disk = Disk::new(info.clone(), storage, disk_pool);
+    *open_disk.lock().unwrap() = Some(disk.clone());
+    verified.set(have);
+    set_state(&state, if picker.is_finished() { TorrentState::Seeding }
+                      else { TorrentState::Downloading });
+
+    let handshake = create_handshake(&info, peer_id.clone());
+    let shared = Arc::new(Mutex::new(Shared {
+        picker: picker,
+        completed: Vec::new(),
+        state: state.clone(),
+        verified: verified.clone(),
+    }));
+
+    let ctx = DownloadContext {
+        info: info,
+        shared: shared.clone(),
+        disk: disk,
+        handshake: handshake,
+        peer_id: peer_id,
+        connected: Arc::new(Mutex::new(HashMap::new())),
+        events: events.clone(),
+        policy: policy,
+        stop: Arc::new(AtomicBool::new(false)),
+        save_path: save_path,
+        file_paths: file_paths,
+        rates: rates,
+        peer_rates: peer_rates,
+        session_rates: session_rates,
+        transferred: Arc::new(Transferred {
+            uploaded: AtomicUsize::new(0),
+            downloaded: AtomicUsize::new(0),
+        }),
+    };
+
+    {
+        let events = ctx.events.clone();
+        thread::spawn(move || reannounce_timer(events));
+    }
+
+    let mut manager = ConnectionManager::new(settings.max_connections, limit);
+    start_web_seeds(&ctx, &mut manager);
+
+    let mut paused = false;
+    let mut removed = false;
+    let mut was_complete = shared.lock().unwrap().picker.is_finished();
+
+    // whether the trackers think we're running, and whether they know
+    // we've finished (which they do if we started out that way)
+    let mut started = false;
+    let mut sent_completed = was_complete;
+    while !(removed && manager.is_idle()) {
+        let complete = shared.lock().unwrap().picker.is_finished();
+        if complete && !was_complete {
+            save_resume(&ctx);
+            if !sent_completed {
+                sent_completed = true;
+                if started {
+                    start_announce(&ctx, EventType::Completed);
+                }
+            }
+        }
+        was_complete = complete;
+        while !complete && !paused && !removed {
+            match manager.next() {
+                Some(peer) => {
+                    let ctx = ctx.clone();
+                    thread::spawn(move || connect_peer(peer, ctx));
+                },
+                None => break,
+            }
+        }
+
+        match rx.recv() {
+            Ok(ManagerEvent::Discovered(source, peers)) =>
+                manager.add(&ctx.info, source, peers),
+            Ok(ManagerEvent::Closed(addr, handshaken)) =>
+                manager.closed_outgoing(&addr, handshaken),
+            Ok(ManagerEvent::InboundClosed) => manager.closed(),
+            Ok(ManagerEvent::WebSeedClosed) => manager.web_seeds -= 1,
+            Ok(ManagerEvent::Incoming(addr, stream, hs)) => {
+                if !paused && !removed && manager.accept() {
+                    let ctx = ctx.clone();
+                    thread::spawn(move || run_inbound(addr, stream, hs, ctx));
+                }
+            },
+            // seeds keep announcing too, so that peers can find them
+            Ok(ManagerEvent::Reannounce) => {
+                if !paused && !removed {
+                    start_announce(&ctx, if started { EventType::Empty }
+                                         else { EventType::Started });
+                    started = true;
+                }
+            },
+            Ok(ManagerEvent::Recheck) => {
+                set_state(&state, TorrentState::Checking);
+                let root = ctx.save_path.lock().unwrap().clone();
+                let paths = ctx.file_paths.lock().unwrap().clone();
+                match recheck(&ctx.info, &root, &paths, &skipped) {
+                    Ok(have) => {
+                        verified.set(have.clone());
+                        let complete = {
+                            let mut shared = shared.lock().unwrap();
+                            shared.picker.reset_have(have);
+                            shared.picker.is_finished()
+                        };
+                        set_state(&state, if paused { TorrentState::Paused }
+                                          else if complete { TorrentState::Seeding }
+                                          else { TorrentState::Downloading });
+                    },
+                    Err(e) => set_state(&state, TorrentState::Error(format!("{:?}", e))),
+                }
+            },
+            Ok(ManagerEvent::SetFilePriorities(priorities)) => {
+                shared.lock().unwrap().picker.set_priorities(
+                    picker::piece_priorities(&ctx.info, &priorities));
+                skipped = skipped_files(&priorities);
+                match ctx.disk.set_skipped(&skipped) {
+                    Ok(()) => {
+                        if !paused && !removed {
+                            wants_changed(&ctx, &mut manager, &state, complete);
+                        }
+                    },
+                    Err(e) => set_state(&state, TorrentState::Error(format!("{:?}", e))),
+                }
+            },
+            Ok(ManagerEvent::SetSequential(sequential)) =>
+                shared.lock().unwrap().picker.set_sequential(sequential),
+            Ok(ManagerEvent::SetDeadlines(deadlines)) => {
+                {
+                    let mut shared = shared.lock().unwrap();
+                    for (index, deadline) in deadlines.into_iter() {
+                        shared.picker.set_deadline(index, deadline);
+                    }
+                }
+                if !paused && !removed {
+                    wants_changed(&ctx, &mut manager, &state, complete);
+                }
+            },
+            Ok(ManagerEvent::MoveStorage(path, conflict, reply)) => {
+                let result = ctx.disk.move_storage(&path, conflict).map(|needs_recheck| {
+                    let old = mem::replace(&mut *ctx.save_path.lock().unwrap(), path);
+                    resume::remove(&ctx.info, &old);
+                    save_resume(&ctx);
+                    if needs_recheck {
+                        let _ = ctx.events.send(ManagerEvent::Recheck);
+                    }
+                });
+                let _ = reply.send(result);
+            },
+            Ok(ManagerEvent::RenameFiles(renames, reply)) => {
+                let result = ctx.disk.rename_files(&renames).map(|paths| {
+                    *ctx.file_paths.lock().unwrap() = paths;
+                    save_resume(&ctx);
+                });
+                let _ = reply.send(result);
+            },
+            Ok(ManagerEvent::SetMaxConnections(max)) => manager.max_connections = max,
+            Ok(ManagerEvent::Peers(reply)) => {
+                let _ = reply.send(manager.peers.peers());
+            },
+            Ok(ManagerEvent::Pause) => {
+                paused = true;
+                ctx.stop.store(true, Ordering::SeqCst);
+                set_state(&state, TorrentState::Paused);
+                save_resume(&ctx);
+                if started {
+                    start_announce(&ctx, EventType::Stopped);
+                    started = false;
+                }
+            },
+            Ok(ManagerEvent::Resume) => {
+                if paused && !removed {
+                    paused = false;
+                    ctx.stop.store(false, Ordering::SeqCst);
+                    set_state(&state, if complete { TorrentState::Seeding }
+                                      else { TorrentState::Downloading });
+                    start_announce(&ctx, EventType::Started);
+                    started = true;
+                    if !complete {
+                        start_web_seeds(&ctx, &mut manager);
+                    }
+                }
+            },
+            Ok(ManagerEvent::Remove) => {
+                removed = true;
+                ctx.stop.store(true, Ordering::SeqCst);
+                verified.close();
+                *open_disk.lock().unwrap() = None;
+                save_resume(&ctx);
+                if started {
+                    start_announce(&ctx, EventType::Stopped);
+                    started = false;
+                }
+            },
+            Err(_) => break,
+        }
+    }
+    verified.close();
 }
@@ -0,0 +1,269 @@
+// Extension protocol (BEP 10).
+
+use message::Message;
+use util;
+
+use bencode::{self, Bencode};
+use std::collections::BTreeMap;
+use std::net;
+
+// Reserved bit a peer sets in its handshake to advertise the extension protocol
+pub const RESERVED_BYTE: usize = 5;
+pub const RESERVED_BIT: u8 = 0x10;
+
+// extended message id of the extended handshake itself
+pub const HANDSHAKE_ID: u8 = 0;
+
+// number of outstanding requests we tell peers we'll queue
+pub const REQQ: i64 = 250;
+
+pub fn set_reserved_bit(reserved: &mut [u8; 8]) {
+    reserved[RESERVED_BYTE] |= RESERVED_BIT;
+}
+
+pub fn supported(reserved: &[u8; 8]) -> bool {
+    reserved[RESERVED_BYTE] & RESERVED_BIT != 0
+}
+
+pub fn client_version() -> String {
+    format!("deluge-rs {}", env!("CARGO_PKG_VERSION"))
+}
+
+// The bencoded dictionary sent as extended message 0
+#[derive(Debug, Clone, Default)]
+pub struct ExtendedHandshake {
+    // extension name -> message id the sender wants to receive it as.
+    // An id of 0 means the extension is disabled.
+    pub m: BTreeMap<String, u8>,
+
+    // client name and version
+    pub v: Option<String>,
+
+    // TCP port the sender listens on
+    pub p: Option<u16>,
+
+    // number of outstanding requests the sender will queue
+    pub reqq: Option<i64>,
+
+    // the receiver's IP address as the sender sees it, 4 or 16 bytes
+    pub yourip: Option<Vec<u8>>,
+
+    // size of the info dict in bytes, for ut_metadata
+    pub metadata_size: Option<i64>,
+}
+
+impl ExtendedHandshake {
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut m = BTreeMap::new();
+        for (name, &id) in self.m.iter() {
+            m.insert(bencode::util::ByteString::from_str(name), Bencode::Number(id as i64));
+        }
+
+        let mut pairs = vec![("m", Bencode::Dict(m))];
+        if let Some(ref v) = self.v {
+            pairs.push(("v", Bencode::ByteString(v.clone().into_bytes())));
+        }
+        if let Some(p) = self.p {
+            pairs.push(("p", Bencode::Number(p as i64)));
+        }
+        if let Some(reqq) = self.reqq {
+            pairs.push(("reqq", Bencode::Number(reqq)));
+        }
+        if let Some(ref ip) = self.yourip {
+            pairs.push(("yourip", Bencode::ByteString(ip.clone())));
+        }
+        if let Some(size) = self.metadata_size {
+            pairs.push(("metadata_size", Bencode::Number(size)));
+        }
+
+        util::bencode_dict(pairs).to_bytes().unwrap()
+    }
+
+    // Unknown keys and keys of the wrong type are ignored, as BEP 10 asks
+    pub fn from_bytes(bytes: &[u8]) -> Result<ExtendedHandshake, String> {
+        let b = match bencode::from_buffer(bytes) {
+            Ok(b) => b,
+            Err(e) => return Err(format!("Error decoding extended handshake: {:?}", e)),
+        };
+
+        let map = match b {
+            Bencode::Dict(map) => map,
+            _ => return Err(String::from("Extended handshake is not a dictionary")),
+        };
+
+        let mut hs = ExtendedHandshake::default();
+
+        if let Some(Bencode::Dict(m)) = util::maybe_get_field(&map, "m") {
+            for (name, id) in m.into_iter() {
+                let name = match String::from_utf8(name.as_slice().to_vec()) {
+                    Ok(name) => name,
+                    Err(_) => continue,
+                };
+                match id {
+                    Bencode::Number(id) if id >= 0 && id <= 255 => {
+                        hs.m.insert(name, id as u8);
+                    },
+                    _ => {},
+                }
+            }
+        }
+
+        if let Some(Bencode::ByteString(v)) = util::maybe_get_field(&map, "v") {
+            hs.v = String::from_utf8(v).ok();
+        }
+        if let Some(Bencode::Number(p)) = util::maybe_get_field(&map, "p") {
+            if p > 0 && p <= 65535 {
+                hs.p = Some(p as u16);
+            }
+        }
+        if let Some(Bencode::Number(reqq)) = util::maybe_get_field(&map, "reqq") {
+            hs.reqq = Some(reqq);
+        }
+        if let Some(Bencode::ByteString(ip)) = util::maybe_get_field(&map, "yourip") {
+            if ip.len() == 4 || ip.len() == 16 {
+                hs.yourip = Some(ip);
+            }
+        }
+        if let Some(Bencode::Number(size)) = util::maybe_get_field(&map, "metadata_size") {
+            if size > 0 {
+                hs.metadata_size = Some(size);
+            }
+        }
+
+        Ok(hs)
+    }
+
+    // message id the sender assigned to extension `name`, if it supports it
+    pub fn id_for(&self, name: &str) -> Option<u8> {
+        match self.m.get(name) {
+            Some(&0) | None => None,
+            Some(&id) => Some(id),
+        }
+    }
+}
+
+pub fn compact_ip(ip: &net::IpAddr) -> Vec<u8> {
+    match *ip {
+        net::IpAddr::V4(ip) => ip.octets().to_vec(),
+        net::IpAddr::V6(ip) => {
+            let mut v = Vec::with_capacity(16);
+            for seg in ip.segments().iter() {
+                v.extend(util::u16_to_be_bytes(*seg).iter());
+            }
+            v
+        },
+    }
+}
+
+// An extension that runs over extended messages. Each peer connection
+// gets its own instance of every registered handler.
+pub trait ExtensionHandler: Send {
+    // name the extension is negotiated under in the `m` dictionary
+    fn name(&self) -> &'static str;
+
+    // add anything this extension needs to our extended handshake
+    fn extend_handshake(&self, _hs: &mut ExtendedHandshake) {}
+
+    // called with the peer's extended handshake, if it supports this extension
+    fn on_handshake(&mut self, _hs: &ExtendedHandshake) {}
+
+    // Handles a message the peer sent us. Returns the payloads of any
+    // messages to send back.
+    fn on_message(&mut self, payload: &[u8]) -> Result<Vec<Vec<u8>>, String>;
+
+    // Called periodically; returns payloads of messages to send unprompted
+    fn tick(&mut self) -> Vec<Vec<u8>> {
+        Vec::new()
+    }
+}
+
+// The extensions available on a connection, by name
+pub struct Extensions {
+    // our message id for handlers[i] is i + 1
+    handlers: Vec<Box<ExtensionHandler>>,
+
+    // the peer's extended handshake, once received
+    remote: Option<ExtendedHandshake>,
+}
+
+impl Extensions {
+    pub fn new() -> Extensions {
+        Extensions {
+            handlers: Vec::new(),
+            remote: None,
+        }
+    }
+
+    pub fn register(&mut self, handler: Box<ExtensionHandler>) {
+        assert!(self.handlers.len() < 255);
+        assert!(self.handlers.iter().all(|h| h.name() != handler.name()));
+        self.handlers.push(handler);
+    }
+
+    pub fn remote(&self) -> Option<&ExtendedHandshake> {
+        self.remote.as_ref()
+    }
+
+    // Our extended handshake for a peer at `peer_ip`
+    pub fn handshake(&self, peer_ip: &net::IpAddr, port: Option<u16>) -> Message {
+        let mut hs = ExtendedHandshake::default();
+        for (i, h) in self.handlers.iter().enumerate() {
+            hs.m.insert(String::from(h.name()), (i + 1) as u8);
+        }
+        hs.v = Some(client_version());
+        hs.p = port;
+        hs.reqq = Some(REQQ);
+        hs.yourip = Some(compact_ip(peer_ip));
+
+        for h in self.handlers.iter() {
+            h.extend_handshake(&mut hs);
+        }
+
+        Message::Extended(HANDSHAKE_ID, hs.to_bytes())
+    }
+
+    // Dispatches an extended message to its handler, returning the
+    // messages to reply with.
+    pub fn on_message(&mut self, id: u8, payload: &[u8]) -> Result<Vec<Message>, String> {
+        if id == HANDSHAKE_ID {
+            let hs = try!(ExtendedHandshake::from_bytes(payload));
+            for h in self.handlers.iter_mut() {
+                if hs.id_for(h.name()).is_some() {
+                    h.on_handshake(&hs);
+                }
+            }
+            self.remote = Some(hs);
+            return Ok(Vec::new());
+        }
+
+        // an id we never gave out; ignored like unknown message ids
+        let i = (id - 1) as usize;
+        if i >= self.handlers.len() {
+            return Ok(Vec::new());
+        }
+
+        let replies = try!(self.handlers[i].on_message(payload));
+        let name = self.handlers[i].name();
+        Ok(self.wrap(name, replies))
+    }
+
+    pub fn tick(&mut self) -> Vec<Message> {
+        let mut msgs = Vec::new();
+        for i in 0..self.handlers.len() {
+            let payloads = self.handlers[i].tick();
+            let name = self.handlers[i].name();
+            msgs.extend(self.wrap(name, payloads).into_iter());
+        }
+        msgs
+    }
+
+    // address payloads to the id the peer assigned to `name`, dropping
+    // them if the peer doesn't support that extension
+    fn wrap(&self, name: &str, payloads: Vec<Vec<u8>>) -> Vec<Message> {
+        let id = match self.remote.as_ref().and_then(|hs| hs.id_for(name)) {
+            Some(id) => id,
+            None => return Vec::new(),
+        };
+        payloads.into_iter().map(|p| Message::Extended(id, p)).collect()
+    }
+}
@@ -0,0 +1,57 @@
+// Fast extension (BEP 6) helpers.
+
+use util;
+
+use openssl::crypto::hash as openssl_hash;
+use std::net;
+
+// Reserved bit a peer sets in its handshake to advertise the Fast extension
+pub const RESERVED_BYTE: usize = 7;
+pub const RESERVED_BIT: u8 = 0x04;
+
+// Number of pieces in the allowed fast set we give each peer
+pub const ALLOWED_FAST_COUNT: u32 = 10;
+
+pub fn set_reserved_bit(reserved: &mut [u8; 8]) {
+    reserved[RESERVED_BYTE] |= RESERVED_BIT;
+}
+
+pub fn supported(reserved: &[u8; 8]) -> bool {
+    reserved[RESERVED_BYTE] & RESERVED_BIT != 0
+}
+
+// The canonical allowed fast set from BEP 6: pieces a peer at `ip` may
+// request while choked. Only defined for IPv4 peers; everyone else gets
+// an empty set.
+pub fn allowed_fast_set(ip: &net::IpAddr, info_hash: &[u8],
+                        num_pieces: u32, k: u32) -> Vec<u32> {
+    let ip = match *ip {
+        net::IpAddr::V4(ip) => ip,
+        net::IpAddr::V6(_) => return Vec::new(),
+    };
+
+    let k = if k > num_pieces { num_pieces } else { k };
+    let mut set = Vec::with_capacity(k as usize);
+
+    // use the /24 the peer is in, so a peer can't get more pieces by
+    // connecting from several neighbouring addresses
+    let octets = ip.octets();
+    let mut x = vec![octets[0], octets[1], octets[2], 0];
+    x.extend(info_hash.iter());
+
+    while (set.len() as u32) < k {
+        x = openssl_hash::hash(openssl_hash::Type::SHA1, &x[..]);
+        for i in 0..5 {
+            if (set.len() as u32) >= k {
+                break;
+            }
+            let y = util::u32_from_be_bytes(&x[i * 4..i * 4 + 4]);
+            let index = y % num_pieces;
+            if !set.contains(&index) {
+                set.push(index);
+            }
+        }
+    }
+
+    set
+}
@@ -0,0 +1,176 @@
+// Hoffman-style HTTP seeding (BEP 17): a script that serves pieces by
+// index, given the torrent's info hash.
+
+use metainfo::MetaInfo;
+use ratelimit::{Throttle, Throttled};
+use webseed::{Backoff, Seed, WebSeedError};
+
+use hyper::Client;
+use hyper::client::Response;
+use hyper::header::Connection;
+use hyper::status::StatusCode;
+use std::io::Read;
+use std::str;
+use url::percent_encoding::{percent_encode, FORM_URLENCODED_ENCODE_SET};
+
+// how long to wait when the server is busy but doesn't say for how long
+const DEFAULT_RETRY_SECS: i64 = 60;
+
+pub struct HttpSeed {
+    url: String,
+    backoff: Backoff,
+}
+
+impl HttpSeed {
+    pub fn new(url: String) -> HttpSeed {
+        HttpSeed {
+            url: url,
+            backoff: Backoff::new(),
+        }
+    }
+
+    // The URL for piece `index`. `ranges` are (begin, length) pairs within
+    // the piece, sent as BEP 17's inclusive `start-end` byte ranges; with
+    // none, the server sends the whole piece.
+    fn piece_url(&self, info_hash: &[u8], index: u32, ranges: &[(u32, u32)]) -> String {
+        let sep = if self.url.contains('?') { "&" } else { "?" };
+        let mut url = format!("{}{}info_hash={}&piece={}", self.url, sep,
+                              percent_encode(info_hash, FORM_URLENCODED_ENCODE_SET), index);
+        if !ranges.is_empty() {
+            let ranges: Vec<String> = ranges.iter()
+                                            .map(|&(begin, length)| {
+                                                format!("{}-{}", begin, begin + length - 1)
+                                            })
+                                            .collect();
+            url.push_str("&ranges=");
+            url.push_str(&ranges.connect(","));
+        }
+        url
+    }
+
+    // Downloads the `length` bytes of piece `index`. If the server stops
+    // partway through, we keep what we got and only ask for the gap.
+    fn fetch(&self, info_hash: &[u8], index: u32, length: u32, throttle: &Throttle)
+             -> Result<Vec<u8>, WebSeedError> {
+        let mut data = Vec::with_capacity(length as usize);
+        while (data.len() as u32) < length {
+            let begin = data.len() as u32;
+            let ranges = if begin == 0 { Vec::new() } else { vec![(begin, length - begin)] };
+            let url = self.piece_url(info_hash, index, &ranges);
+
+            let mut part = try!(get_piece(&url, (length - begin) as u64, throttle));
+            if part.is_empty() {
+                let msg = format!("short response from {}: {} of {} bytes", url, begin, length);
+                return Err(WebSeedError::HttpError(msg));
+            }
+            data.append(&mut part);
+        }
+        Ok(data)
+    }
+}
+
+impl Seed for HttpSeed {
+    fn url(&self) -> &str {
+        &self.url
+    }
+
+    fn backoff(&mut self) -> &mut Backoff {
+        &mut self.backoff
+    }
+
+    fn fetch_piece(&mut self, info: &MetaInfo, index: u32, throttle: &Throttle)
+                   -> Result<Vec<u8>, WebSeedError> {
+        self.fetch(&info.info_hash, index, info.piece_size(index), throttle)
+    }
+}
+
+// Requests `url` and reads up to `length` bytes of the body. A body that
+// breaks off early is returned as far as it got.
+fn get_piece(url: &str, length: u64, throttle: &Throttle) -> Result<Vec<u8>, WebSeedError> {
+    let client = Client::new();
+    let mut res = try!(client.get(url)
+                             .header(Connection::close())
+                             .send());
+
+    match res.status {
+        StatusCode::Ok => {},
+        StatusCode::ServiceUnavailable => {
+            return Err(WebSeedError::RetryAfter(retry_after(&mut res)));
+        },
+        status => return Err(WebSeedError::HttpError(format!("{} from {}", status, url))),
+    }
+
+    let mut body = Vec::new();
+    let mut res = Throttled::new(res, throttle.clone(), Throttle::unlimited());
+    if let Err(e) = res.by_ref().take(length).read_to_end(&mut body) {
+        if body.is_empty() {
+            return Err(WebSeedError::from(e));
+        }
+        log!("error reading from {} after {} of {} bytes: {:?}", url, body.len(), length, e);
+    }
+    Ok(body)
+}
+
+// BEP 17 servers put the number of seconds to wait in the body of a 503.
+// Some send a Retry-After header instead.
+fn retry_after(res: &mut Response) -> i64 {
+    let header = res.headers.get_raw("Retry-After")
+                            .and_then(|v| v.first())
+                            .and_then(|v| str::from_utf8(v).ok())
+                            .and_then(|v| v.trim().parse::<i64>().ok());
+    if let Some(secs) = header {
+        return secs;
+    }
+
+    let mut body = Vec::new();
+    if res.by_ref().take(32).read_to_end(&mut body).is_err() {
+        return DEFAULT_RETRY_SECS;
+    }
+    str::from_utf8(&body).ok()
+                         .and_then(|b| b.trim().parse::<i64>().ok())
+                         .unwrap_or(DEFAULT_RETRY_SECS)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::HttpSeed;
+    use ratelimit::Throttle;
+    use util;
+
+    // The first line of each request
+    fn request_lines(requests: Vec<Vec<String>>) -> Vec<String> {
+        requests.into_iter().map(|head| head[0].clone()).collect()
+    }
+
+    #[test]
+    fn piece_url() {
+        let seed = HttpSeed::new(String::from("http://example.com/seed.php?key=1"));
+        assert_eq!(seed.piece_url(&[0xab, 0x01], 3, &[]),
+                   "http://example.com/seed.php?key=1&info_hash=%AB%01&piece=3");
+        assert_eq!(seed.piece_url(&[0xab], 3, &[(0, 16384), (32768, 16384)]),
+                   "http://example.com/seed.php?key=1&info_hash=%AB&piece=3\
+                    &ranges=0-16383,32768-49151");
+    }
+
+    #[test]
+    fn whole_piece() {
+        let (url, server) = util::serve_http("/seed", vec![
+            "HTTP/1.1 200 OK\r\nContent-Length: 6\r\n\r\nabcdef"]);
+        let seed = HttpSeed::new(url);
+        assert_eq!(seed.fetch(&[1], 0, 6, &Throttle::unlimited()).unwrap(), b"abcdef".to_vec());
+        assert_eq!(request_lines(server.join().unwrap()),
+                   vec!["GET /seed?info_hash=%01&piece=0 HTTP/1.1"]);
+    }
+
+    #[test]
+    fn gap_after_short_response() {
+        let (url, server) = util::serve_http("/seed", vec![
+            "HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nab",
+            "HTTP/1.1 200 OK\r\nContent-Length: 4\r\n\r\ncdef"]);
+        let seed = HttpSeed::new(url);
+        assert_eq!(seed.fetch(&[1], 5, 6, &Throttle::unlimited()).unwrap(), b"abcdef".to_vec());
+        assert_eq!(request_lines(server.join().unwrap()),
+                   vec!["GET /seed?info_hash=%01&piece=5 HTTP/1.1",
+                        "GET /seed?info_hash=%01&piece=5&ranges=2-5 HTTP/1.1"]);
+    }
+}
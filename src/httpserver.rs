@@ -0,0 +1,234 @@
+// Serves the files of a session's torrents over HTTP while they download,
+// at /<info hash in hex>/<path of the file in the torrent>. Range requests
+// are supported so that players can seek; reads wait for the pieces they
+// cover to be verified.
+
+use session::Torrents;
+use stream::TorrentFile;
+use util;
+
+use hyper::{self, Server};
+use hyper::header::ContentLength;
+use hyper::method::Method;
+use hyper::net::Fresh;
+use hyper::server::{Handler, Listening, Request, Response};
+use hyper::status::StatusCode;
+use hyper::uri::RequestUri;
+use std::cmp;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::str;
+use url::percent_encoding::lossy_utf8_percent_decode;
+
+// Requests are handled one per thread, and a thread can be waiting on a
+// piece for a long time
+pub const SERVER_THREADS: usize = 16;
+
+const COPY_BUFFER_SIZE: usize = 16384;
+
+pub struct StreamServer {
+    listening: Listening,
+}
+
+impl StreamServer {
+    pub fn start(torrents: Torrents, port: u16) -> Result<StreamServer, hyper::Error> {
+        let server = try!(Server::http(("0.0.0.0", port)));
+        let listening = try!(server.handle_threads(StreamHandler { torrents: torrents },
+                                                   SERVER_THREADS));
+        Ok(StreamServer { listening: listening })
+    }
+}
+
+// Stops accepting requests; ones already being served carry on
+impl Drop for StreamServer {
+    fn drop(&mut self) {
+        let _ = self.listening.close();
+    }
+}
+
+struct StreamHandler {
+    torrents: Torrents,
+}
+
+#[derive(Debug, PartialEq)]
+enum ByteRange {
+    // no Range header, or one we don't understand
+    Whole,
+
+    // first and last byte, inclusive
+    Partial(u64, u64),
+
+    Unsatisfiable,
+}
+
+impl Handler for StreamHandler {
+    fn handle<'a, 'k>(&'a self, req: Request<'a, 'k>, mut res: Response<'a, Fresh>) {
+        if req.method != Method::Get && req.method != Method::Head {
+            *res.status_mut() = StatusCode::MethodNotAllowed;
+            let _ = res.send(b"");
+            return;
+        }
+
+        let file = match req.uri {
+            RequestUri::AbsolutePath(ref path) => self.open(path),
+            _ => None,
+        };
+        let mut file = match file {
+            Some(file) => file,
+            None => {
+                *res.status_mut() = StatusCode::NotFound;
+                let _ = res.send(b"no such file\n");
+                return;
+            },
+        };
+
+        let len = file.len();
+        let range = req.headers.get_raw("Range")
+                               .and_then(|v| v.first())
+                               .and_then(|v| str::from_utf8(v).ok())
+                               .map_or(ByteRange::Whole, |r| parse_range(r, len));
+
+        res.headers_mut().set_raw("Accept-Ranges", vec![b"bytes".to_vec()]);
+        res.headers_mut().set_raw("Content-Type",
+                                  vec![content_type(&file.entry().path).as_bytes().to_vec()]);
+        let (start, end) = match range {
+            ByteRange::Whole => (0, len),
+            ByteRange::Partial(first, last) => {
+                *res.status_mut() = StatusCode::PartialContent;
+                res.headers_mut().set_raw("Content-Range",
+                                          vec![format!("bytes {}-{}/{}", first, last, len)
+                                                   .into_bytes()]);
+                (first, last + 1)
+            },
+            ByteRange::Unsatisfiable => {
+                *res.status_mut() = StatusCode::RangeNotSatisfiable;
+                res.headers_mut().set_raw("Content-Range",
+                                          vec![format!("bytes */{}", len).into_bytes()]);
+                let _ = res.send(b"");
+                return;
+            },
+        };
+        res.headers_mut().set(ContentLength(end - start));
+
+        let mut res = match res.start() {
+            Ok(res) => res,
+            Err(_) => return,
+        };
+        if req.method == Method::Get {
+            if let Err(e) = copy_range(&mut file, start, end, &mut res) {
+                // most likely the client went away
+                log!("stopped serving {}: {:?}", file.entry().path.display(), e);
+                return;
+            }
+        }
+        let _ = res.end();
+    }
+}
+
+impl StreamHandler {
+    // The file at /<info hash>/<path>, ignoring any query string
+    fn open(&self, path: &str) -> Option<TorrentFile> {
+        let path = path.split('?').next().unwrap_or("");
+        let mut parts = path.split('/').filter(|p| !p.is_empty());
+        let info_hash = match parts.next() {
+            Some(hex) => hex.to_lowercase(),
+            None => return None,
+        };
+        let components: Vec<String> = parts.map(|p| lossy_utf8_percent_decode(p.as_bytes()))
+                                           .collect();
+
+        let torrents = self.torrents.lock().unwrap();
+        let torrent = match torrents.values()
+                                    .find(|t| util::to_hex(&t.info.info_hash) == info_hash) {
+            Some(torrent) => torrent,
+            None => return None,
+        };
+        let index = match torrent.info.files().iter().position(|f| f.components == components) {
+            Some(index) => index,
+            None => return None,
+        };
+        TorrentFile::new(torrent, index)
+    }
+}
+
+// The first range of a `bytes=` Range header, for a file `len` bytes long
+fn parse_range(header: &str, len: u64) -> ByteRange {
+    let mut parts = header.trim().splitn(2, '=');
+    if parts.next().map(|unit| unit.trim()) != Some("bytes") {
+        return ByteRange::Whole;
+    }
+    let spec = parts.next().and_then(|ranges| ranges.split(',').next()).unwrap_or("");
+    let mut ends = spec.trim().splitn(2, '-').map(|e| e.trim());
+    let (first, last) = match (ends.next(), ends.next()) {
+        (Some(first), Some(last)) => (first, last),
+        _ => return ByteRange::Whole,
+    };
+
+    if first.is_empty() {
+        // the last `last` bytes
+        return match last.parse::<u64>() {
+            Ok(0) => ByteRange::Unsatisfiable,
+            Ok(_) if len == 0 => ByteRange::Unsatisfiable,
+            Ok(n) => ByteRange::Partial(len - cmp::min(n, len), len - 1),
+            Err(_) => ByteRange::Whole,
+        };
+    }
+
+    let first = match first.parse::<u64>() {
+        Ok(first) => first,
+        Err(_) => return ByteRange::Whole,
+    };
+    let last = if last.is_empty() {
+        len.saturating_sub(1)
+    } else {
+        match last.parse::<u64>() {
+            Ok(last) if last >= first => cmp::min(last, len.saturating_sub(1)),
+            _ => return ByteRange::Whole,
+        }
+    };
+    if first >= len {
+        return ByteRange::Unsatisfiable;
+    }
+    ByteRange::Partial(first, last)
+}
+
+fn content_type(path: &Path) -> &'static str {
+    let ext = path.extension()
+                  .and_then(|e| e.to_str())
+                  .map(|e| e.to_lowercase())
+                  .unwrap_or(String::new());
+    match &ext[..] {
+        "mp4" | "m4v" => "video/mp4",
+        "mkv" => "video/x-matroska",
+        "webm" => "video/webm",
+        "avi" => "video/x-msvideo",
+        "mp3" => "audio/mpeg",
+        "flac" => "audio/flac",
+        "ogg" => "audio/ogg",
+        "jpg" | "jpeg" => "image/jpeg",
+        "png" => "image/png",
+        "txt" | "nfo" => "text/plain; charset=utf-8",
+        "html" | "htm" => "text/html",
+        "pdf" => "application/pdf",
+        _ => "application/octet-stream",
+    }
+}
+
+// Copies bytes `start` up to `end` of `file` to `out`, waiting for each
+// piece as it's reached
+fn copy_range<W: Write>(file: &mut TorrentFile, start: u64, end: u64, out: &mut W)
+        -> io::Result<()> {
+    try!(file.seek(SeekFrom::Start(start)));
+    let mut buf = vec![0; COPY_BUFFER_SIZE];
+    let mut left = end - start;
+    while left > 0 {
+        let want = cmp::min(left, buf.len() as u64) as usize;
+        let n = try!(file.read(&mut buf[..want]));
+        if n == 0 {
+            break;
+        }
+        try!(out.write_all(&buf[..n]));
+        left -= n as u64;
+    }
+    Ok(())
+}
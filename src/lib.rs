@@ -0,0 +1,61 @@
+#![feature(ip_addr, read_exact)]
+
+extern crate bencode;
+extern crate hyper;
+extern crate libc;
+extern crate openssl;
+extern crate rand;
+extern crate time;
+extern crate url;
+
+// Progress and diagnostic messages. These go to stderr, leaving stdout to
+// whatever program is using the library.
+macro_rules! log {
+    ($($arg:tt)*) => ({
+        use std::io::Write;
+        let _ = writeln!(&mut ::std::io::stderr(), $($arg)*);
+    })
+}
+
+pub mod bitfield;
+pub mod create;
+pub mod message;
+pub mod metainfo;
+pub mod peer_id;
+pub mod tracker;
+pub mod verify;
+
+mod disk;
+mod download;
+mod extension;
+mod fast;
+mod httpseed;
+mod httpserver;
+mod lsd;
+mod metadata;
+mod mmap;
+mod mse;
+mod peerlist;
+mod pex;
+mod picker;
+mod ratelimit;
+mod resume;
+mod session;
+mod storage;
+mod stream;
+mod transport;
+mod util;
+mod utp;
+mod webseed;
+
+pub use httpserver::StreamServer;
+pub use message::{Message, MessageError, read_message, write_message};
+pub use metainfo::{MetaInfo, ParseError};
+pub use mse::EncryptionPolicy;
+pub use peerlist::{PeerInfo, PeerSource};
+pub use picker::FilePriority;
+pub use session::{Session, SessionError, TorrentSettings, TorrentState};
+pub use storage::{Allocation, MoveConflict, StorageMode};
+pub use stream::TorrentFile;
+pub use tracker::{Peer, ScrapeInfo, TrackerError};
+pub use util::to_hex;
@@ -0,0 +1,332 @@
+// Local Service Discovery (BEP 14): finds peers for the same torrent on
+// the local network by multicasting announces.
+
+use tracker::Peer;
+use util;
+
+use libc;
+use rand::{self, Rng};
+use std::collections::HashMap;
+use std::io;
+use std::mem;
+use std::net::{self, Ipv4Addr, SocketAddr, UdpSocket};
+use std::str;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+use time;
+
+pub const MULTICAST_PORT: u16 = 6771;
+
+pub fn multicast_addr() -> Ipv4Addr {
+    Ipv4Addr::new(239, 192, 152, 143)
+}
+
+// BEP 14 asks for an announce every 5 minutes per torrent
+pub const ANNOUNCE_INTERVAL_SECS: i64 = 5 * 60;
+
+// how often the background threads check whether they should stop
+const POLL_MS: u64 = 1000;
+
+fn from_hex(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 {
+        return None;
+    }
+    let mut v = Vec::with_capacity(s.len() / 2);
+    for i in 0..s.len() / 2 {
+        match u8::from_str_radix(&s[i * 2..i * 2 + 2], 16) {
+            Ok(b) => v.push(b),
+            Err(_) => return None,
+        }
+    }
+    Some(v)
+}
+
+pub fn announce_message(info_hash: &[u8], port: u16, cookie: &str) -> String {
+    format!("BT-SEARCH * HTTP/1.1\r\n\
+             Host: {}:{}\r\n\
+             Port: {}\r\n\
+             Infohash: {}\r\n\
+             cookie: {}\r\n\
+             \r\n\r\n",
+            multicast_addr(), MULTICAST_PORT, port, util::to_hex(info_hash), cookie)
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Announce {
+    pub port: u16,
+    pub info_hashes: Vec<Vec<u8>>,
+    pub cookie: Option<String>,
+}
+
+pub fn parse_announce(buf: &[u8]) -> Option<Announce> {
+    let s = match str::from_utf8(buf) {
+        Ok(s) => s,
+        Err(_) => return None,
+    };
+
+    let mut lines = s.split("\r\n");
+    if lines.next() != Some("BT-SEARCH * HTTP/1.1") {
+        return None;
+    }
+
+    let mut port = None;
+    let mut info_hashes = Vec::new();
+    let mut cookie = None;
+    for line in lines {
+        let mut parts = line.splitn(2, ':');
+        let (name, value) = match (parts.next(), parts.next()) {
+            (Some(name), Some(value)) => (name.trim().to_lowercase(), value.trim()),
+            _ => continue,
+        };
+        match &name[..] {
+            "port" => port = value.parse::<u16>().ok(),
+            "infohash" => {
+                if let Some(ih) = from_hex(value) {
+                    if ih.len() == 20 {
+                        info_hashes.push(ih);
+                    }
+                }
+            },
+            "cookie" => cookie = Some(String::from(value)),
+            _ => {},
+        }
+    }
+
+    match port {
+        Some(port) if port != 0 && !info_hashes.is_empty() => Some(Announce {
+            port: port,
+            info_hashes: info_hashes,
+            cookie: cookie,
+        }),
+        _ => None,
+    }
+}
+
+struct Registered {
+    on_discovered: Box<Fn(Vec<Peer>) + Send>,
+
+    // when we last announced it, in seconds since the epoch
+    last_sent: Option<i64>,
+}
+
+// the torrents we announce, by info hash
+type Registry = Arc<Mutex<HashMap<Vec<u8>, Registered>>>;
+
+// Local discovery for a whole session: one socket on the multicast port,
+// which other LSD clients on the host can share, whose announces are handed
+// to the torrents they're for. Stops when dropped.
+pub struct LocalDiscovery {
+    torrents: Registry,
+    stop: Arc<AtomicBool>,
+}
+
+impl LocalDiscovery {
+    // `port` is the one we give the tracker
+    pub fn start(port: u16) -> Result<LocalDiscovery, io::Error> {
+        let listener = try!(bind_shared(MULTICAST_PORT));
+        try!(listener.join_multicast_v4(&multicast_addr(), &Ipv4Addr::new(0, 0, 0, 0)));
+        try!(listener.set_read_timeout(Some(Duration::from_millis(POLL_MS))));
+        let sender = try!(UdpSocket::bind(("0.0.0.0", 0)));
+
+        // lets us ignore our own announces when they're looped back
+        let cookie: String = rand::thread_rng().gen_ascii_chars().take(8).collect();
+        let torrents: Registry = Arc::new(Mutex::new(HashMap::new()));
+        let stop = Arc::new(AtomicBool::new(false));
+
+        {
+            let torrents = torrents.clone();
+            let cookie = cookie.clone();
+            let stop = stop.clone();
+            thread::spawn(move || announce(sender, torrents, port, cookie, stop));
+        }
+        {
+            let torrents = torrents.clone();
+            let stop = stop.clone();
+            thread::spawn(move || listen(listener, torrents, cookie, stop));
+        }
+
+        Ok(LocalDiscovery {
+            torrents: torrents,
+            stop: stop,
+        })
+    }
+
+    // Starts announcing a torrent, and reporting peers that announce it
+    pub fn add(&self, info_hash: Vec<u8>, on_discovered: Box<Fn(Vec<Peer>) + Send>) {
+        self.torrents.lock().unwrap().insert(info_hash, Registered {
+            on_discovered: on_discovered,
+            last_sent: None,
+        });
+    }
+
+    pub fn remove(&self, info_hash: &[u8]) {
+        self.torrents.lock().unwrap().remove(info_hash);
+    }
+}
+
+impl Drop for LocalDiscovery {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+    }
+}
+
+// Binds `port` on every interface with SO_REUSEADDR, and SO_REUSEPORT
+// where that's what lets UDP ports be shared, so that we and any other LSD
+// clients on the host all get the announces
+#[cfg(unix)]
+fn bind_shared(port: u16) -> Result<UdpSocket, io::Error> {
+    use std::os::unix::io::FromRawFd;
+
+    let fd = unsafe { libc::socket(libc::AF_INET, libc::SOCK_DGRAM, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // owns the descriptor from here on, so it's closed on errors
+    let socket = unsafe { UdpSocket::from_raw_fd(fd) };
+
+    try!(set_option(fd, libc::SO_REUSEADDR));
+    try!(set_reuse_port(fd));
+
+    let mut addr: libc::sockaddr_in = unsafe { mem::zeroed() };
+    addr.sin_family = libc::AF_INET as libc::sa_family_t;
+    addr.sin_port = port.to_be();
+    addr.sin_addr = libc::in_addr { s_addr: 0 };
+    let bound = unsafe {
+        libc::bind(fd, &addr as *const libc::sockaddr_in as *const libc::sockaddr,
+                   mem::size_of::<libc::sockaddr_in>() as libc::socklen_t)
+    };
+    if bound != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(socket)
+}
+
+#[cfg(unix)]
+fn set_option(fd: libc::c_int, option: libc::c_int) -> Result<(), io::Error> {
+    let on: libc::c_int = 1;
+    let set = unsafe {
+        libc::setsockopt(fd, libc::SOL_SOCKET, option,
+                         &on as *const libc::c_int as *const libc::c_void,
+                         mem::size_of::<libc::c_int>() as libc::socklen_t)
+    };
+    if set == 0 { Ok(()) } else { Err(io::Error::last_os_error()) }
+}
+
+// the BSDs only let UDP sockets share a port with SO_REUSEPORT; on Linux
+// SO_REUSEADDR is enough
+#[cfg(any(target_os = "macos", target_os = "ios", target_os = "freebsd",
+          target_os = "openbsd", target_os = "netbsd", target_os = "dragonfly"))]
+fn set_reuse_port(fd: libc::c_int) -> Result<(), io::Error> {
+    set_option(fd, libc::SO_REUSEPORT)
+}
+
+#[cfg(all(unix, not(any(target_os = "macos", target_os = "ios", target_os = "freebsd",
+                        target_os = "openbsd", target_os = "netbsd", target_os = "dragonfly"))))]
+fn set_reuse_port(_: libc::c_int) -> Result<(), io::Error> {
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn bind_shared(port: u16) -> Result<UdpSocket, io::Error> {
+    UdpSocket::bind(("0.0.0.0", port))
+}
+
+// Announces each torrent when it's added, then every
+// `ANNOUNCE_INTERVAL_SECS`
+fn announce(socket: UdpSocket, torrents: Registry, port: u16, cookie: String,
+            stop: Arc<AtomicBool>) {
+    let dest = SocketAddr::new(net::IpAddr::V4(multicast_addr()), MULTICAST_PORT);
+    while !stop.load(Ordering::SeqCst) {
+        let now = time::get_time().sec;
+        for (info_hash, torrent) in torrents.lock().unwrap().iter_mut() {
+            match torrent.last_sent {
+                Some(t) if now - t < ANNOUNCE_INTERVAL_SECS => continue,
+                _ => {},
+            }
+            let msg = announce_message(info_hash, port, &cookie);
+            if let Err(e) = socket.send_to(msg.as_bytes(), dest) {
+                log!("LSD announce failed: {:?}", e);
+            }
+            torrent.last_sent = Some(now);
+        }
+        thread::sleep(Duration::from_millis(POLL_MS));
+    }
+}
+
+fn listen(socket: UdpSocket, torrents: Registry, cookie: String, stop: Arc<AtomicBool>) {
+    let mut buf = [0; 1500];
+    while !stop.load(Ordering::SeqCst) {
+        if let Ok((n, from)) = socket.recv_from(&mut buf) {
+            dispatch(&torrents, &cookie, &buf[..n], from);
+        }
+    }
+}
+
+// Hands the peer behind an announce from `from` to each of our torrents
+// it's for
+fn dispatch(torrents: &Registry, cookie: &str, buf: &[u8], from: SocketAddr) {
+    let announce = match parse_announce(buf) {
+        Some(announce) => announce,
+        None => return,
+    };
+    if announce.cookie.as_ref().map(|c| &c[..]) == Some(cookie) {
+        return;
+    }
+
+    let addr = SocketAddr::new(from.ip(), announce.port);
+    let torrents = torrents.lock().unwrap();
+    for info_hash in announce.info_hashes.iter() {
+        if let Some(torrent) = torrents.get(info_hash) {
+            log!("LSD: found local peer {:?}", addr);
+            (torrent.on_discovered)(vec![Peer::from_socketaddr(addr)]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{announce_message, bind_shared, dispatch, Registered, Registry};
+    use tracker::Peer;
+
+    use std::collections::HashMap;
+    use std::net::SocketAddr;
+    use std::sync::{Arc, Mutex};
+    use std::sync::mpsc;
+
+    #[test]
+    fn port_is_shared() {
+        let first = bind_shared(0).unwrap();
+        let port = first.local_addr().unwrap().port();
+        let second = bind_shared(port).unwrap();
+        assert_eq!(second.local_addr().unwrap().port(), port);
+    }
+
+    #[test]
+    fn announces_go_to_their_torrent() {
+        let registry: Registry = Arc::new(Mutex::new(HashMap::new()));
+        let mut found = Vec::new();
+        for i in 0..2 {
+            let (tx, rx) = mpsc::channel();
+            registry.lock().unwrap().insert(vec![i; 20], Registered {
+                on_discovered: Box::new(move |peers: Vec<Peer>| {
+                    let _ = tx.send(peers[0].addr);
+                }),
+                last_sent: None,
+            });
+            found.push(rx);
+        }
+        let from: SocketAddr = "192.168.1.5:6771".parse().unwrap();
+
+        dispatch(&registry, "ours", announce_message(&[1; 20], 4000, "theirs").as_bytes(), from);
+        assert_eq!(found[1].try_recv().unwrap(), "192.168.1.5:4000".parse().unwrap());
+        assert!(found[0].try_recv().is_err());
+
+        // our own announces, and ones for torrents we don't have, are ignored
+        dispatch(&registry, "ours", announce_message(&[1; 20], 4000, "ours").as_bytes(), from);
+        dispatch(&registry, "ours", announce_message(&[2; 20], 4000, "theirs").as_bytes(), from);
+        assert!(found[0].try_recv().is_err());
+        assert!(found[1].try_recv().is_err());
+    }
+}
@@ -1,107 +1,559 @@
-#![feature(ip_addr, read_exact)]
-
-extern crate bencode;
+extern crate deluge;
 extern crate getopts;
-extern crate hyper;
-extern crate openssl;
-extern crate rand;
-extern crate url;
+extern crate rustc_serialize;
 
-use getopts::Options;
-use rand::Rng;
+use deluge::{metainfo, tracker, verify, Allocation, EncryptionPolicy, FilePriority, MetaInfo,
+             Session, StorageMode, TorrentSettings, TorrentState};
+use deluge::create::{self, TorrentOptions};
+use getopts::{Matches, Options};
+use rustc_serialize::json::{self, Json};
+use std::collections::BTreeMap;
 use std::env;
+use std::fs::File;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::thread;
+use std::time::Duration;
+
+// how often the torrents' states are printed
+const STATUS_INTERVAL_SECS: u64 = 10;
+
+const EXIT_OK: i32 = 0;
 
-mod download;
-mod metainfo;
-mod tracker;
-mod util;
+// something went wrong: a bad torrent, a tracker error, a failed download
+const EXIT_FAILURE: i32 = 1;
 
-//static DEFAULT_TORRENT_FILE: &'static str = "Fedora-Live-LXDE-x86_64-22.torrent";
-//static DEFAULT_TORRENT_FILE: &'static str = "archlinux-2015.06.01-dual.iso.torrent";
-//static DEFAULT_TORRENT_FILE: &'static str = "flagfromserver.torrent";
-static DEFAULT_TORRENT_FILE: &'static str = "ubuntu-15.04-desktop-amd64.iso.torrent";
-//static DEFAULT_TORRENT_FILE: &'static str = "debian-8.2.0-amd64-netinst.iso.torrent";
+const EXIT_USAGE: i32 = 2;
 
-const PEER_ID_PREFIX: &'static str = "-NH0001-";
+// `verify` found pieces missing or corrupt
+const EXIT_INCOMPLETE: i32 = 3;
 
-fn print_usage(program: &str, opts: Options) {
-    let brief = format!("Usage: {} [options]", program);
+const COMMANDS: &'static str = "\
+Commands:
+    info SOURCE          show a torrent's files, pieces and trackers
+    create PATH          make a torrent from a file or directory
+    verify SOURCE        check downloaded data against a torrent
+    download SOURCE...   download torrents, exiting when they're complete
+    seed SOURCE...       download and then keep seeding until killed
+    scrape SOURCE        ask a torrent's trackers about its swarm
+
+A SOURCE is a path, an http(s) URL, or - for stdin.";
+
+fn print_usage(program: &str) {
+    println!("Usage: {} COMMAND [options]\n\n{}\n\n\
+              Run `{} COMMAND -h` for a command's options.", program, COMMANDS, program);
+}
+
+fn print_command_usage(program: &str, command: &str, args: &str, opts: &Options) {
+    let brief = format!("Usage: {} {} [options] {}", program, command, args);
     print!("{}", opts.usage(&brief));
 }
 
+fn print_error(msg: &str) {
+    let _ = writeln!(&mut io::stderr(), "error: {}", msg);
+}
+
 fn main() {
     let args: Vec<String> = env::args().collect();
     let program = args[0].clone();
 
-    let mut opts = Options::new();
-    opts.optopt("t", "", "set torrent file name", "NAME");
-    opts.optflag("h", "help", "print this help menu");
+    if args.len() < 2 {
+        print_usage(&program);
+        process::exit(EXIT_USAGE);
+    }
+
+    let rest = &args[2..];
+    let code = match &args[1][..] {
+        "info" => cmd_info(&program, rest),
+        "create" => cmd_create(&program, rest),
+        "verify" => cmd_verify(&program, rest),
+        "download" => cmd_run(&program, "download", rest),
+        "seed" => cmd_run(&program, "seed", rest),
+        "scrape" => cmd_scrape(&program, rest),
+        "-h" | "--help" | "help" => {
+            print_usage(&program);
+            EXIT_OK
+        },
+        command => {
+            print_error(&format!("unknown command {:?}", command));
+            print_usage(&program);
+            EXIT_USAGE
+        },
+    };
+    process::exit(code);
+}
 
-    let matches = match opts.parse(&args[1..]) {
-        Ok(m) => { m }
-        Err(f) => { panic!(f.to_string()) }
+// Parses a command's options. `Err` holds the exit code when the command
+// shouldn't go on: after -h, or a usage error.
+fn parse_args(program: &str, command: &str, usage: &str, mut opts: Options, args: &[String],
+              min_free: usize, max_free: Option<usize>) -> Result<Matches, i32> {
+    opts.optflag("h", "help", "print this help menu");
+    let matches = match opts.parse(args) {
+        Ok(m) => m,
+        Err(f) => {
+            print_error(&f.to_string());
+            print_command_usage(program, command, usage, &opts);
+            return Err(EXIT_USAGE);
+        },
     };
 
     if matches.opt_present("h") {
-        print_usage(&program, opts);
-        return;
+        print_command_usage(program, command, usage, &opts);
+        return Err(EXIT_OK);
+    }
+
+    let n = matches.free.len();
+    if n < min_free || max_free.map_or(false, |max| n > max) {
+        print_command_usage(program, command, usage, &opts);
+        return Err(EXIT_USAGE);
+    }
+    Ok(matches)
+}
+
+fn json_flag(opts: &mut Options) {
+    opts.optflag("", "json", "print machine-readable JSON");
+}
+
+fn obj(pairs: Vec<(&str, Json)>) -> Json {
+    let mut map = BTreeMap::new();
+    for (k, v) in pairs.into_iter() {
+        map.insert(String::from(k), v);
+    }
+    Json::Object(map)
+}
+
+fn strings(v: &[String]) -> Json {
+    Json::Array(v.iter().map(|s| Json::String(s.clone())).collect())
+}
+
+fn print_json(j: &Json) {
+    println!("{}", json::as_pretty_json(j));
+}
+
+fn load(source: &str) -> Result<MetaInfo, i32> {
+    metainfo::load(source).map_err(|e| {
+        print_error(&format!("{}: {}", source, e));
+        EXIT_FAILURE
+    })
+}
+
+fn try_code<T>(r: Result<T, i32>) -> T {
+    match r {
+        Ok(t) => t,
+        Err(code) => process::exit(code),
+    }
+}
+
+fn cmd_info(program: &str, args: &[String]) -> i32 {
+    let mut opts = Options::new();
+    json_flag(&mut opts);
+    let matches = try_code(parse_args(program, "info", "SOURCE", opts, args, 1, Some(1)));
+    let info = try_code(load(&matches.free[0]));
+    let files = info.files();
+
+    if matches.opt_present("json") {
+        let files = files.iter().map(|f| obj(vec![
+            ("path", strings(&f.components[..])),
+            ("length", Json::U64(f.length)),
+        ])).collect();
+        print_json(&obj(vec![
+            ("name", Json::String(String::from(info.info.name()))),
+            ("info_hash", Json::String(deluge::to_hex(&info.info_hash))),
+            ("total_size", Json::U64(info.num_file_bytes())),
+            ("piece_length", Json::U64(info.info.piece_length() as u64)),
+            ("pieces", Json::U64(info.num_pieces() as u64)),
+            ("private", Json::Boolean(info.info.is_private())),
+            ("trackers", strings(&info.trackers())),
+            ("url_list", strings(&info.url_list)),
+            ("http_seeds", strings(&info.http_seeds)),
+            ("created_by", info.created_by.clone().map_or(Json::Null, Json::String)),
+            ("creation_date", info.creation_date.map_or(Json::Null, Json::I64)),
+            ("files", Json::Array(files)),
+        ]));
+        return EXIT_OK;
+    }
+
+    println!("name:         {}", info.info.name());
+    println!("info hash:    {}", deluge::to_hex(&info.info_hash));
+    println!("size:         {} bytes", info.num_file_bytes());
+    println!("pieces:       {} x {} bytes", info.num_pieces(), info.info.piece_length());
+    println!("private:      {}", if info.info.is_private() { "yes" } else { "no" });
+    if let Some(ref created_by) = info.created_by {
+        println!("created by:   {}", created_by);
+    }
+    println!("trackers:");
+    for t in info.trackers() {
+        println!("    {}", t);
+    }
+    if !info.url_list.is_empty() || !info.http_seeds.is_empty() {
+        println!("web seeds:");
+        for s in info.url_list.iter().chain(info.http_seeds.iter()) {
+            println!("    {}", s);
+        }
+    }
+    println!("files:");
+    for (i, f) in files.iter().enumerate() {
+        println!("    {:>4}  {:>14}  {}", i, f.length, f.path.display());
+    }
+    EXIT_OK
+}
+
+fn cmd_create(program: &str, args: &[String]) -> i32 {
+    let mut opts = Options::new();
+    opts.optopt("o", "output", "where to write the torrent (default NAME.torrent)", "FILE");
+    opts.optmulti("a", "announce", "add a tracker (may be repeated)", "URL");
+    opts.optmulti("w", "web-seed", "add a web seed (may be repeated)", "URL");
+    opts.optopt("l", "piece-length", "piece length in bytes (default 262144)", "BYTES");
+    opts.optflag("p", "private", "make a private torrent");
+    json_flag(&mut opts);
+    let matches = try_code(parse_args(program, "create", "PATH", opts, args, 1, Some(1)));
+
+    let mut options = TorrentOptions::new();
+    options.trackers = matches.opt_strs("a");
+    options.web_seeds = matches.opt_strs("w");
+    options.private = matches.opt_present("p");
+    if let Some(l) = matches.opt_str("l") {
+        match l.parse::<u32>() {
+            Ok(l) if l >= 1 << 14 && l.is_power_of_two() => options.piece_length = l,
+            _ => {
+                print_error("piece length must be a power of two of at least 16384");
+                return EXIT_USAGE;
+            },
+        }
+    }
+    if options.trackers.is_empty() {
+        print_error("a torrent needs at least one tracker (-a)");
+        return EXIT_USAGE;
     }
 
-    let torrent_file = matches.opt_str("t");
+    let path = Path::new(&matches.free[0]);
+    let bytes = match create::create_torrent(path, &options) {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            print_error(&format!("{}: {}", path.display(), e));
+            return EXIT_FAILURE;
+        },
+    };
+    let info = match metainfo::from_bytes(&bytes) {
+        Ok(info) => info,
+        Err(e) => {
+            print_error(&format!("made an unreadable torrent: {}", e));
+            return EXIT_FAILURE;
+        },
+    };
+
+    let output = matches.opt_str("o")
+                        .unwrap_or_else(|| format!("{}.torrent", info.info.name()));
+    let written = File::create(&output).and_then(|mut f| f.write_all(&bytes));
+    if let Err(e) = written {
+        print_error(&format!("{}: {}", output, e));
+        return EXIT_FAILURE;
+    }
 
-    if !matches.free.is_empty() {
-        print_usage(&program, opts);
-        return;
+    if matches.opt_present("json") {
+        print_json(&obj(vec![
+            ("torrent", Json::String(output)),
+            ("info_hash", Json::String(deluge::to_hex(&info.info_hash))),
+        ]));
+    } else {
+        println!("wrote {} (info hash {})", output, deluge::to_hex(&info.info_hash));
     }
+    EXIT_OK
+}
+
+fn cmd_verify(program: &str, args: &[String]) -> i32 {
+    let mut opts = Options::new();
+    opts.optopt("j", "threads", "number of hashing threads (default 4)", "N");
+    opts.optopt("d", "dir", "directory the torrent's files are in (default .)", "DIR");
+    json_flag(&mut opts);
+    let matches = try_code(parse_args(program, "verify", "SOURCE", opts, args, 1, Some(1)));
+    let info = try_code(load(&matches.free[0]));
+
+    let threads = match matches.opt_str("j").map(|j| j.parse::<usize>()) {
+        None => verify::DEFAULT_THREADS,
+        Some(Ok(j)) if j > 0 => j,
+        Some(_) => {
+            print_error("the number of threads must be a positive integer");
+            return EXIT_USAGE;
+        },
+    };
 
-    println!("torrent_file = {:?}", torrent_file);
+    // progress goes to stderr, so it stays out of the way of --json
+    let mut last_percent = None;
+    let dir = matches.opt_str("d").unwrap_or(".".to_string());
+    let paths = verify::file_paths(&info, Path::new(&dir));
+    let part_file = verify::part_file_path(&info, Path::new(&dir));
+    let result = verify::recheck(&info, &paths, &[], &part_file, threads, &mut |done, total| {
+        let percent = done as u64 * 100 / total as u64;
+        if last_percent != Some(percent) {
+            last_percent = Some(percent);
+            let _ = write!(&mut io::stderr(), "\rchecking: {:3}%", percent);
+        }
+    });
+    let _ = writeln!(&mut io::stderr(), "");
 
-    let torrent_filename = match torrent_file {
-        Some(ref file) => &file[..],
-        None => DEFAULT_TORRENT_FILE,
+    let result = match result {
+        Ok(result) => result,
+        Err(e) => {
+            print_error(&format!("couldn't read data: {}", e));
+            return EXIT_FAILURE;
+        },
     };
+    let have = &result.have;
+    let files = info.files();
 
-    match run(torrent_filename) {
-        Err(e) => panic!("Error running: {:?}", e),
-        _ => {},
+    if matches.opt_present("json") {
+        let corrupt = result.corrupt.iter().map(|r| obj(vec![
+            ("path", strings(&files[r.file].components[..])),
+            ("offset", Json::U64(r.offset)),
+            ("length", Json::U64(r.length)),
+        ])).collect();
+        print_json(&obj(vec![
+            ("name", Json::String(String::from(info.info.name()))),
+            ("pieces", Json::U64(info.num_pieces() as u64)),
+            ("verified", Json::U64(have.count() as u64)),
+            ("missing", Json::U64(result.missing as u64)),
+            ("complete", Json::Boolean(have.is_complete())),
+            ("corrupt", Json::Array(corrupt)),
+        ]));
+    } else {
+        println!("{}: {} of {} pieces verified, {} missing", info.info.name(), have.count(),
+                 info.num_pieces(), result.missing);
+        if !result.corrupt.is_empty() {
+            println!("corrupt data:");
+            for r in result.corrupt.iter() {
+                println!("    {}: bytes {}-{}", files[r.file].path.display(), r.offset,
+                         r.offset + r.length - 1);
+            }
+        }
     }
+
+    if have.is_complete() { EXIT_OK } else { EXIT_INCOMPLETE }
 }
 
-#[derive(Debug)]
-enum RunError {
-    FileError(metainfo::ParseError),
-    TrackerError(tracker::TrackerError),
+// INDEX=LEVEL, from --priority
+fn parse_priority(s: &str) -> Result<(usize, FilePriority), i32> {
+    let mut parts = s.splitn(2, '=');
+    let index = parts.next().and_then(|i| i.parse::<usize>().ok());
+    let priority = parts.next().and_then(FilePriority::from_str);
+    match (index, priority) {
+        (Some(index), Some(priority)) => Ok((index, priority)),
+        _ => {
+            print_error(&format!("bad file priority {:?}, expected INDEX=LEVEL", s));
+            Err(EXIT_USAGE)
+        },
+    }
 }
 
-impl From<metainfo::ParseError> for RunError {
-    fn from(e: metainfo::ParseError) -> RunError {
-        RunError::FileError(e)
+// A rate in KiB/s, as bytes a second
+fn parse_rate(s: &str) -> Result<usize, i32> {
+    match s.parse::<usize>() {
+        Ok(kib) => Ok(kib * 1024),
+        Err(_) => {
+            print_error(&format!("bad rate {:?}, expected a number of KiB/s", s));
+            Err(EXIT_USAGE)
+        },
     }
 }
 
-impl From<tracker::TrackerError> for RunError {
-    fn from(e: tracker::TrackerError) -> RunError {
-        RunError::TrackerError(e)
+fn parse_count(s: &str) -> Result<usize, i32> {
+    match s.parse::<usize>() {
+        Ok(n) if n > 0 => Ok(n),
+        _ => {
+            print_error(&format!("bad number {:?}, expected a positive integer", s));
+            Err(EXIT_USAGE)
+        },
     }
 }
 
-fn run(filename: &str) -> Result<(), RunError> {
-    let metainfo = try!(metainfo::parse_torrent_file(filename));
-    println!("metainfo = {:?}", metainfo);
+// `download` stops once every torrent is complete; `seed` runs until killed
+fn cmd_run(program: &str, command: &str, args: &[String]) -> i32 {
+    let mut opts = Options::new();
+    opts.optopt("e", "encryption", "connection encryption policy \
+                                   (plaintext, prefer or require; default prefer)", "POLICY");
+    opts.optmulti("P", "priority", "priority of file INDEX, as numbered by `info` \
+                                    (skip, low, normal or high; default normal)", "INDEX=LEVEL");
+    opts.optflag("s", "sequential", "download pieces in order, to play files as they arrive");
+    opts.optopt("d", "dir", "directory to keep the torrents' files in (default .)", "DIR");
+    opts.optopt("", "allocate", "how to create files: sparse, or full to reserve the space \
+                                 up front (default sparse)", "MODE");
+    opts.optopt("", "storage", "how to read and write files: files, or mmap to map them \
+                                into memory (default files)", "MODE");
+    opts.optopt("", "download-rate", "limit downloading, across all torrents, to RATE KiB/s",
+                "RATE");
+    opts.optopt("", "upload-rate", "limit uploading, across all torrents, to RATE KiB/s",
+                "RATE");
+    opts.optopt("", "connections", "peers each torrent may be connected to at once \
+                                    (default 30)", "N");
+    opts.optopt("", "global-connections", "peers all torrents together may be connected to \
+                                           at once (default 200)", "N");
+    opts.optopt("", "http", "serve the torrents' files over HTTP on PORT while they \
+                             download", "PORT");
+    json_flag(&mut opts);
+    let matches = try_code(parse_args(program, command, "SOURCE...", opts, args, 1, None));
 
-    // send GET to tracker
-    let peer_id = gen_peer_id();
-    let peers = try!(tracker::get_tracker(&metainfo, peer_id.clone()));
-    println!("peers.len() = {}", peers.len());
+    let mut settings = TorrentSettings::new();
+    settings.sequential = matches.opt_present("s");
+    if let Some(dir) = matches.opt_str("d") {
+        settings.save_path = PathBuf::from(dir);
+    }
+    if let Some(ref s) = matches.opt_str("allocate") {
+        settings.allocation = match Allocation::from_str(s) {
+            Some(allocation) => allocation,
+            None => {
+                print_error(&format!("unknown allocation mode {:?}", s));
+                return EXIT_USAGE;
+            },
+        };
+    }
+    if let Some(ref s) = matches.opt_str("storage") {
+        settings.storage_mode = match StorageMode::from_str(s) {
+            Some(mode) => mode,
+            None => {
+                print_error(&format!("unknown storage mode {:?}", s));
+                return EXIT_USAGE;
+            },
+        };
+    }
+    for p in matches.opt_strs("P").iter() {
+        let (index, priority) = try_code(parse_priority(p));
+        if settings.file_priorities.len() <= index {
+            settings.file_priorities.resize(index + 1, FilePriority::Normal);
+        }
+        settings.file_priorities[index] = priority;
+    }
 
-    download::download(&metainfo, &peers[..], peer_id.clone());
-    Ok(())
+    let policy = match matches.opt_str("e") {
+        Some(ref s) => match EncryptionPolicy::from_str(s) {
+            Some(policy) => policy,
+            None => {
+                print_error(&format!("unknown encryption policy {:?}", s));
+                return EXIT_USAGE;
+            },
+        },
+        None => EncryptionPolicy::PreferEncrypted,
+    };
+    let json = matches.opt_present("json");
+    let http_port = match matches.opt_str("http") {
+        Some(ref s) => match s.parse::<u16>() {
+            Ok(port) => Some(port),
+            Err(_) => {
+                print_error(&format!("bad port {:?}", s));
+                return EXIT_USAGE;
+            },
+        },
+        None => None,
+    };
+
+    if let Some(ref s) = matches.opt_str("connections") {
+        settings.max_connections = try_code(parse_count(s));
+    }
+    let global_connections = match matches.opt_str("global-connections") {
+        Some(ref s) => Some(try_code(parse_count(s))),
+        None => None,
+    };
+    let download_rate = match matches.opt_str("download-rate") {
+        Some(ref s) => try_code(parse_rate(s)),
+        None => 0,
+    };
+    let upload_rate = match matches.opt_str("upload-rate") {
+        Some(ref s) => try_code(parse_rate(s)),
+        None => 0,
+    };
+
+    let mut torrents = Vec::new();
+    for source in matches.free.iter() {
+        torrents.push(try_code(load(source)));
+    }
+
+    let session = Session::new(deluge::peer_id::generate(), policy);
+    session.set_rate_limits(download_rate, upload_rate);
+    if let Some(max) = global_connections {
+        session.set_max_connections(max);
+    }
+    for info in torrents.into_iter() {
+        let name = String::from(info.info.name());
+        if let Err(e) = session.add_with_settings(info, settings.clone()) {
+            print_error(&format!("{}: {}", name, e));
+            return EXIT_FAILURE;
+        }
+    }
+
+    // serves until it's dropped, when we exit
+    let _server = match http_port {
+        Some(port) => match session.serve_http(port) {
+            Ok(server) => {
+                for (ih, name, _) in session.torrents() {
+                    let _ = writeln!(&mut io::stderr(), "serving {} at http://localhost:{}/{}/",
+                                     name, port, deluge::to_hex(&ih));
+                }
+                Some(server)
+            },
+            Err(e) => {
+                print_error(&format!("couldn't serve HTTP on port {}: {}", port, e));
+                return EXIT_FAILURE;
+            },
+        },
+        None => None,
+    };
+
+    loop {
+        thread::sleep(Duration::from_secs(STATUS_INTERVAL_SECS));
+        let states = session.torrents();
+
+        if json {
+            // one object per torrent per line, so that it can be read as a stream
+            for &(ref ih, ref name, ref state) in states.iter() {
+                println!("{}", obj(vec![
+                    ("info_hash", Json::String(deluge::to_hex(ih))),
+                    ("name", Json::String(name.clone())),
+                    ("state", Json::String(state.to_string())),
+                ]));
+            }
+        } else {
+            for &(_, ref name, ref state) in states.iter() {
+                println!("{}: {}", name, state);
+            }
+        }
+
+        if states.iter().any(|&(_, _, ref state)| match *state {
+            TorrentState::Error(_) => true,
+            _ => false,
+        }) {
+            return EXIT_FAILURE;
+        }
+        if command == "download"
+           && states.iter().all(|&(_, _, ref state)| *state == TorrentState::Seeding) {
+            return EXIT_OK;
+        }
+    }
 }
 
+fn cmd_scrape(program: &str, args: &[String]) -> i32 {
+    let mut opts = Options::new();
+    json_flag(&mut opts);
+    let matches = try_code(parse_args(program, "scrape", "SOURCE", opts, args, 1, Some(1)));
+    let info = try_code(load(&matches.free[0]));
 
-fn gen_peer_id() -> String {
-    let mut rng = rand::thread_rng();
-    let prefix_len = PEER_ID_PREFIX.len();
-    let s: String = rng.gen_ascii_chars().take(20 - prefix_len).collect();
-    format!("{}{}", PEER_ID_PREFIX, s)
+    let stats = match tracker::scrape(&info) {
+        Ok(stats) => stats,
+        Err(e) => {
+            print_error(&format!("{}", e));
+            return EXIT_FAILURE;
+        },
+    };
+
+    if matches.opt_present("json") {
+        print_json(&obj(vec![
+            ("info_hash", Json::String(deluge::to_hex(&info.info_hash))),
+            ("complete", Json::I64(stats.complete)),
+            ("downloaded", Json::I64(stats.downloaded)),
+            ("incomplete", Json::I64(stats.incomplete)),
+        ]));
+    } else {
+        println!("{}: {} seeds, {} leechers, {} downloads", info.info.name(),
+                 stats.complete, stats.incomplete, stats.downloaded);
+    }
+    EXIT_OK
 }
@@ -0,0 +1,330 @@
+use util;
+
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Read, Write};
+
+// Largest message we'll accept. A `piece` message with a 16 KiB block is
+// 16397 bytes, but bitfields for huge torrents can be bigger than that.
+const MAX_MESSAGE_LEN: u32 = 1 << 21;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Message {
+    KeepAlive,
+    Choke,
+    Unchoke,
+    Interested,
+    NotInterested,
+    Have(u32),
+    Bitfield(Vec<u8>),
+    // index, begin, length
+    Request(u32, u32, u32),
+    // index, begin, block
+    Piece(u32, u32, Vec<u8>),
+    // index, begin, length
+    Cancel(u32, u32, u32),
+    Port(u16),
+
+    // Fast extension (BEP 6)
+    SuggestPiece(u32),
+    HaveAll,
+    HaveNone,
+    // index, begin, length
+    RejectRequest(u32, u32, u32),
+    AllowedFast(u32),
+
+    // Extension protocol (BEP 10): extended message id, payload
+    Extended(u8, Vec<u8>),
+
+    // a message id we don't know, with its payload, which is ignored so
+    // that peers can use extensions we don't support
+    Unknown(u8, Vec<u8>),
+}
+
+#[derive(Debug)]
+pub enum MessageError {
+    IoError(io::Error),
+    // message id, length of payload
+    BadLength(u8, u32),
+    TooLong(u32),
+}
+
+impl From<io::Error> for MessageError {
+    fn from(e: io::Error) -> MessageError {
+        MessageError::IoError(e)
+    }
+}
+
+impl fmt::Display for MessageError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            MessageError::IoError(ref e) => write!(f, "{}", e),
+            MessageError::BadLength(id, len) =>
+                write!(f, "message {} has bad payload length {}", id, len),
+            MessageError::TooLong(len) => write!(f, "message of {} bytes is too long", len),
+        }
+    }
+}
+
+impl Error for MessageError {
+    fn description(&self) -> &str {
+        match *self {
+            MessageError::IoError(_) => "I/O error",
+            MessageError::BadLength(..) => "bad payload length",
+            MessageError::TooLong(_) => "message too long",
+        }
+    }
+}
+
+impl Message {
+    fn id(&self) -> Option<u8> {
+        use self::Message::*;
+        match *self {
+            KeepAlive => None,
+            Choke => Some(0),
+            Unchoke => Some(1),
+            Interested => Some(2),
+            NotInterested => Some(3),
+            Have(_) => Some(4),
+            Bitfield(_) => Some(5),
+            Request(..) => Some(6),
+            Piece(..) => Some(7),
+            Cancel(..) => Some(8),
+            Port(_) => Some(9),
+            SuggestPiece(_) => Some(0x0D),
+            HaveAll => Some(0x0E),
+            HaveNone => Some(0x0F),
+            RejectRequest(..) => Some(0x10),
+            AllowedFast(_) => Some(0x11),
+            Extended(..) => Some(20),
+            Unknown(id, _) => Some(id),
+        }
+    }
+
+    fn payload(&self) -> Vec<u8> {
+        use self::Message::*;
+        let mut v = Vec::new();
+        match *self {
+            KeepAlive | Choke | Unchoke | Interested | NotInterested
+            | HaveAll | HaveNone => {},
+            Have(index) | SuggestPiece(index) | AllowedFast(index) => {
+                v.extend(util::u32_to_be_bytes(index).iter());
+            },
+            Bitfield(ref bytes) => v.extend(bytes.iter()),
+            Request(index, begin, length)
+            | Cancel(index, begin, length)
+            | RejectRequest(index, begin, length) => {
+                v.extend(util::u32_to_be_bytes(index).iter());
+                v.extend(util::u32_to_be_bytes(begin).iter());
+                v.extend(util::u32_to_be_bytes(length).iter());
+            },
+            Piece(index, begin, ref block) => {
+                v.extend(util::u32_to_be_bytes(index).iter());
+                v.extend(util::u32_to_be_bytes(begin).iter());
+                v.extend(block.iter());
+            },
+            Port(port) => v.extend(util::u16_to_be_bytes(port).iter()),
+            Extended(id, ref payload) => {
+                v.push(id);
+                v.extend(payload.iter());
+            },
+            Unknown(_, ref payload) => v.extend(payload.iter()),
+        }
+        v
+    }
+
+    // length-prefixed wire encoding
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let payload = self.payload();
+        let mut v = Vec::with_capacity(payload.len() + 5);
+        match self.id() {
+            None => v.extend(util::u32_to_be_bytes(0).iter()),
+            Some(id) => {
+                v.extend(util::u32_to_be_bytes(payload.len() as u32 + 1).iter());
+                v.push(id);
+                v.extend(payload.into_iter());
+            },
+        }
+        v
+    }
+
+    // Decodes a message from its id and payload (everything after the length prefix)
+    pub fn from_parts(id: u8, payload: &[u8]) -> Result<Message, MessageError> {
+        use self::Message::*;
+
+        let len = payload.len();
+        let expect = |n: usize| -> Result<(), MessageError> {
+            if len == n { Ok(()) } else { Err(MessageError::BadLength(id, len as u32)) }
+        };
+        let u32_at = |i: usize| util::u32_from_be_bytes(&payload[i..i+4]);
+
+        let msg = match id {
+            0 => { try!(expect(0)); Choke },
+            1 => { try!(expect(0)); Unchoke },
+            2 => { try!(expect(0)); Interested },
+            3 => { try!(expect(0)); NotInterested },
+            4 => { try!(expect(4)); Have(u32_at(0)) },
+            5 => Bitfield(payload.to_vec()),
+            6 => { try!(expect(12)); Request(u32_at(0), u32_at(4), u32_at(8)) },
+            7 => {
+                if len < 8 {
+                    return Err(MessageError::BadLength(id, len as u32));
+                }
+                Piece(u32_at(0), u32_at(4), payload[8..].to_vec())
+            },
+            8 => { try!(expect(12)); Cancel(u32_at(0), u32_at(4), u32_at(8)) },
+            9 => { try!(expect(2)); Port(util::u16_from_be_bytes(payload)) },
+            0x0D => { try!(expect(4)); SuggestPiece(u32_at(0)) },
+            0x0E => { try!(expect(0)); HaveAll },
+            0x0F => { try!(expect(0)); HaveNone },
+            0x10 => { try!(expect(12)); RejectRequest(u32_at(0), u32_at(4), u32_at(8)) },
+            0x11 => { try!(expect(4)); AllowedFast(u32_at(0)) },
+            20 => {
+                if len < 1 {
+                    return Err(MessageError::BadLength(id, len as u32));
+                }
+                Extended(payload[0], payload[1..].to_vec())
+            },
+            _ => Unknown(id, payload.to_vec()),
+        };
+        Ok(msg)
+    }
+
+    // Only meaningful if both peers set the Fast extension bit
+    pub fn is_fast_extension(&self) -> bool {
+        use self::Message::*;
+        match *self {
+            SuggestPiece(_) | HaveAll | HaveNone | RejectRequest(..) | AllowedFast(_) => true,
+            _ => false,
+        }
+    }
+}
+
+pub fn read_message<R: Read>(r: &mut R) -> Result<Message, MessageError> {
+    let mut buf_len = [0; 4];
+    try!(r.read_exact(&mut buf_len));
+    let len = util::u32_from_be_bytes(&buf_len);
+
+    if len == 0 {
+        return Ok(Message::KeepAlive);
+    }
+    if len > MAX_MESSAGE_LEN {
+        return Err(MessageError::TooLong(len));
+    }
+
+    let mut buf = vec![0; len as usize];
+    try!(r.read_exact(&mut buf));
+    Message::from_parts(buf[0], &buf[1..])
+}
+
+// Length of the first message in `buf`, counting its length prefix, once
+// all of it has arrived. Lets a reader that can time out mid-message keep
+// partial messages around instead of losing them.
+pub fn frame_len(buf: &[u8]) -> Result<Option<usize>, MessageError> {
+    if buf.len() < 4 {
+        return Ok(None);
+    }
+    let len = util::u32_from_be_bytes(&buf[..4]);
+    if len > MAX_MESSAGE_LEN {
+        return Err(MessageError::TooLong(len));
+    }
+
+    let total = 4 + len as usize;
+    Ok(if buf.len() >= total { Some(total) } else { None })
+}
+
+pub fn write_message<W: Write>(w: &mut W, msg: &Message) -> Result<(), io::Error> {
+    w.write_all(&msg.to_bytes()[..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{frame_len, read_message, write_message, Message, MessageError,
+                MAX_MESSAGE_LEN};
+    use super::Message::*;
+    use util;
+
+    fn round_trip(msg: Message) {
+        let mut buf = Vec::new();
+        write_message(&mut buf, &msg).unwrap();
+        assert_eq!(frame_len(&buf).unwrap(), Some(buf.len()));
+        assert_eq!(read_message(&mut &buf[..]).unwrap(), msg);
+    }
+
+    #[test]
+    fn round_trips() {
+        let messages = vec![
+            KeepAlive, Choke, Unchoke, Interested, NotInterested,
+            Have(7),
+            Bitfield(vec![0xff, 0x80]),
+            Request(1, 16384, 16384),
+            Piece(1, 16384, vec![1, 2, 3]),
+            Cancel(1, 16384, 16384),
+            Port(6881),
+            SuggestPiece(3), HaveAll, HaveNone,
+            RejectRequest(2, 0, 16384),
+            AllowedFast(9),
+            Extended(0, b"d1:md6:ut_pexi1eee".to_vec()),
+            Extended(3, Vec::new()),
+        ];
+        for msg in messages {
+            round_trip(msg);
+        }
+    }
+
+    #[test]
+    fn wire_format() {
+        assert_eq!(KeepAlive.to_bytes(), vec![0, 0, 0, 0]);
+        assert_eq!(Have(0x01020304).to_bytes(), vec![0, 0, 0, 5, 4, 1, 2, 3, 4]);
+        assert_eq!(HaveAll.to_bytes(), vec![0, 0, 0, 1, 0x0E]);
+        assert_eq!(RejectRequest(1, 2, 3).to_bytes(),
+                   vec![0, 0, 0, 13, 0x10, 0, 0, 0, 1, 0, 0, 0, 2, 0, 0, 0, 3]);
+        assert_eq!(Extended(1, vec![b'x']).to_bytes(), vec![0, 0, 0, 3, 20, 1, b'x']);
+    }
+
+    #[test]
+    fn fast_extension_messages() {
+        assert!(HaveNone.is_fast_extension());
+        assert!(AllowedFast(0).is_fast_extension());
+        assert!(!Have(0).is_fast_extension());
+        assert!(!Extended(0, Vec::new()).is_fast_extension());
+    }
+
+    #[test]
+    fn bad_messages() {
+        match Message::from_parts(4, &[0, 0, 1]) {
+            Err(MessageError::BadLength(4, 3)) => {},
+            other => panic!("expected BadLength, got {:?}", other),
+        }
+        match Message::from_parts(0x0E, &[0]) {
+            Err(MessageError::BadLength(0x0E, 1)) => {},
+            other => panic!("expected BadLength, got {:?}", other),
+        }
+        match Message::from_parts(20, &[]) {
+            Err(MessageError::BadLength(20, 0)) => {},
+            other => panic!("expected BadLength, got {:?}", other),
+        }
+        // unknown ids aren't errors, so that they can be skipped
+        assert_eq!(Message::from_parts(0x42, &[1, 2]).unwrap(), Unknown(0x42, vec![1, 2]));
+        round_trip(Unknown(0x42, vec![1, 2]));
+
+        let too_long = util::u32_to_be_bytes(MAX_MESSAGE_LEN + 1);
+        match read_message(&mut &too_long[..]) {
+            Err(MessageError::TooLong(_)) => {},
+            other => panic!("expected TooLong, got {:?}", other),
+        }
+        assert!(frame_len(&too_long).is_err());
+    }
+
+    #[test]
+    fn partial_frames() {
+        let bytes = Piece(0, 0, vec![7; 10]).to_bytes();
+        assert_eq!(frame_len(&bytes[..3]).unwrap(), None);
+        assert_eq!(frame_len(&bytes[..bytes.len() - 1]).unwrap(), None);
+
+        let mut two = bytes.clone();
+        two.extend(Choke.to_bytes().into_iter());
+        assert_eq!(frame_len(&two).unwrap(), Some(bytes.len()));
+        assert!(read_message(&mut &bytes[..bytes.len() - 1]).is_err());
+    }
+}
@@ -0,0 +1,100 @@
+// Metadata exchange (ut_metadata, BEP 9). We always start from a torrent
+// file, so this side only serves our info dict to peers that ask for it.
+
+use extension::{ExtendedHandshake, ExtensionHandler};
+use util;
+
+use bencode::{self, Bencode};
+use std::sync::Arc;
+
+pub const NAME: &'static str = "ut_metadata";
+
+// metadata is sent in pieces of this size, the last one may be shorter
+pub const PIECE_SIZE: usize = 16384;
+
+const MSG_REQUEST: i64 = 0;
+const MSG_DATA: i64 = 1;
+const MSG_REJECT: i64 = 2;
+
+pub struct MetadataExchange {
+    // the bencoded info dictionary
+    info_bytes: Arc<Vec<u8>>,
+}
+
+impl MetadataExchange {
+    pub fn new(info_bytes: Arc<Vec<u8>>) -> MetadataExchange {
+        MetadataExchange {
+            info_bytes: info_bytes,
+        }
+    }
+
+    fn num_pieces(&self) -> usize {
+        (self.info_bytes.len() + PIECE_SIZE - 1) / PIECE_SIZE
+    }
+
+    fn data(&self, piece: usize) -> Vec<u8> {
+        let start = piece * PIECE_SIZE;
+        let end = if start + PIECE_SIZE < self.info_bytes.len() {
+            start + PIECE_SIZE
+        } else {
+            self.info_bytes.len()
+        };
+
+        let header = util::bencode_dict(vec![
+            ("msg_type", Bencode::Number(MSG_DATA)),
+            ("piece", Bencode::Number(piece as i64)),
+            ("total_size", Bencode::Number(self.info_bytes.len() as i64)),
+        ]);
+        let mut v = header.to_bytes().unwrap();
+        v.extend(self.info_bytes[start..end].iter());
+        v
+    }
+
+    fn reject(&self, piece: i64) -> Vec<u8> {
+        util::bencode_dict(vec![
+            ("msg_type", Bencode::Number(MSG_REJECT)),
+            ("piece", Bencode::Number(piece)),
+        ]).to_bytes().unwrap()
+    }
+}
+
+impl ExtensionHandler for MetadataExchange {
+    fn name(&self) -> &'static str {
+        NAME
+    }
+
+    fn extend_handshake(&self, hs: &mut ExtendedHandshake) {
+        hs.metadata_size = Some(self.info_bytes.len() as i64);
+    }
+
+    fn on_message(&mut self, payload: &[u8]) -> Result<Vec<Vec<u8>>, String> {
+        // `data` messages have the piece appended after the dictionary and
+        // won't decode, but we never request metadata so we can drop them.
+        let map = match bencode::from_buffer(payload) {
+            Ok(Bencode::Dict(map)) => map,
+            _ => return Ok(Vec::new()),
+        };
+
+        let msg_type = match util::maybe_get_field(&map, "msg_type") {
+            Some(Bencode::Number(t)) => t,
+            _ => return Err(String::from("ut_metadata message without msg_type")),
+        };
+        let piece = match util::maybe_get_field(&map, "piece") {
+            Some(Bencode::Number(p)) => p,
+            _ => return Err(String::from("ut_metadata message without piece")),
+        };
+
+        match msg_type {
+            MSG_REQUEST => {
+                if piece >= 0 && (piece as usize) < self.num_pieces() {
+                    Ok(vec![self.data(piece as usize)])
+                } else {
+                    Ok(vec![self.reject(piece)])
+                }
+            },
+            MSG_DATA | MSG_REJECT => Ok(Vec::new()),
+            // unknown message types must be ignored
+            _ => Ok(Vec::new()),
+        }
+    }
+}
@@ -1,25 +1,37 @@
 use util;
 
 use bencode::{self, FromBencode, Bencode};
+use hyper::{self, Client};
+use hyper::header::Connection;
+use hyper::status::StatusCode;
 use openssl::crypto::hash as openssl_hash;
 use std::collections::BTreeMap;
-use std::path::PathBuf;
+use std::error::Error;
+use std::path::{Path, PathBuf};
 use std::fs::File;
 use std::fmt;
+use std::u32;
 use std::io::{self, Read};
 
-static TORRENT_FILE_DIR: &'static str = "data";
 
 pub type Sha1Hash = Vec<u8>;
 
+#[derive(Clone)]
 pub struct MetaInfo {
-    pub info: SingleFileInfo,
+    pub info: Info,
 
     // Hash of the info dict
     pub info_hash: Sha1Hash,
 
-    // announce URL of tracker
-    pub announce: String,
+    // the bencoded info dict, as served to peers over ut_metadata
+    pub info_bytes: Vec<u8>,
+
+    // announce URL of tracker; optional when there's an `announce-list`
+    pub announce: Option<String>,
+
+    // BEP 12 tiers of tracker URLs, tried in order; empty if the torrent
+    // only has `announce`
+    pub announce_list: Vec<Vec<String>>,
 
     // in Unix epoch format
     pub creation_date: Option<i64>,
@@ -29,11 +41,137 @@ pub struct MetaInfo {
 
     // encoding used for `pieces` portion of info dictionary
     pub encoding: Option<String>,
+
+    // BEP 19 web seeds: HTTP servers with a copy of the files
+    pub url_list: Vec<String>,
+
+    // BEP 17 HTTP seeds: scripts that serve pieces by index
+    pub http_seeds: Vec<String>,
+}
+
+// A file's place in the torrent, which is all the files laid end to end
+#[derive(Debug, Clone)]
+pub struct FileEntry {
+    // relative path on disk; under a directory named after the torrent
+    // for multi-file torrents
+    pub path: PathBuf,
+
+    // the path as it appears in the torrent, starting with `name`
+    pub components: Vec<String>,
+
+    pub length: u64,
+
+    // where the file starts in the torrent
+    pub offset: u64,
+}
+
+// The part of a file covered by some range of the torrent
+#[derive(Debug, Clone, PartialEq)]
+pub struct FileSlice {
+    // index into `MetaInfo::files()`
+    pub file: usize,
+
+    // offset within the file
+    pub offset: u64,
+
+    pub length: u64,
 }
 
 impl MetaInfo {
-    pub fn num_file_bytes(&self) -> u32 {
-        self.info.length
+    pub fn num_file_bytes(&self) -> u64 {
+        match self.info {
+            Info::SingleFile(ref info) => info.length,
+            Info::MultiFile(ref info) => {
+                info.files.iter().map(|f| f.length).fold(0, |a, b| a + b)
+            },
+        }
+    }
+
+    // Tracker URLs in the order they should be tried. When a torrent has
+    // an `announce-list` it replaces `announce`.
+    pub fn trackers(&self) -> Vec<String> {
+        if self.announce_list.is_empty() {
+            return self.announce.iter().cloned().collect();
+        }
+        let mut trackers = Vec::new();
+        for tier in self.announce_list.iter() {
+            for url in tier.iter() {
+                if !trackers.contains(url) {
+                    trackers.push(url.clone());
+                }
+            }
+        }
+        trackers
+    }
+
+    pub fn num_pieces(&self) -> u32 {
+        self.info.pieces().len() as u32
+    }
+
+    pub fn piece_offset(&self, index: u32) -> u64 {
+        index as u64 * self.info.piece_length() as u64
+    }
+
+    // length of piece `index`; the last piece is usually shorter
+    pub fn piece_size(&self, index: u32) -> u32 {
+        let piece_length = self.info.piece_length() as u64;
+        let remaining = self.num_file_bytes() - self.piece_offset(index);
+        if remaining < piece_length { remaining as u32 } else { piece_length as u32 }
+    }
+
+    pub fn files(&self) -> Vec<FileEntry> {
+        match self.info {
+            Info::SingleFile(ref info) => vec![FileEntry {
+                path: PathBuf::from(&info.name),
+                components: vec![info.name.clone()],
+                length: info.length,
+                offset: 0,
+            }],
+            Info::MultiFile(ref info) => {
+                let mut offset = 0;
+                let mut files = Vec::new();
+                for f in info.files.iter() {
+                    let mut path = PathBuf::from(&info.name);
+                    let mut components = vec![info.name.clone()];
+                    for c in f.path.iter() {
+                        path.push(c);
+                        components.push(c.clone());
+                    }
+                    files.push(FileEntry {
+                        path: path,
+                        components: components,
+                        length: f.length,
+                        offset: offset,
+                    });
+                    offset += f.length;
+                }
+                files
+            },
+        }
+    }
+
+    // Splits `length` bytes starting at `offset` into the files they fall in
+    pub fn map_range(&self, offset: u64, length: u64) -> Vec<FileSlice> {
+        let end = offset + length;
+        let mut slices = Vec::new();
+        for (i, f) in self.files().iter().enumerate() {
+            let file_end = f.offset + f.length;
+            if file_end <= offset || f.offset >= end || f.length == 0 {
+                continue;
+            }
+            let start = if offset > f.offset { offset } else { f.offset };
+            let stop = if end < file_end { end } else { file_end };
+            slices.push(FileSlice {
+                file: i,
+                offset: start - f.offset,
+                length: stop - start,
+            });
+        }
+        slices
+    }
+
+    pub fn map_piece(&self, index: u32) -> Vec<FileSlice> {
+        self.map_range(self.piece_offset(index), self.piece_size(index) as u64)
     }
 }
 
@@ -50,6 +188,44 @@ pub trait InfoDictionary {
 }
 
 // "a dictionary that describes the file(s) of the torrent"
+#[derive(Clone)]
+pub enum Info {
+    SingleFile(SingleFileInfo),
+    MultiFile(MultiFileInfo),
+}
+
+impl Info {
+    pub fn piece_length(&self) -> u32 {
+        match *self {
+            Info::SingleFile(ref info) => info.piece_length,
+            Info::MultiFile(ref info) => info.piece_length,
+        }
+    }
+
+    pub fn pieces(&self) -> &[Sha1Hash] {
+        match *self {
+            Info::SingleFile(ref info) => &info.pieces[..],
+            Info::MultiFile(ref info) => &info.pieces[..],
+        }
+    }
+
+    // file name, or directory name for multi-file torrents
+    pub fn name(&self) -> &str {
+        match *self {
+            Info::SingleFile(ref info) => &info.name,
+            Info::MultiFile(ref info) => &info.name,
+        }
+    }
+
+    pub fn is_private(&self) -> bool {
+        match *self {
+            Info::SingleFile(ref info) => info.private,
+            Info::MultiFile(ref info) => info.private,
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct SingleFileInfo {
     pub piece_length: u32,
     pub pieces: Vec<Sha1Hash>,
@@ -58,18 +234,37 @@ pub struct SingleFileInfo {
     pub name: String,
 
     // length of file in bytes
-    pub length: u32,
+    pub length: u64,
 
     pub md5sum: Option<[char; 32]>,
+
+    // BEP 27: peers may only be obtained from the tracker
+    pub private: bool,
 }
 
-fn bencode_dict_insert(dict: &mut Bencode,
-                       key: bencode::util::ByteString,
-                       value: Bencode) -> Option<Bencode> {
-    match *dict {
-        Bencode::Dict(ref mut map) => map.insert(key, value),
-        _ => return panic!("bencode_dict_insert: `dict` is not a `Bencode::Dict`"),
-    }
+#[derive(Clone)]
+pub struct MultiFileInfo {
+    pub piece_length: u32,
+    pub pieces: Vec<Sha1Hash>,
+
+    // name of the directory the files go in
+    pub name: String,
+
+    pub files: Vec<FileInfo>,
+
+    // BEP 27: peers may only be obtained from the tracker
+    pub private: bool,
+}
+
+#[derive(Clone)]
+pub struct FileInfo {
+    // length of file in bytes
+    pub length: u64,
+
+    // path components, the last one being the file name
+    pub path: Vec<String>,
+
+    pub md5sum: Option<[char; 32]>,
 }
 
 pub type DecodeError = String;
@@ -79,18 +274,15 @@ impl FromBencode for MetaInfo {
     fn from_bencode(b: &Bencode) -> Result<MetaInfo, Self::Err> {
         match *b {
             Bencode::Dict(ref m) => {
-                let announce = util::get_field(m, "announce");
+                let announce = util::maybe_get_field(m, "announce");
                 let created_by = util::maybe_get_field(m, "created by");
                 let creation_date = util::maybe_get_field(m, "creation date");
                 let encoding = util::maybe_get_field(m, "encoding");
+                let url_list = util::maybe_get_field(m, "url-list");
+                let http_seeds = util::maybe_get_field(m, "httpseeds");
+                let announce_list = util::maybe_get_field(m, "announce-list");
 
-                println!("announce = {:?},\n\
-                          creation_date = {:?},\n\
-                          created by = {:?},\n\
-                          encoding = {:?}",
-                          announce, creation_date, created_by, encoding);
-
-                let info_dict = util::get_field(m, "info");
+                let info_dict = try!(util::get_field(m, "info"));
                 let info = try!(FromBencode::from_bencode(&info_dict));
                 let info_dict_bytes = match info_dict.to_bytes() {
                     Ok(b) => b,
@@ -99,24 +291,30 @@ impl FromBencode for MetaInfo {
                 let info_hash = openssl_hash::hash(openssl_hash::Type::SHA1,
                                                &info_dict_bytes[..]);
 
-                fn unwrap_bencode_bytestring(b: Bencode, field_name: &str) -> String {
-                    let bytes = util::bencode_string_unwrap_bytes(b);
-                    match String::from_utf8(bytes) {
-                        Ok(s) => s,
-                        Err(e) => return panic!("Error converting {} to string: {:?}",
-                                                field_name, e),
-                    }
-                }
+                let announce_list = match announce_list {
+                    Some(b) => try!(field(util::bencode_unwrap_list(b), "announce-list"))
+                        .into_iter()
+                        .map(string_or_list)
+                        .filter(|tier| !tier.is_empty())
+                        .collect(),
+                    None => Vec::new(),
+                };
 
                 Ok(MetaInfo {
                     info: info,
                     info_hash: info_hash,
-                    announce: unwrap_bencode_bytestring(announce, "announce"),
-                    creation_date: creation_date.map(|cd| util::bencode_unwrap_number(cd)),
-                    created_by: created_by.map(|cb| unwrap_bencode_bytestring(cb,
-                                                                              "created_by")),
-                    encoding: encoding.map(|enc| unwrap_bencode_bytestring(enc,
-                                                                           "encoding")),
+                    info_bytes: info_dict_bytes,
+                    announce: try!(maybe_string(announce, "announce")),
+                    announce_list: announce_list,
+                    creation_date: match creation_date {
+                        Some(cd) => Some(try!(field(util::bencode_unwrap_number(cd),
+                                                    "creation date"))),
+                        None => None,
+                    },
+                    created_by: try!(maybe_string(created_by, "created by")),
+                    encoding: try!(maybe_string(encoding, "encoding")),
+                    url_list: url_list.map_or(Vec::new(), |b| string_or_list(b)),
+                    http_seeds: http_seeds.map_or(Vec::new(), |b| string_or_list(b)),
                 })
             },
             _ => Err(format!("Bencoded string is not a dictionary.")),
@@ -124,43 +322,174 @@ impl FromBencode for MetaInfo {
     }
 }
 
+// `url-list` and `httpseeds` may be a single string or a list of them.
+// Entries that aren't UTF-8 strings are skipped.
+fn string_or_list(b: Bencode) -> Vec<String> {
+    let items = match b {
+        Bencode::List(v) => v,
+        b => vec![b],
+    };
+    items.into_iter()
+         .filter_map(|b| match b {
+             Bencode::ByteString(v) => String::from_utf8(v).ok(),
+             _ => None,
+         })
+         .filter(|s| !s.is_empty())
+         .collect()
+}
+
+// Names the field a util::bencode_unwrap_* failure came from
+fn field<T>(r: Result<T, String>, name: &str) -> Result<T, DecodeError> {
+    r.map_err(|e| format!("{}: {}", name, e))
+}
+
+fn maybe_string(b: Option<Bencode>, name: &str) -> Result<Option<String>, DecodeError> {
+    match b {
+        Some(b) => field(util::bencode_string_unwrap_string(b), name).map(Some),
+        None => Ok(None),
+    }
+}
+
+fn split_pieces(pieces: Bencode) -> Result<Vec<Sha1Hash>, DecodeError> {
+    let bytes = try!(field(util::bencode_string_unwrap_bytes(pieces), "pieces"));
+    if bytes.len() % 20 != 0 {
+        return Err(format!("pieces: length {} is not a multiple of 20", bytes.len()));
+    }
+    Ok(bytes.chunks(20).map(|c| c.to_vec()).collect())
+}
+
+fn is_private(private: Option<Bencode>) -> Result<bool, DecodeError> {
+    match private {
+        Some(p) => field(util::bencode_unwrap_number(p), "private").map(|p| p == 1),
+        None => Ok(false),
+    }
+}
+
+fn unwrap_piece_length(b: Bencode) -> Result<u32, DecodeError> {
+    let n = try!(field(util::bencode_unwrap_number(b), "piece length"));
+    if n <= 0 || n > u32::MAX as i64 {
+        return Err(format!("Invalid piece length: {}", n));
+    }
+    Ok(n as u32)
+}
+
+fn unwrap_file_length(b: Bencode) -> Result<u64, DecodeError> {
+    let n = try!(field(util::bencode_unwrap_number(b), "length"));
+    if n < 0 {
+        return Err(format!("Invalid file length: {}", n));
+    }
+    Ok(n as u64)
+}
+
+// Every byte of the torrent is in exactly one piece, so the piece count is
+// fixed by the total length; anything else would leave pieces without data
+// or data without pieces.
+fn check_pieces(piece_length: u32,
+                pieces: &[Sha1Hash],
+                total_length: u64) -> Result<(), DecodeError> {
+    let piece_length = piece_length as u64;
+    let expected = total_length / piece_length +
+                   if total_length % piece_length == 0 { 0 } else { 1 };
+    if pieces.len() as u64 != expected {
+        return Err(format!("{} pieces of {} bytes can't hold {} bytes",
+                           pieces.len(), piece_length, total_length));
+    }
+    Ok(())
+}
+
+// A path component that can't take a file outside the torrent's directory
+fn valid_component(c: &str) -> bool {
+    !c.is_empty() && c != "." && c != ".." && !c.contains('/') && !c.contains('\\')
+}
+
+// `name` is the torrent's directory, or its only file, so it follows the
+// same rules as the components of a file's path
+fn check_name(name: &str) -> Result<(), DecodeError> {
+    if valid_component(name) {
+        Ok(())
+    } else {
+        Err(format!("Invalid torrent name: {:?}", name))
+    }
+}
+
+impl FromBencode for Info {
+    type Err = DecodeError;
+    fn from_bencode(b: &Bencode) -> Result<Info, Self::Err> {
+        match *b {
+            Bencode::Dict(ref m) => {
+                if util::maybe_get_field(m, "files").is_some() {
+                    FromBencode::from_bencode(b).map(Info::MultiFile)
+                } else {
+                    FromBencode::from_bencode(b).map(Info::SingleFile)
+                }
+            },
+            _ => Err(format!("Bencoded string is not a dictionary.")),
+        }
+    }
+}
+
 impl FromBencode for SingleFileInfo {
     type Err = DecodeError;
     fn from_bencode(b: &Bencode) -> Result<SingleFileInfo, Self::Err> {
         match *b {
             Bencode::Dict(ref m) => {
-                let piece_length = util::get_field(m, "piece length");
-                let pieces = util::get_field(m, "pieces");
-                let name = util::get_field(m, "name");
-                let length = util::get_field(m, "length");
-
-                println!("piece_length = {:?},\n\
-                          pieces = {:?},\n\
-                          name = {:?},\n\
-                          length = {:?}",
-                          piece_length, pieces, name, length);
-
-                let mut pieces_vec = Vec::new();
-                let mut buf = Vec::new();
-                let mut i = 0;
-                for b in util::bencode_string_unwrap_bytes(pieces).into_iter() {
-                    buf.push(b);
-                    if i == 19 {
-                        pieces_vec.push(buf);
-                        buf = Vec::new();
-                        i = 0;
-                    } else {
-                        i += 1;
-                    }
-                }
+                let piece_length = try!(util::get_field(m, "piece length"));
+                let piece_length = try!(unwrap_piece_length(piece_length));
+                let pieces = try!(split_pieces(try!(util::get_field(m, "pieces"))));
+                let name = try!(util::get_field(m, "name"));
+                let length = try!(unwrap_file_length(try!(util::get_field(m, "length"))));
+                let private = util::maybe_get_field(m, "private");
+                let name = try!(field(util::bencode_string_unwrap_string(name), "name"));
+                try!(check_name(&name));
+                try!(check_pieces(piece_length, &pieces, length));
 
                 // TODO: md5sum
                 Ok(SingleFileInfo {
-                    piece_length: util::bencode_unwrap_number(piece_length) as u32,
-                    pieces: pieces_vec,
-                    name: util::bencode_string_unwrap_string(name),
-                    length: util::bencode_unwrap_number(length) as u32,
+                    piece_length: piece_length,
+                    pieces: pieces,
+                    name: name,
+                    length: length,
                     md5sum: None,
+                    private: try!(is_private(private)),
+                })
+            },
+            _ => Err(format!("Bencoded string is not a dictionary.")),
+        }
+    }
+}
+
+impl FromBencode for MultiFileInfo {
+    type Err = DecodeError;
+    fn from_bencode(b: &Bencode) -> Result<MultiFileInfo, Self::Err> {
+        match *b {
+            Bencode::Dict(ref m) => {
+                let piece_length = try!(util::get_field(m, "piece length"));
+                let piece_length = try!(unwrap_piece_length(piece_length));
+                let pieces = try!(split_pieces(try!(util::get_field(m, "pieces"))));
+                let name = try!(util::get_field(m, "name"));
+                let files = try!(util::get_field(m, "files"));
+                let private = util::maybe_get_field(m, "private");
+                let name = try!(field(util::bencode_string_unwrap_string(name), "name"));
+                try!(check_name(&name));
+
+                let mut files_vec: Vec<FileInfo> = Vec::new();
+                let mut total_length: u64 = 0;
+                for f in try!(field(util::bencode_unwrap_list(files), "files")).iter() {
+                    let f: FileInfo = try!(FromBencode::from_bencode(f));
+                    total_length = match total_length.checked_add(f.length) {
+                        Some(n) => n,
+                        None => return Err(format!("Total length of files overflows")),
+                    };
+                    files_vec.push(f);
+                }
+                try!(check_pieces(piece_length, &pieces, total_length));
+
+                Ok(MultiFileInfo {
+                    piece_length: piece_length,
+                    pieces: pieces,
+                    name: name,
+                    files: files_vec,
+                    private: try!(is_private(private)),
                 })
             },
             _ => Err(format!("Bencoded string is not a dictionary.")),
@@ -168,10 +497,41 @@ impl FromBencode for SingleFileInfo {
     }
 }
 
+impl FromBencode for FileInfo {
+    type Err = DecodeError;
+    fn from_bencode(b: &Bencode) -> Result<FileInfo, Self::Err> {
+        match *b {
+            Bencode::Dict(ref m) => {
+                let length = try!(unwrap_file_length(try!(util::get_field(m, "length"))));
+                let path = try!(util::get_field(m, "path"));
+
+                let mut components = Vec::new();
+                for c in try!(field(util::bencode_unwrap_list(path), "path")).into_iter() {
+                    components.push(try!(field(util::bencode_string_unwrap_string(c), "path")));
+                }
+
+                // don't let a torrent write outside its own directory
+                if components.is_empty() || !components.iter().all(|c| valid_component(c)) {
+                    return Err(format!("Invalid file path: {:?}", components));
+                }
+
+                // TODO: md5sum
+                Ok(FileInfo {
+                    length: length,
+                    path: components,
+                    md5sum: None,
+                })
+            },
+            _ => Err(format!("File entry is not a dictionary.")),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum ParseError {
     IoError(io::Error),
     BencodeDecodingError(bencode::streaming::Error),
+    HyperError(hyper::Error),
     Other(String),
 }
 
@@ -187,21 +547,212 @@ impl From<bencode::streaming::Error> for ParseError {
     }
 }
 
-pub fn parse_torrent_file(torrent_file: &str) -> Result<MetaInfo, ParseError> {
-    let mut path = PathBuf::from(TORRENT_FILE_DIR);
-    path.push(torrent_file);
+impl From<hyper::Error> for ParseError {
+    fn from(err: hyper::Error) -> ParseError {
+        ParseError::HyperError(err)
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            ParseError::IoError(ref e) => write!(f, "couldn't read torrent: {}", e),
+            ParseError::BencodeDecodingError(ref e) => write!(f, "invalid bencode: {:?}", e),
+            ParseError::HyperError(ref e) => write!(f, "couldn't fetch torrent: {}", e),
+            ParseError::Other(ref e) => write!(f, "invalid torrent: {}", e),
+        }
+    }
+}
+
+impl Error for ParseError {
+    fn description(&self) -> &str {
+        match *self {
+            ParseError::IoError(_) => "couldn't read torrent",
+            ParseError::BencodeDecodingError(_) => "invalid bencode",
+            ParseError::HyperError(_) => "couldn't fetch torrent",
+            ParseError::Other(_) => "invalid torrent",
+        }
+    }
+}
 
-    println!("parse_torrent_file, path = {:?}\n", path);
+// Parses a torrent from `source`, which may be a path, `-` for stdin, or
+// an http(s) URL
+pub fn load(source: &str) -> Result<MetaInfo, ParseError> {
+    if source == "-" {
+        let mut buf = Vec::new();
+        try!(io::stdin().read_to_end(&mut buf));
+        from_bytes(&buf)
+    } else if source.starts_with("http://") || source.starts_with("https://") {
+        fetch_torrent(source)
+    } else {
+        parse_torrent_file(source)
+    }
+}
 
-    let mut f = try!(File::open(path));
+pub fn parse_torrent_file<P: AsRef<Path>>(torrent_file: P) -> Result<MetaInfo, ParseError> {
+    let mut f = try!(File::open(torrent_file));
     let mut buf = Vec::new();
     try!(f.read_to_end(&mut buf));
+    from_bytes(&buf)
+}
 
-    let bencode = try!(bencode::from_vec(buf));
-    let metainfo = match FromBencode::from_bencode(&bencode) {
-        Ok(metainfo) => metainfo,
-        Err(e) => return Err(ParseError::Other(e))
-    };
+pub fn fetch_torrent(url: &str) -> Result<MetaInfo, ParseError> {
+    let client = Client::new();
+    let mut res = try!(client.get(url)
+                             .header(Connection::close())
+                             .send());
+    if res.status != StatusCode::Ok {
+        return Err(ParseError::Other(format!("{} fetching {}", res.status, url)));
+    }
+
+    let mut buf = Vec::new();
+    try!(res.read_to_end(&mut buf));
+    from_bytes(&buf)
+}
+
+pub fn from_bytes(buf: &[u8]) -> Result<MetaInfo, ParseError> {
+    let bencode = try!(util::decode(buf));
+    match FromBencode::from_bencode(&bencode) {
+        Ok(metainfo) => Ok(metainfo),
+        Err(e) => Err(ParseError::Other(e)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::from_bytes;
+    use util;
+
+    use bencode::Bencode;
+
+    fn bytes(s: &str) -> Bencode {
+        Bencode::ByteString(s.as_bytes().to_vec())
+    }
+
+    // a single 6 byte file in two 4 byte pieces
+    fn info() -> Vec<(&'static str, Bencode)> {
+        vec![("name", bytes("t")),
+             ("piece length", Bencode::Number(4)),
+             ("length", Bencode::Number(6)),
+             ("pieces", Bencode::ByteString(vec![0; 40]))]
+    }
+
+    fn torrent(mut pairs: Vec<(&'static str, Bencode)>) -> Vec<u8> {
+        pairs.push(("info", util::bencode_dict(info())));
+        util::bencode_dict(pairs).to_bytes().unwrap()
+    }
+
+    // a torrent whose info dict has `key` set to `value`
+    fn torrent_with(key: &'static str, value: Bencode) -> Vec<u8> {
+        let mut info: Vec<_> = info().into_iter().filter(|&(k, _)| k != key).collect();
+        info.push((key, value));
+        util::bencode_dict(vec![("info", util::bencode_dict(info))]).to_bytes().unwrap()
+    }
+
+    #[test]
+    fn zero_piece_length() {
+        assert!(from_bytes(&torrent_with("piece length", Bencode::Number(0))).is_err());
+        assert!(from_bytes(&torrent_with("piece length", Bencode::Number(-4))).is_err());
+    }
+
+    #[test]
+    fn pieces_not_multiple_of_20() {
+        assert!(from_bytes(&torrent_with("pieces", Bencode::ByteString(vec![0; 39]))).is_err());
+    }
+
+    #[test]
+    fn piece_count_mismatch() {
+        // 6 bytes need two 4 byte pieces
+        assert!(from_bytes(&torrent_with("pieces", Bencode::ByteString(vec![0; 20]))).is_err());
+        assert!(from_bytes(&torrent_with("pieces", Bencode::ByteString(vec![0; 60]))).is_err());
+        assert!(from_bytes(&torrent_with("length", Bencode::Number(9))).is_err());
+        assert!(from_bytes(&torrent_with("length", Bencode::Number(-1))).is_err());
+    }
+
+    #[test]
+    fn multi_file_piece_count() {
+        fn file(length: i64) -> Bencode {
+            util::bencode_dict(vec![("length", Bencode::Number(length)),
+                                    ("path", Bencode::List(vec![bytes("f")]))])
+        }
+        let with_files = |files| {
+            let info = util::bencode_dict(vec![("name", bytes("t")),
+                                               ("piece length", Bencode::Number(4)),
+                                               ("pieces", Bencode::ByteString(vec![0; 40])),
+                                               ("files", Bencode::List(files))]);
+            util::bencode_dict(vec![("info", info)]).to_bytes().unwrap()
+        };
+        let info = from_bytes(&with_files(vec![file(3), file(3)])).unwrap();
+        assert_eq!(info.piece_size(1), 2);
+        assert!(from_bytes(&with_files(vec![file(3), file(6)])).is_err());
+        assert!(from_bytes(&with_files(vec![file(3), file(-3)])).is_err());
+    }
+
+    #[test]
+    fn wrong_types() {
+        assert!(from_bytes(&torrent_with("name", Bencode::Number(1))).is_err());
+        assert!(from_bytes(&torrent_with("piece length", bytes("4"))).is_err());
+        assert!(from_bytes(&torrent_with("pieces", Bencode::Number(0))).is_err());
+        assert!(from_bytes(&torrent_with("private", bytes("1"))).is_err());
+        assert!(from_bytes(&torrent(vec![("announce", Bencode::Number(1))])).is_err());
+        assert!(from_bytes(&torrent(vec![("announce-list", bytes("http://a"))])).is_err());
+        assert!(from_bytes(&torrent(vec![("creation date", bytes("today"))])).is_err());
+    }
+
+    #[test]
+    fn missing_fields() {
+        assert!(from_bytes(&util::bencode_dict(vec![]).to_bytes().unwrap()).is_err());
+        for key in ["name", "piece length", "pieces", "length"].iter() {
+            let info: Vec<_> = info().into_iter().filter(|&(k, _)| k != *key).collect();
+            let torrent = util::bencode_dict(vec![("info", util::bencode_dict(info))]);
+            assert!(from_bytes(&torrent.to_bytes().unwrap()).is_err());
+        }
+    }
+
+    #[test]
+    fn announce_list_without_announce() {
+        let tiers = Bencode::List(vec![Bencode::List(vec![bytes("http://a/announce")]),
+                                       Bencode::List(vec![bytes("http://b/announce")])]);
+        let info = from_bytes(&torrent(vec![("announce-list", tiers)])).unwrap();
+        assert_eq!(info.announce, None);
+        assert_eq!(info.trackers(), vec!["http://a/announce", "http://b/announce"]);
+    }
+
+    #[test]
+    fn announce_only() {
+        let info = from_bytes(&torrent(vec![("announce", bytes("http://a/announce"))])).unwrap();
+        assert_eq!(info.announce, Some(String::from("http://a/announce")));
+        assert_eq!(info.trackers(), vec!["http://a/announce"]);
+    }
+
+    #[test]
+    fn truncated() {
+        let buf = torrent(vec![("announce", bytes("http://a/announce"))]);
+        assert!(from_bytes(&buf).is_ok());
+        for n in 0..buf.len() {
+            assert!(from_bytes(&buf[..n]).is_err(), "parsed {} of {} bytes", n, buf.len());
+        }
+    }
+
+    #[test]
+    fn mistyped() {
+        let not_dicts = vec![Bencode::Number(1),
+                             bytes("d4:infoe"),
+                             Bencode::List(vec![util::bencode_dict(info())])];
+        for b in not_dicts.into_iter() {
+            assert!(from_bytes(&b.to_bytes().unwrap()).is_err());
+            let torrent = util::bencode_dict(vec![("info", b)]);
+            assert!(from_bytes(&torrent.to_bytes().unwrap()).is_err());
+        }
+
+        let mut files = info();
+        files.push(("files", Bencode::List(vec![Bencode::Number(6)])));
+        let torrent = util::bencode_dict(vec![("info", util::bencode_dict(files))]);
+        assert!(from_bytes(&torrent.to_bytes().unwrap()).is_err());
 
-    Ok(metainfo)
+        let mut files = info();
+        files.push(("files", bytes("t")));
+        let torrent = util::bencode_dict(vec![("info", util::bencode_dict(files))]);
+        assert!(from_bytes(&torrent.to_bytes().unwrap()).is_err());
+    }
 }
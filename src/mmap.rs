@@ -0,0 +1,73 @@
+// Files mapped into memory, for storage that reads and writes pieces
+// without going through read() and write().
+
+use libc;
+use std::fs::File;
+use std::io;
+use std::ptr;
+use std::slice;
+use std::usize;
+
+// A whole file mapped read-write and shared, so that writes to the mapping
+// are writes to the file. The file has to stay at least as long as the
+// mapping: touching pages past its end kills the process with SIGBUS, as
+// does running out of disk while writing to a sparse file.
+pub struct Mmap {
+    ptr: *mut u8,
+    len: usize,
+}
+
+// the mapping is plain memory; `&mut` access is what stops writes racing
+unsafe impl Send for Mmap {}
+unsafe impl Sync for Mmap {}
+
+impl Mmap {
+    // Maps the first `len` bytes of `file`, which must be open for reading
+    // and writing
+    #[cfg(unix)]
+    pub fn map(file: &File, len: u64) -> io::Result<Mmap> {
+        use std::os::unix::io::AsRawFd;
+
+        if len == 0 || len > usize::MAX as u64 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "can't map an empty file, or one bigger than memory"));
+        }
+        let ptr = unsafe {
+            libc::mmap(ptr::null_mut(), len as libc::size_t,
+                       libc::PROT_READ | libc::PROT_WRITE, libc::MAP_SHARED,
+                       file.as_raw_fd(), 0)
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Mmap {
+            ptr: ptr as *mut u8,
+            len: len as usize,
+        })
+    }
+
+    #[cfg(not(unix))]
+    pub fn map(_: &File, _: u64) -> io::Result<Mmap> {
+        Err(io::Error::new(io::ErrorKind::Other, "memory mapping isn't supported here"))
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe { slice::from_raw_parts(self.ptr, self.len) }
+    }
+
+    pub fn as_mut_slice(&mut self) -> &mut [u8] {
+        unsafe { slice::from_raw_parts_mut(self.ptr, self.len) }
+    }
+}
+
+impl Drop for Mmap {
+    #[cfg(unix)]
+    fn drop(&mut self) {
+        unsafe {
+            libc::munmap(self.ptr as *mut libc::c_void, self.len as libc::size_t);
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn drop(&mut self) {}
+}
@@ -0,0 +1,583 @@
+// Message Stream Encryption, a.k.a. Protocol Encryption.
+//
+// A Diffie-Hellman exchange gives both sides a shared secret S, and the
+// torrent's info hash (SKEY) is mixed in so that the receiving side can
+// tell which torrent the connection is for without it appearing on the
+// wire. Everything after that is obfuscated with RC4.
+
+use util;
+
+use openssl::bn::BigNum;
+use openssl::crypto::hash as openssl_hash;
+use openssl::ssl::error::SslError;
+use rand::{self, Rng};
+use std::io::{self, Read, Write};
+
+// 768 bit safe prime
+static P: &'static [u8] = &[
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xC9, 0x0F, 0xDA, 0xA2,
+    0x21, 0x68, 0xC2, 0x34, 0xC4, 0xC6, 0x62, 0x8B, 0x80, 0xDC, 0x1C, 0xD1,
+    0x29, 0x02, 0x4E, 0x08, 0x8A, 0x67, 0xCC, 0x74, 0x02, 0x0B, 0xBE, 0xA6,
+    0x3B, 0x13, 0x9B, 0x22, 0x51, 0x4A, 0x08, 0x79, 0x8E, 0x34, 0x04, 0xDD,
+    0xEF, 0x95, 0x19, 0xB3, 0xCD, 0x3A, 0x43, 0x1B, 0x30, 0x2B, 0x0A, 0x6D,
+    0xF2, 0x5F, 0x14, 0x37, 0x4F, 0xE1, 0x35, 0x6D, 0x6D, 0x51, 0xC2, 0x45,
+    0xE4, 0x85, 0xB5, 0x76, 0x62, 0x5E, 0x7E, 0xC6, 0xF4, 0x4C, 0x42, 0xE9,
+    0xA6, 0x3A, 0x36, 0x21, 0x00, 0x00, 0x00, 0x00, 0x00, 0x09, 0x05, 0x63,
+];
+
+const G: u64 = 2;
+
+// length of the DH public keys and shared secret
+const KEY_LEN: usize = 96;
+
+// length of our random private keys
+const PRIVATE_KEY_LEN: usize = 20;
+
+const MAX_PAD_LEN: usize = 512;
+
+// verification constant
+const VC: [u8; 8] = [0; 8];
+
+// crypto_provide / crypto_select bits
+pub const CRYPTO_PLAINTEXT: u32 = 0x01;
+pub const CRYPTO_RC4: u32 = 0x02;
+
+// RC4 output discarded after keying, as the spec requires
+const RC4_DISCARD: usize = 1024;
+
+const PROTOCOL: &'static [u8] = b"\x13BitTorrent protocol";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EncryptionPolicy {
+    // never use MSE; refuse incoming encrypted connections
+    PlaintextOnly,
+
+    // try MSE first and fall back to plaintext; accept both
+    PreferEncrypted,
+
+    // only RC4-encrypted connections, in both directions
+    RequireEncrypted,
+}
+
+impl EncryptionPolicy {
+    pub fn from_str(s: &str) -> Option<EncryptionPolicy> {
+        match s {
+            "plaintext" => Some(EncryptionPolicy::PlaintextOnly),
+            "prefer" => Some(EncryptionPolicy::PreferEncrypted),
+            "require" => Some(EncryptionPolicy::RequireEncrypted),
+            _ => None,
+        }
+    }
+
+    fn crypto_provide(&self) -> u32 {
+        match *self {
+            EncryptionPolicy::PlaintextOnly => CRYPTO_PLAINTEXT,
+            EncryptionPolicy::PreferEncrypted => CRYPTO_RC4 | CRYPTO_PLAINTEXT,
+            EncryptionPolicy::RequireEncrypted => CRYPTO_RC4,
+        }
+    }
+
+    // the method we pick from what an initiator provides
+    fn crypto_select(&self, provide: u32) -> Option<u32> {
+        let ours = self.crypto_provide();
+        if provide & ours & CRYPTO_RC4 != 0 {
+            Some(CRYPTO_RC4)
+        } else if provide & ours & CRYPTO_PLAINTEXT != 0 {
+            Some(CRYPTO_PLAINTEXT)
+        } else {
+            None
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum MseError {
+    IoError(io::Error),
+    ProtocolError(String),
+
+    // the connection doesn't satisfy our policy
+    Refused(String),
+}
+
+impl From<io::Error> for MseError {
+    fn from(e: io::Error) -> MseError {
+        MseError::IoError(e)
+    }
+}
+
+#[derive(Clone)]
+pub struct Rc4 {
+    s: [u8; 256],
+    i: u8,
+    j: u8,
+}
+
+impl Rc4 {
+    pub fn new(key: &[u8]) -> Rc4 {
+        let mut s = [0u8; 256];
+        for i in 0..256 {
+            s[i] = i as u8;
+        }
+        let mut j: u8 = 0;
+        for i in 0..256 {
+            j = j.wrapping_add(s[i]).wrapping_add(key[i % key.len()]);
+            s.swap(i, j as usize);
+        }
+
+        let mut rc4 = Rc4 { s: s, i: 0, j: 0 };
+        let mut discard = [0u8; RC4_DISCARD];
+        rc4.process(&mut discard);
+        rc4
+    }
+
+    // encrypts or decrypts `data` in place
+    pub fn process(&mut self, data: &mut [u8]) {
+        for b in data.iter_mut() {
+            self.i = self.i.wrapping_add(1);
+            self.j = self.j.wrapping_add(self.s[self.i as usize]);
+            self.s.swap(self.i as usize, self.j as usize);
+            let k = self.s[self.s[self.i as usize].wrapping_add(self.s[self.j as usize]) as usize];
+            *b ^= k;
+        }
+    }
+}
+
+// A peer connection, after MSE negotiation. Reads and writes go through
+// RC4 if it was selected.
+pub struct PeerStream<S> {
+    inner: S,
+
+    // peer protocol bytes received during negotiation, already decrypted
+    buffered: Vec<u8>,
+
+    encrypt: Option<Rc4>,
+    decrypt: Option<Rc4>,
+}
+
+impl<S> PeerStream<S> {
+    pub fn plaintext(inner: S) -> PeerStream<S> {
+        PeerStream {
+            inner: inner,
+            buffered: Vec::new(),
+            encrypt: None,
+            decrypt: None,
+        }
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.encrypt.is_some()
+    }
+
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    pub fn get_mut(&mut self) -> &mut S {
+        &mut self.inner
+    }
+}
+
+impl<S: Read> Read for PeerStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.buffered.is_empty() {
+            let n = if buf.len() < self.buffered.len() { buf.len() } else { self.buffered.len() };
+            for (dst, src) in buf.iter_mut().zip(self.buffered.drain(..n)) {
+                *dst = src;
+            }
+            return Ok(n);
+        }
+
+        let n = try!(self.inner.read(buf));
+        if let Some(ref mut rc4) = self.decrypt {
+            rc4.process(&mut buf[..n]);
+        }
+        Ok(n)
+    }
+}
+
+impl<S: Write> Write for PeerStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.encrypt {
+            Some(ref mut rc4) => {
+                let mut data = buf.to_vec();
+                rc4.process(&mut data);
+                try!(self.inner.write_all(&data));
+                Ok(buf.len())
+            },
+            None => self.inner.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+fn sha1(parts: &[&[u8]]) -> Vec<u8> {
+    let mut v = Vec::new();
+    for p in parts.iter() {
+        v.extend(p.iter());
+    }
+    openssl_hash::hash(openssl_hash::Type::SHA1, &v[..])
+}
+
+fn xor(a: &[u8], b: &[u8]) -> Vec<u8> {
+    a.iter().zip(b.iter()).map(|(x, y)| x ^ y).collect()
+}
+
+fn random_pad() -> Vec<u8> {
+    let mut rng = rand::thread_rng();
+    let len = rng.gen_range(0, MAX_PAD_LEN + 1);
+    rng.gen_iter::<u8>().take(len).collect()
+}
+
+// big-endian, left-padded to KEY_LEN bytes
+fn to_key_bytes(n: &BigNum) -> Vec<u8> {
+    let bytes = n.to_vec();
+    let mut v = vec![0; KEY_LEN - bytes.len()];
+    v.extend(bytes.into_iter());
+    v
+}
+
+fn bignum<T>(r: Result<T, SslError>) -> Result<T, MseError> {
+    r.map_err(|e| MseError::ProtocolError(format!("Bignum error: {:?}", e)))
+}
+
+struct KeyPair {
+    private: BigNum,
+    public: Vec<u8>,
+}
+
+impl KeyPair {
+    fn generate() -> Result<KeyPair, MseError> {
+        let mut rng = rand::thread_rng();
+        let private_bytes: Vec<u8> = rng.gen_iter::<u8>().take(PRIVATE_KEY_LEN).collect();
+        let private = try!(bignum(BigNum::new_from_slice(&private_bytes)));
+        let p = try!(bignum(BigNum::new_from_slice(P)));
+        let g = try!(bignum(BigNum::new_from(G)));
+        let public = try!(bignum(g.checked_mod_exp(&private, &p)));
+
+        Ok(KeyPair {
+            private: private,
+            public: to_key_bytes(&public),
+        })
+    }
+
+    // the shared secret S from the other side's public key
+    fn secret(&self, their_public: &[u8]) -> Result<Vec<u8>, MseError> {
+        let y = try!(bignum(BigNum::new_from_slice(their_public)));
+        let p = try!(bignum(BigNum::new_from_slice(P)));
+        let s = try!(bignum(y.checked_mod_exp(&self.private, &p)));
+        Ok(to_key_bytes(&s))
+    }
+}
+
+// Reads until the last bytes read equal `pattern`, giving up after `max`
+// bytes. Used to skip the other side's random padding.
+fn synchronize<S: Read>(stream: &mut S, pattern: &[u8], max: usize) -> Result<(), MseError> {
+    let mut window = Vec::with_capacity(max + pattern.len());
+    let mut byte = [0; 1];
+    while window.len() < max + pattern.len() {
+        try!(stream.read_exact(&mut byte));
+        window.push(byte[0]);
+        if window.ends_with(pattern) {
+            return Ok(());
+        }
+    }
+    Err(MseError::ProtocolError(String::from("Couldn't find synchronization point")))
+}
+
+fn read_decrypted<S: Read>(stream: &mut S, rc4: &mut Rc4, n: usize) -> Result<Vec<u8>, MseError> {
+    let mut buf = vec![0; n];
+    try!(stream.read_exact(&mut buf));
+    rc4.process(&mut buf);
+    Ok(buf)
+}
+
+// Opens an outgoing connection according to `policy`, sending `handshake`
+// (the BitTorrent handshake) as the initial payload.
+pub fn connect<S: Read + Write>(mut stream: S, info_hash: &[u8], policy: EncryptionPolicy,
+                                handshake: &[u8]) -> Result<PeerStream<S>, MseError> {
+    if policy == EncryptionPolicy::PlaintextOnly {
+        try!(stream.write_all(handshake));
+        return Ok(PeerStream::plaintext(stream));
+    }
+
+    let keys = try!(KeyPair::generate());
+    let mut msg = keys.public.clone();
+    msg.extend(random_pad().into_iter());
+    try!(stream.write_all(&msg));
+
+    let mut yb = [0; KEY_LEN];
+    try!(stream.read_exact(&mut yb));
+    let s = try!(keys.secret(&yb));
+
+    let mut encrypt = Rc4::new(&sha1(&[b"keyA", &s, info_hash]));
+    let mut decrypt = Rc4::new(&sha1(&[b"keyB", &s, info_hash]));
+
+    // VC, crypto_provide, len(PadC), PadC (empty), len(IA), IA
+    let mut payload = VC.to_vec();
+    let provide = policy.crypto_provide();
+    payload.extend(util::u32_to_be_bytes(provide).iter());
+    payload.extend(util::u16_to_be_bytes(0).iter());
+    payload.extend(util::u16_to_be_bytes(handshake.len() as u16).iter());
+    payload.extend(handshake.iter());
+    encrypt.process(&mut payload);
+
+    let mut msg = sha1(&[b"req1", &s]);
+    msg.extend(xor(&sha1(&[b"req2", info_hash]), &sha1(&[b"req3", &s])).into_iter());
+    msg.extend(payload.into_iter());
+    try!(stream.write_all(&msg));
+
+    // B's reply starts with an encrypted VC after its padding
+    let mut encrypted_vc = VC.to_vec();
+    decrypt.process(&mut encrypted_vc);
+    try!(synchronize(&mut stream, &encrypted_vc, MAX_PAD_LEN));
+
+    let select = util::u32_from_be_bytes(&try!(read_decrypted(&mut stream, &mut decrypt, 4)));
+    let pad_len = util::u16_from_be_bytes(&try!(read_decrypted(&mut stream, &mut decrypt, 2))) as usize;
+    if pad_len > MAX_PAD_LEN {
+        return Err(MseError::ProtocolError(format!("PadD too long: {}", pad_len)));
+    }
+    try!(read_decrypted(&mut stream, &mut decrypt, pad_len));
+
+    if select & provide == 0 || (select != CRYPTO_RC4 && select != CRYPTO_PLAINTEXT) {
+        return Err(MseError::Refused(format!("Peer selected crypto method {:#x}", select)));
+    }
+
+    let rc4 = select == CRYPTO_RC4;
+    Ok(PeerStream {
+        inner: stream,
+        buffered: Vec::new(),
+        encrypt: if rc4 { Some(encrypt) } else { None },
+        decrypt: if rc4 { Some(decrypt) } else { None },
+    })
+}
+
+// Negotiates an incoming connection according to `policy`. Returns the
+// stream, positioned at the start of the peer's BitTorrent handshake, and
+// for encrypted connections the info hash it was for.
+pub fn accept<S: Read + Write>(mut stream: S, info_hashes: &[Vec<u8>], policy: EncryptionPolicy)
+        -> Result<(PeerStream<S>, Option<Vec<u8>>), MseError> {
+    let mut start = [0; 20];
+    try!(stream.read_exact(&mut start));
+
+    if &start[..] == PROTOCOL {
+        if policy == EncryptionPolicy::RequireEncrypted {
+            return Err(MseError::Refused(String::from("Plaintext connection")));
+        }
+        let mut ps = PeerStream::plaintext(stream);
+        ps.buffered = start.to_vec();
+        return Ok((ps, None));
+    }
+
+    if policy == EncryptionPolicy::PlaintextOnly {
+        return Err(MseError::Refused(String::from("Encrypted connection")));
+    }
+
+    let mut ya = start.to_vec();
+    let mut rest = [0; KEY_LEN - 20];
+    try!(stream.read_exact(&mut rest));
+    ya.extend(rest.iter());
+
+    let keys = try!(KeyPair::generate());
+    let mut msg = keys.public.clone();
+    msg.extend(random_pad().into_iter());
+    try!(stream.write_all(&msg));
+
+    let s = try!(keys.secret(&ya));
+    try!(synchronize(&mut stream, &sha1(&[b"req1", &s]), MAX_PAD_LEN));
+
+    let mut obfuscated = [0; 20];
+    try!(stream.read_exact(&mut obfuscated));
+    let req3 = sha1(&[b"req3", &s]);
+    let skey = match info_hashes.iter().find(|ih| xor(&sha1(&[b"req2", ih]), &req3) == obfuscated) {
+        Some(ih) => ih.clone(),
+        None => return Err(MseError::Refused(String::from("Unknown info hash"))),
+    };
+
+    let mut decrypt = Rc4::new(&sha1(&[b"keyA", &s, &skey]));
+    let mut encrypt = Rc4::new(&sha1(&[b"keyB", &s, &skey]));
+
+    let vc = try!(read_decrypted(&mut stream, &mut decrypt, 8));
+    if vc != VC {
+        return Err(MseError::ProtocolError(String::from("Bad verification constant")));
+    }
+    let provide = util::u32_from_be_bytes(&try!(read_decrypted(&mut stream, &mut decrypt, 4)));
+    let pad_len = util::u16_from_be_bytes(&try!(read_decrypted(&mut stream, &mut decrypt, 2))) as usize;
+    if pad_len > MAX_PAD_LEN {
+        return Err(MseError::ProtocolError(format!("PadC too long: {}", pad_len)));
+    }
+    try!(read_decrypted(&mut stream, &mut decrypt, pad_len));
+    let ia_len = util::u16_from_be_bytes(&try!(read_decrypted(&mut stream, &mut decrypt, 2))) as usize;
+    let ia = try!(read_decrypted(&mut stream, &mut decrypt, ia_len));
+
+    let select = match policy.crypto_select(provide) {
+        Some(select) => select,
+        None => return Err(MseError::Refused(format!("Peer provided crypto methods {:#x}",
+                                                     provide))),
+    };
+
+    // VC, crypto_select, len(PadD), PadD (empty)
+    let mut reply = VC.to_vec();
+    reply.extend(util::u32_to_be_bytes(select).iter());
+    reply.extend(util::u16_to_be_bytes(0).iter());
+    encrypt.process(&mut reply);
+    try!(stream.write_all(&reply));
+
+    let rc4 = select == CRYPTO_RC4;
+    Ok((PeerStream {
+        inner: stream,
+        buffered: ia,
+        encrypt: if rc4 { Some(encrypt) } else { None },
+        decrypt: if rc4 { Some(decrypt) } else { None },
+    }, Some(skey)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{accept, connect, EncryptionPolicy, MseError};
+
+    use std::io::{self, Read, Write};
+    use std::sync::mpsc::{channel, Receiver, Sender};
+    use std::thread;
+
+    // One end of an in-memory connection
+    struct Pipe {
+        tx: Sender<Vec<u8>>,
+        rx: Receiver<Vec<u8>>,
+        pending: Vec<u8>,
+    }
+
+    fn pipe() -> (Pipe, Pipe) {
+        let (tx_a, rx_a) = channel();
+        let (tx_b, rx_b) = channel();
+        (Pipe { tx: tx_a, rx: rx_b, pending: Vec::new() },
+         Pipe { tx: tx_b, rx: rx_a, pending: Vec::new() })
+    }
+
+    impl Read for Pipe {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.pending.is_empty() {
+                match self.rx.recv() {
+                    Ok(data) => self.pending = data,
+                    // the other end was dropped
+                    Err(_) => return Ok(0),
+                }
+            }
+            let n = if buf.len() < self.pending.len() { buf.len() } else { self.pending.len() };
+            for (dst, src) in buf.iter_mut().zip(self.pending.drain(..n)) {
+                *dst = src;
+            }
+            Ok(n)
+        }
+    }
+
+    impl Write for Pipe {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            match self.tx.send(buf.to_vec()) {
+                Ok(()) => Ok(buf.len()),
+                Err(_) => Err(io::Error::new(io::ErrorKind::BrokenPipe, "pipe closed")),
+            }
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn info_hash() -> Vec<u8> {
+        vec![0x42; 20]
+    }
+
+    fn handshake() -> Vec<u8> {
+        let mut hs = b"\x13BitTorrent protocol".to_vec();
+        hs.extend([0; 8].iter());
+        hs.extend(info_hash().into_iter());
+        hs.extend(b"-NH0001-abcdefghijkl".iter());
+        hs
+    }
+
+    // Runs `accept` on the other end of a pipe, then reads the initiator's
+    // handshake and answers "hello". Gives back the handshake, whether the
+    // stream was encrypted and the info hash MSE found.
+    fn accept_in_thread(stream: Pipe, policy: EncryptionPolicy)
+            -> thread::JoinHandle<Result<(Vec<u8>, bool, Option<Vec<u8>>), MseError>> {
+        thread::spawn(move || {
+            let info_hashes = vec![vec![0x17; 20], info_hash()];
+            let (mut stream, skey) = try!(accept(stream, &info_hashes, policy));
+            let mut hs = vec![0; 68];
+            try!(stream.read_exact(&mut hs));
+            try!(stream.write_all(b"hello"));
+            Ok((hs, stream.is_encrypted(), skey))
+        })
+    }
+
+    #[test]
+    fn encrypted_handshake() {
+        let (a, b) = pipe();
+        let acceptor = accept_in_thread(b, EncryptionPolicy::RequireEncrypted);
+
+        let mut stream = connect(a, &info_hash(), EncryptionPolicy::PreferEncrypted,
+                                 &handshake()).unwrap();
+        assert!(stream.is_encrypted());
+        let mut reply = [0; 5];
+        stream.read_exact(&mut reply).unwrap();
+        assert_eq!(&reply, b"hello");
+
+        let (hs, encrypted, skey) = acceptor.join().unwrap().unwrap();
+        assert_eq!(hs, handshake());
+        assert!(encrypted);
+        assert_eq!(skey, Some(info_hash()));
+    }
+
+    #[test]
+    fn plaintext_handshake() {
+        let (a, b) = pipe();
+        let acceptor = accept_in_thread(b, EncryptionPolicy::PreferEncrypted);
+
+        let mut stream = connect(a, &info_hash(), EncryptionPolicy::PlaintextOnly,
+                                 &handshake()).unwrap();
+        assert!(!stream.is_encrypted());
+        let mut reply = [0; 5];
+        stream.read_exact(&mut reply).unwrap();
+        assert_eq!(&reply, b"hello");
+
+        let (hs, encrypted, skey) = acceptor.join().unwrap().unwrap();
+        assert_eq!(hs, handshake());
+        assert!(!encrypted);
+        assert_eq!(skey, None);
+    }
+
+    #[test]
+    fn policies_are_enforced() {
+        let (a, b) = pipe();
+        let acceptor = accept_in_thread(b, EncryptionPolicy::PlaintextOnly);
+        assert!(connect(a, &info_hash(), EncryptionPolicy::RequireEncrypted,
+                        &handshake()).is_err());
+        match acceptor.join().unwrap() {
+            Err(MseError::Refused(_)) => {},
+            other => panic!("expected Refused, got {:?}", other.map(|r| r.0)),
+        }
+
+        let (a, b) = pipe();
+        let acceptor = accept_in_thread(b, EncryptionPolicy::RequireEncrypted);
+        connect(a, &info_hash(), EncryptionPolicy::PlaintextOnly, &handshake()).unwrap();
+        match acceptor.join().unwrap() {
+            Err(MseError::Refused(_)) => {},
+            other => panic!("expected Refused, got {:?}", other.map(|r| r.0)),
+        }
+    }
+
+    #[test]
+    fn unknown_info_hash() {
+        let (a, b) = pipe();
+        let acceptor = accept_in_thread(b, EncryptionPolicy::PreferEncrypted);
+        assert!(connect(a, &[0x99; 20], EncryptionPolicy::RequireEncrypted,
+                        &handshake()).is_err());
+        match acceptor.join().unwrap() {
+            Err(MseError::Refused(_)) => {},
+            other => panic!("expected Refused, got {:?}", other.map(|r| r.0)),
+        }
+    }
+}
@@ -0,0 +1,484 @@
+use rand::{self, Rng};
+use std::fmt;
+
+// Azureus-style prefix for the peer ids we generate
+pub const PEER_ID_PREFIX: &'static str = "-NH0001-";
+
+// A new peer id for a session: our prefix followed by random characters
+pub fn generate() -> String {
+    let mut rng = rand::thread_rng();
+    let prefix_len = PEER_ID_PREFIX.len();
+    let s: String = rng.gen_ascii_chars().take(20 - prefix_len).collect();
+    format!("{}{}", PEER_ID_PREFIX, s)
+}
+
+// Name and version of the client that generated a peer id.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Client {
+    pub name: String,
+    pub version: Option<String>,
+}
+
+impl Client {
+    fn new(name: &str, version: Option<String>) -> Client {
+        Client {
+            name: String::from(name),
+            version: version,
+        }
+    }
+}
+
+impl fmt::Display for Client {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self.version {
+            Some(ref v) => write!(f, "{} {}", self.name, v),
+            None => write!(f, "{}", self.name),
+        }
+    }
+}
+
+// Two character client codes used in Azureus-style ids, e.g. `-AZ2060-`
+static AZUREUS_CLIENTS: &'static [(&'static str, &'static str)] = &[
+    ("7T", "aTorrent"),
+    ("AG", "Ares"),
+    ("A~", "Ares"),
+    ("AR", "Arctic"),
+    ("AT", "Artemis"),
+    ("AV", "Avicora"),
+    ("AX", "BitPump"),
+    ("AZ", "Azureus"),
+    ("BB", "BitBuddy"),
+    ("BC", "BitComet"),
+    ("BE", "Baretorrent"),
+    ("BF", "Bitflu"),
+    ("BG", "BTG"),
+    ("BI", "BiglyBT"),
+    ("BL", "BitBlinder"),
+    ("BP", "BitTorrent Pro"),
+    ("BR", "BitRocket"),
+    ("BS", "BTSlave"),
+    ("BT", "BitTorrent"),
+    ("BW", "BitWombat"),
+    ("BX", "Bittorrent X"),
+    ("CD", "Enhanced CTorrent"),
+    ("CT", "CTorrent"),
+    ("DE", "Deluge"),
+    ("DP", "Propagate Data Client"),
+    ("EB", "EBit"),
+    ("ES", "electric sheep"),
+    ("FC", "FileCroc"),
+    ("FD", "Free Download Manager"),
+    ("FT", "FoxTorrent"),
+    ("FW", "FrostWire"),
+    ("FX", "Freebox BitTorrent"),
+    ("GS", "GSTorrent"),
+    ("HK", "Hekate"),
+    ("HL", "Halite"),
+    ("HN", "Hydranode"),
+    ("KG", "KGet"),
+    ("KT", "KTorrent"),
+    ("LC", "LeechCraft"),
+    ("LH", "LH-ABC"),
+    ("LP", "Lphant"),
+    ("LT", "libtorrent"),
+    ("lt", "libTorrent"),
+    ("LW", "LimeWire"),
+    ("MK", "Meerkat"),
+    ("MO", "MonoTorrent"),
+    ("MP", "MooPolice"),
+    ("MR", "Miro"),
+    ("MT", "MoonlightTorrent"),
+    ("NH", "deluge-rs"),
+    ("NX", "Net Transport"),
+    ("OS", "OneSwarm"),
+    ("OT", "OmegaTorrent"),
+    ("PD", "Pando"),
+    ("PI", "PicoTorrent"),
+    ("qB", "qBittorrent"),
+    ("QD", "QQDownload"),
+    ("QT", "Qt 4 Torrent example"),
+    ("RT", "Retriever"),
+    ("RZ", "RezTorrent"),
+    ("SB", "Swiftbit"),
+    ("SD", "Thunder"),
+    ("SM", "SoMud"),
+    ("SP", "BitSpirit"),
+    ("SS", "SwarmScope"),
+    ("ST", "SymTorrent"),
+    ("st", "sharktorrent"),
+    ("SZ", "Shareaza"),
+    ("TB", "Torch"),
+    ("TE", "terasaur Seed Bank"),
+    ("TL", "Tribler"),
+    ("TN", "TorrentDotNET"),
+    ("TR", "Transmission"),
+    ("TS", "Torrentstorm"),
+    ("TT", "TuoTu"),
+    ("UL", "uLeecher!"),
+    ("UM", "uTorrent for Mac"),
+    ("UT", "uTorrent"),
+    ("UW", "uTorrent Web"),
+    ("VG", "Vagaa"),
+    ("WD", "WebTorrent Desktop"),
+    ("WT", "BitLet"),
+    ("WW", "WebTorrent"),
+    ("WY", "FireTorrent"),
+    ("XF", "Xfplay"),
+    ("XL", "Xunlei"),
+    ("XS", "XSwifter"),
+    ("XT", "XanTorrent"),
+    ("XX", "Xtorrent"),
+    ("ZT", "ZipTorrent"),
+];
+
+// Single character client codes used in Shadow-style ids, e.g. `S58B-----`
+static SHADOW_CLIENTS: &'static [(u8, &'static str)] = &[
+    (b'A', "ABC"),
+    (b'O', "Osprey Permaseed"),
+    (b'Q', "BTQueue"),
+    (b'R', "Tribler"),
+    (b'S', "Shadow's client"),
+    (b'T', "BitTornado"),
+    (b'U', "UPnP NAT Bit Torrent"),
+];
+
+// Attempts to identify the client that generated `peer_id`. Returns `None`
+// if the id doesn't match any encoding we know about.
+pub fn parse(peer_id: &[u8]) -> Option<Client> {
+    if peer_id.len() != 20 {
+        return None;
+    }
+
+    parse_one_off(peer_id)
+        .or_else(|| parse_azureus(peer_id))
+        .or_else(|| parse_mainline(peer_id))
+        .or_else(|| parse_shadow(peer_id))
+}
+
+// Same as `parse`, but gives something printable for unrecognised ids.
+pub fn describe(peer_id: &[u8]) -> String {
+    match parse(peer_id) {
+        Some(client) => client.to_string(),
+        None => format!("unknown client ({})", escape(peer_id)),
+    }
+}
+
+// Peer ids are arbitrary bytes, so show the printable prefix and escape the rest
+fn escape(peer_id: &[u8]) -> String {
+    let mut s = String::new();
+    for &b in peer_id.iter() {
+        if b >= 0x20 && b < 0x7f {
+            s.push(b as char);
+        } else {
+            s.push_str(&format!("\\x{:02x}", b));
+        }
+    }
+    s
+}
+
+// '-' + two character client id + four version characters + '-'
+fn parse_azureus(id: &[u8]) -> Option<Client> {
+    if id[0] != b'-' || id[7] != b'-' {
+        return None;
+    }
+
+    let code = match String::from_utf8(id[1..3].to_vec()) {
+        Ok(code) => code,
+        Err(_) => return None,
+    };
+
+    let name = match AZUREUS_CLIENTS.iter().find(|&&(c, _)| c == code) {
+        Some(&(_, name)) => name,
+        None => return None,
+    };
+
+    let v = &id[3..7];
+    let version = match &code[..] {
+        // Transmission 0.x used "-TR0072-", later releases "-TR2840-" for 2.84
+        "TR" => {
+            if v[0] == b'0' {
+                Some(format!("0.{}", ascii_str(&v[2..4])))
+            } else {
+                Some(format!("{}.{}", v[0] as char, ascii_str(&v[1..3])))
+            }
+        },
+        // uTorrent uses the final character for the release type
+        "UT" | "UM" | "UW" => {
+            let mut s = join_version(&v[..3]);
+            match v[3] {
+                b'B' => s.push_str(" beta"),
+                b'A' => s.push_str(" alpha"),
+                _ => {},
+            }
+            Some(s)
+        },
+        _ => {
+            if v[3] == b'0' {
+                Some(join_version(&v[..3]))
+            } else {
+                Some(join_version(v))
+            }
+        },
+    };
+
+    Some(Client::new(name, version))
+}
+
+// A single character client id, up to five version characters, then dashes
+fn parse_shadow(id: &[u8]) -> Option<Client> {
+    let name = match SHADOW_CLIENTS.iter().find(|&&(c, _)| c == id[0]) {
+        Some(&(_, name)) => name,
+        None => return None,
+    };
+
+    if &id[6..9] != b"---" {
+        return None;
+    }
+
+    let mut parts = Vec::new();
+    for &b in id[1..6].iter() {
+        if b == b'-' {
+            break;
+        }
+        match shadow_digit(b) {
+            Some(d) => parts.push(d.to_string()),
+            None => return None,
+        }
+    }
+
+    if parts.is_empty() {
+        return None;
+    }
+
+    Some(Client::new(name, Some(parts.connect("."))))
+}
+
+// Mainline and Queen Bee: 'M4-3-6--' or 'Q1-10-0-'
+fn parse_mainline(id: &[u8]) -> Option<Client> {
+    let name = match id[0] {
+        b'M' => "Mainline",
+        b'Q' => "Queen Bee",
+        _ => return None,
+    };
+
+    let mut parts = Vec::new();
+    let mut current = String::new();
+    for &b in id[1..].iter() {
+        match b {
+            b'0'...b'9' => current.push(b as char),
+            b'-' if !current.is_empty() => {
+                parts.push(current);
+                current = String::new();
+                if parts.len() == 3 {
+                    break;
+                }
+            },
+            _ => return None,
+        }
+    }
+
+    if parts.len() != 3 {
+        return None;
+    }
+
+    Some(Client::new(name, Some(parts.connect("."))))
+}
+
+fn parse_one_off(id: &[u8]) -> Option<Client> {
+    if id.starts_with(b"exbc") {
+        let version = format!("{}.{:02}", id[4], id[5]);
+        let name = if &id[6..10] == b"LORD" { "BitLord" } else { "BitComet" };
+        return Some(Client::new(name, Some(version)));
+    }
+
+    if id.starts_with(b"FUTB") {
+        return Some(Client::new("BitComet (Solidox)", None));
+    }
+
+    if id.starts_with(b"AZ2500BT") {
+        return Some(Client::new("BitTyrant", None));
+    }
+
+    if id.starts_with(b"XBT") && is_digits(&id[3..6]) {
+        let mut version = join_version(&id[3..6]);
+        if id[6] == b'd' {
+            version.push_str(" debug");
+        }
+        return Some(Client::new("XBT Client", Some(version)));
+    }
+
+    if id.starts_with(b"OP") && is_digits(&id[2..6]) {
+        return Some(Client::new("Opera", Some(format!("build {}", ascii_str(&id[2..6])))));
+    }
+
+    if id.starts_with(b"-ML") {
+        let version: Vec<u8> = id[3..].iter()
+                                      .take_while(|&&b| b != b'-')
+                                      .cloned()
+                                      .collect();
+        return Some(Client::new("MLDonkey", Some(ascii_str(&version))));
+    }
+
+    if id.starts_with(b"-BOW") {
+        return Some(Client::new("Bits on Wheels", Some(ascii_str(&id[4..7]))));
+    }
+
+    if id.starts_with(b"-G3") {
+        return Some(Client::new("G3 Torrent", None));
+    }
+
+    if id.starts_with(b"-FG") && is_digits(&id[3..7]) {
+        return Some(Client::new("FlashGet", Some(join_version(&id[3..7]))));
+    }
+
+    if id.starts_with(b"-Qt-") {
+        return Some(Client::new("Qt 4 Torrent example", None));
+    }
+
+    if id.starts_with(b"Plus") {
+        return Some(Client::new("Plus!", Some(join_version(&id[4..7]))));
+    }
+
+    if id.starts_with(b"btpd") {
+        return Some(Client::new("BT Protocol Daemon", None));
+    }
+
+    if id.starts_with(b"BitLet") {
+        return Some(Client::new("BitLet", None));
+    }
+
+    if id.starts_with(b"LIME") {
+        return Some(Client::new("Limewire", None));
+    }
+
+    if id.starts_with(b"eX") {
+        return Some(Client::new("eXeem", None));
+    }
+
+    if id.starts_with(b"346-") {
+        return Some(Client::new("TorrentTopia", None));
+    }
+
+    if id.starts_with(b"Deadman Walking-") {
+        return Some(Client::new("Deadman", None));
+    }
+
+    if id.starts_with(b"a00---0") || id.starts_with(b"a02---0") {
+        return Some(Client::new("Swarmy", None));
+    }
+
+    if id.starts_with(b"T00---0") {
+        return Some(Client::new("Teeweety", None));
+    }
+
+    if id.starts_with(b"-SP") && is_digits(&id[3..6]) {
+        return Some(Client::new("BitSpirit", Some(join_version(&id[3..6]))));
+    }
+
+    if &id[2..4] == b"BS" {
+        // BitSpirit v2 puts the version in the first byte
+        let version = if id[1] == 0 { "1".to_string() } else { id[1].to_string() };
+        return Some(Client::new("BitSpirit", Some(version)));
+    }
+
+    if id.iter().take(12).all(|&b| b == 0) {
+        if &id[12..16] == b"\x97\x97\x97\x97" {
+            return Some(Client::new("Experimental", Some(String::from("3.2.1b2"))));
+        }
+        if id[12..].iter().all(|&b| b == 0) {
+            return Some(Client::new("Experimental", Some(String::from("3.1"))));
+        }
+        return Some(Client::new("Generic", None));
+    }
+
+    None
+}
+
+// Shadow-style version characters: 0-9, A-Z for 10-35, a-z for 36-61,
+// '.' for 62.
+fn shadow_digit(b: u8) -> Option<u8> {
+    match b {
+        b'0'...b'9' => Some(b - b'0'),
+        b'A'...b'Z' => Some(b - b'A' + 10),
+        b'a'...b'z' => Some(b - b'a' + 36),
+        b'.' => Some(62),
+        _ => None,
+    }
+}
+
+fn join_version(v: &[u8]) -> String {
+    let parts: Vec<String> = v.iter()
+                              .map(|&b| match shadow_digit(b) {
+                                  Some(d) => d.to_string(),
+                                  None => (b as char).to_string(),
+                              })
+                              .collect();
+    parts.connect(".")
+}
+
+fn is_digits(v: &[u8]) -> bool {
+    v.iter().all(|&b| b >= b'0' && b <= b'9')
+}
+
+fn ascii_str(v: &[u8]) -> String {
+    v.iter().map(|&b| b as char).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{describe, generate, parse, Client, PEER_ID_PREFIX};
+
+    fn client(name: &str, version: Option<&str>) -> Option<Client> {
+        Some(Client::new(name, version.map(String::from)))
+    }
+
+    #[test]
+    fn generated_ids_are_ours() {
+        let id = generate();
+        assert_eq!(id.len(), 20);
+        assert!(id.starts_with(PEER_ID_PREFIX));
+        assert_eq!(parse(id.as_bytes()), client("deluge-rs", Some("0.0.0.1")));
+    }
+
+    #[test]
+    fn azureus_style() {
+        assert_eq!(parse(b"-AZ2060-abcdefghijkl"), client("Azureus", Some("2.0.6")));
+        assert_eq!(parse(b"-qB4250-abcdefghijkl"), client("qBittorrent", Some("4.2.5")));
+        assert_eq!(parse(b"-TR2840-abcdefghijkl"), client("Transmission", Some("2.84")));
+        assert_eq!(parse(b"-TR0072-abcdefghijkl"), client("Transmission", Some("0.72")));
+        assert_eq!(parse(b"-UT355B-abcdefghijkl"), client("uTorrent", Some("3.5.5 beta")));
+        assert_eq!(parse(b"-ZZ1000-abcdefghijkl"), None);
+    }
+
+    #[test]
+    fn shadow_style() {
+        assert_eq!(parse(b"S58B-----abcdefghijk"), client("Shadow's client", Some("5.8.11")));
+        assert_eq!(parse(b"T03I--00abcdefghijkl"), None);
+        assert_eq!(parse(b"T03I-----abcdefghijk"), client("BitTornado", Some("0.3.18")));
+    }
+
+    #[test]
+    fn mainline_style() {
+        assert_eq!(parse(b"M4-3-6--abcdefghijkl"), client("Mainline", Some("4.3.6")));
+        assert_eq!(parse(b"M7-10-2-abcdefghijkl"), client("Mainline", Some("7.10.2")));
+        assert_eq!(parse(b"Q1-10-0-abcdefghijkl"), client("Queen Bee", Some("1.10.0")));
+        assert_eq!(parse(b"M4-3x6--abcdefghijkl"), None);
+    }
+
+    #[test]
+    fn one_offs() {
+        assert_eq!(parse(b"exbc\x00\x38LORDabcdefghij"), client("BitLord", Some("0.56")));
+        assert_eq!(parse(b"exbc\x00\x38abcdefghijklmn"), client("BitComet", Some("0.56")));
+        assert_eq!(parse(b"XBT054d-abcdefghijkl"), client("XBT Client", Some("0.5.4 debug")));
+        assert_eq!(parse(b"OP7685abcdefghijklmn"), client("Opera", Some("build 7685")));
+        assert_eq!(parse(&[0; 20]), client("Experimental", Some("3.1")));
+    }
+
+    #[test]
+    fn unknown_ids() {
+        assert_eq!(parse(b"-AZ2060-"), None);
+        assert_eq!(parse(b"\x01\x02unknown-client-id"), None);
+        assert_eq!(describe(b"\x01\x02unknown-client-id"),
+                   "unknown client (\\x01\\x02unknown-client-id)");
+    }
+}
@@ -0,0 +1,294 @@
+// A torrent's peer list: every peer we've heard of, from any source, and
+// how connecting to it has gone, so that the most promising ones are tried
+// first and ones that never answer stop taking up connection slots.
+
+use metainfo::MetaInfo;
+use tracker::Peer;
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::u32;
+use time;
+
+// peers kept per torrent; past this, new ones are ignored until some are
+// dropped
+pub const MAX_PEERS: usize = 1000;
+
+// failed attempts in a row after which a peer is dropped. It's added again
+// if a source mentions it later.
+pub const MAX_FAILURES: u32 = 3;
+
+// seconds before a peer is tried again, doubled for each failure in a row
+const RETRY_SECS: i64 = 60;
+
+// Where we heard about a peer
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PeerSource {
+    Tracker,
+    Pex,
+    Lsd,
+}
+
+impl PeerSource {
+    // BEP 27: a private torrent's peers may only come from its trackers
+    pub fn allowed_for(&self, info: &MetaInfo) -> bool {
+        *self == PeerSource::Tracker || !info.info.is_private()
+    }
+
+    // How likely a peer from here is to be there: ones on our own network
+    // most, ones passed on by other peers least
+    fn rank(&self) -> u8 {
+        match *self {
+            PeerSource::Lsd => 2,
+            PeerSource::Tracker => 1,
+            PeerSource::Pex => 0,
+        }
+    }
+}
+
+// What we know about one peer
+#[derive(Debug, Clone)]
+pub struct PeerInfo {
+    pub peer: Peer,
+
+    // every source that's told us about it
+    pub sources: Vec<PeerSource>,
+
+    // failed connection attempts since the last one that worked
+    pub failures: u32,
+
+    // when a source last mentioned it, or we were last connected to it,
+    // in seconds since the epoch
+    pub last_seen: i64,
+
+    // we've got as far as a handshake with it before
+    pub reachable: bool,
+
+    pub connected: bool,
+
+    // when we last tried connecting to it, if we have
+    last_attempt: Option<i64>,
+}
+
+impl PeerInfo {
+    // when it may be tried again
+    fn retry_at(&self) -> i64 {
+        match self.last_attempt {
+            Some(t) => t + (RETRY_SECS << (if self.failures < 6 { self.failures } else { 6 })),
+            None => 0,
+        }
+    }
+
+    // higher is better: peers that have worked before, then ones that
+    // have failed least, then by source, then the most recently seen
+    fn rank(&self) -> (bool, u32, u8, i64) {
+        let source = self.sources.iter().map(|s| s.rank()).max().unwrap_or(0);
+        (self.reachable, u32::MAX - self.failures, source, self.last_seen)
+    }
+}
+
+pub struct PeerList {
+    peers: HashMap<SocketAddr, PeerInfo>,
+}
+
+impl PeerList {
+    pub fn new() -> PeerList {
+        PeerList { peers: HashMap::new() }
+    }
+
+    // Adds peers a source has told us about, or notes that it's seen ones
+    // we already know again
+    pub fn add(&mut self, source: PeerSource, peers: Vec<Peer>) {
+        let now = time::get_time().sec;
+        for peer in peers.into_iter() {
+            if let Some(info) = self.peers.get_mut(&peer.addr) {
+                if !info.sources.contains(&source) {
+                    info.sources.push(source);
+                }
+                info.last_seen = now;
+                continue;
+            }
+            if self.peers.len() >= MAX_PEERS {
+                continue;
+            }
+            self.peers.insert(peer.addr, PeerInfo {
+                peer: peer,
+                sources: vec![source],
+                failures: 0,
+                last_seen: now,
+                reachable: false,
+                connected: false,
+                last_attempt: None,
+            });
+        }
+    }
+
+    // The best peer to connect to now, if there's one we aren't connected
+    // to and aren't waiting to retry
+    pub fn best(&self) -> Option<Peer> {
+        let now = time::get_time().sec;
+        let mut best: Option<&PeerInfo> = None;
+        for info in self.peers.values() {
+            if info.connected || info.retry_at() > now {
+                continue;
+            }
+            if best.map_or(true, |b| info.rank() > b.rank()) {
+                best = Some(info);
+            }
+        }
+        best.map(|info| info.peer.clone())
+    }
+
+    pub fn connecting(&mut self, addr: &SocketAddr) {
+        if let Some(info) = self.peers.get_mut(addr) {
+            info.connected = true;
+            info.last_attempt = Some(time::get_time().sec);
+        }
+    }
+
+    // A connection we made to `addr` has ended. `handshaken` says whether
+    // it got as far as a handshake; if not, it counts as a failure.
+    pub fn closed(&mut self, addr: &SocketAddr, handshaken: bool) {
+        let drop = match self.peers.get_mut(addr) {
+            Some(info) => {
+                info.connected = false;
+                if handshaken {
+                    info.failures = 0;
+                    info.reachable = true;
+                    info.last_seen = time::get_time().sec;
+                } else {
+                    info.failures += 1;
+                }
+                info.failures >= MAX_FAILURES
+            },
+            None => false,
+        };
+        if drop {
+            self.peers.remove(addr);
+        }
+    }
+
+    pub fn peers(&self) -> Vec<PeerInfo> {
+        self.peers.values().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{PeerList, PeerSource, MAX_FAILURES, MAX_PEERS};
+    use tracker::Peer;
+
+    use std::net::SocketAddr;
+    use time;
+
+    fn addr(port: u16) -> SocketAddr {
+        format!("10.0.0.1:{}", port).parse().unwrap()
+    }
+
+    fn peers(ports: &[u16]) -> Vec<Peer> {
+        ports.iter().map(|&p| Peer::from_socketaddr(addr(p))).collect()
+    }
+
+    fn best_port(list: &PeerList) -> Option<u16> {
+        list.best().map(|p| p.addr.port())
+    }
+
+    // pretend our last attempt at `port` was `secs` seconds ago
+    fn attempted_ago(list: &mut PeerList, port: u16, secs: i64) {
+        let info = list.peers.get_mut(&addr(port)).unwrap();
+        info.last_attempt = Some(time::get_time().sec - secs);
+    }
+
+    #[test]
+    fn ranked_by_source() {
+        let mut list = PeerList::new();
+        list.add(PeerSource::Pex, peers(&[1]));
+        list.add(PeerSource::Tracker, peers(&[2]));
+        list.add(PeerSource::Lsd, peers(&[3]));
+
+        assert_eq!(best_port(&list), Some(3));
+        list.connecting(&addr(3));
+        assert_eq!(best_port(&list), Some(2));
+        list.connecting(&addr(2));
+        assert_eq!(best_port(&list), Some(1));
+        list.connecting(&addr(1));
+        assert_eq!(best_port(&list), None);
+    }
+
+    #[test]
+    fn sources_are_merged() {
+        let mut list = PeerList::new();
+        list.add(PeerSource::Pex, peers(&[1, 2]));
+        list.add(PeerSource::Lsd, peers(&[2]));
+        list.add(PeerSource::Pex, peers(&[2]));
+
+        assert_eq!(list.peers().len(), 2);
+        let info = list.peers.get(&addr(2)).unwrap();
+        assert_eq!(info.sources, vec![PeerSource::Pex, PeerSource::Lsd]);
+        assert_eq!(best_port(&list), Some(2));
+    }
+
+    #[test]
+    fn reachable_and_reliable_peers_first() {
+        let mut list = PeerList::new();
+        list.add(PeerSource::Pex, peers(&[1]));
+        list.add(PeerSource::Lsd, peers(&[2, 3]));
+
+        // a handshake makes up for a worse source
+        list.connecting(&addr(1));
+        list.closed(&addr(1), true);
+        attempted_ago(&mut list, 1, 1000);
+        assert_eq!(best_port(&list), Some(1));
+
+        // a failure counts against a peer even once it may be retried
+        list.connecting(&addr(1));
+        list.connecting(&addr(2));
+        list.closed(&addr(2), false);
+        attempted_ago(&mut list, 2, 1000);
+        assert_eq!(best_port(&list), Some(3));
+    }
+
+    #[test]
+    fn failures_back_off() {
+        let mut list = PeerList::new();
+        list.add(PeerSource::Tracker, peers(&[1]));
+
+        // waits a minute after a clean close, two after one failure
+        list.connecting(&addr(1));
+        list.closed(&addr(1), true);
+        assert_eq!(best_port(&list), None);
+        attempted_ago(&mut list, 1, 70);
+        assert_eq!(best_port(&list), Some(1));
+
+        list.connecting(&addr(1));
+        list.closed(&addr(1), false);
+        attempted_ago(&mut list, 1, 70);
+        assert_eq!(best_port(&list), None);
+        attempted_ago(&mut list, 1, 130);
+        assert_eq!(best_port(&list), Some(1));
+    }
+
+    #[test]
+    fn dropped_after_too_many_failures() {
+        let mut list = PeerList::new();
+        list.add(PeerSource::Tracker, peers(&[1]));
+        for _ in 0..MAX_FAILURES {
+            assert_eq!(list.peers().len(), 1);
+            list.connecting(&addr(1));
+            list.closed(&addr(1), false);
+        }
+        assert!(list.peers().is_empty());
+
+        // until a source mentions it again
+        list.add(PeerSource::Tracker, peers(&[1]));
+        assert_eq!(list.peers()[0].failures, 0);
+    }
+
+    #[test]
+    fn limited_size() {
+        let mut list = PeerList::new();
+        let ports: Vec<u16> = (0..MAX_PEERS as u16 + 10).collect();
+        list.add(PeerSource::Tracker, peers(&ports));
+        assert_eq!(list.peers().len(), MAX_PEERS);
+    }
+}
@@ -0,0 +1,196 @@
+// Peer exchange (ut_pex, BEP 11).
+
+use extension::{ExtendedHandshake, ExtensionHandler};
+use tracker::{self, Peer};
+use util;
+
+use bencode::{self, Bencode};
+use std::collections::{HashMap, HashSet};
+use std::net;
+use std::sync::{Arc, Mutex};
+use time;
+
+pub const NAME: &'static str = "ut_pex";
+
+// BEP 11 asks for no more than one message a minute...
+pub const INTERVAL_SECS: i64 = 60;
+
+// ...with at most this many added and dropped peers in each
+pub const MAX_PEERS_PER_MESSAGE: usize = 50;
+
+// flags sent in `added.f`
+pub const FLAG_PREFERS_ENCRYPTION: u8 = 0x01;
+pub const FLAG_SEED: u8 = 0x02;
+pub const FLAG_SUPPORTS_UTP: u8 = 0x04;
+pub const FLAG_SUPPORTS_HOLEPUNCH: u8 = 0x08;
+pub const FLAG_REACHABLE: u8 = 0x10;
+
+// Peers the download is currently connected to, with their PEX flags.
+// Shared between every connection's PEX handler.
+pub type ConnectedPeers = Arc<Mutex<HashMap<net::SocketAddr, u8>>>;
+
+pub struct PeerExchange {
+    // address of the peer this handler talks to; never sent back to it
+    peer_addr: net::SocketAddr,
+
+    connected: ConnectedPeers,
+
+    // the set of peers this peer has heard about from us
+    sent: HashSet<net::SocketAddr>,
+
+    // when we last sent a message, in seconds since the epoch
+    last_sent: Option<i64>,
+
+    // whether the peer supports ut_pex
+    enabled: bool,
+
+    // called with peers the remote peer tells us about
+    on_discovered: Box<Fn(Vec<Peer>) + Send>,
+}
+
+impl PeerExchange {
+    pub fn new(peer_addr: net::SocketAddr, connected: ConnectedPeers,
+               on_discovered: Box<Fn(Vec<Peer>) + Send>) -> PeerExchange {
+        PeerExchange {
+            peer_addr: peer_addr,
+            connected: connected,
+            sent: HashSet::new(),
+            last_sent: None,
+            enabled: false,
+            on_discovered: on_discovered,
+        }
+    }
+
+    // Builds the next added/dropped delta, or `None` if nothing changed
+    fn delta(&mut self) -> Option<Vec<u8>> {
+        let connected = self.connected.lock().unwrap().clone();
+
+        let mut added = Vec::new();
+        for (addr, &flags) in connected.iter() {
+            if *addr != self.peer_addr && !self.sent.contains(addr) {
+                added.push((*addr, flags));
+            }
+            if added.len() == MAX_PEERS_PER_MESSAGE {
+                break;
+            }
+        }
+
+        let dropped: Vec<net::SocketAddr> = self.sent.iter()
+                                                    .filter(|a| !connected.contains_key(a))
+                                                    .take(MAX_PEERS_PER_MESSAGE)
+                                                    .cloned()
+                                                    .collect();
+
+        if added.is_empty() && dropped.is_empty() {
+            return None;
+        }
+
+        for &(addr, _) in added.iter() {
+            self.sent.insert(addr);
+        }
+        for addr in dropped.iter() {
+            self.sent.remove(addr);
+        }
+
+        Some(encode(&added, &dropped))
+    }
+}
+
+fn encode(added: &[(net::SocketAddr, u8)], dropped: &[net::SocketAddr]) -> Vec<u8> {
+    let mut added4 = Vec::new();
+    let mut added4_f = Vec::new();
+    let mut added6 = Vec::new();
+    let mut added6_f = Vec::new();
+    for &(addr, flags) in added.iter() {
+        match addr.ip() {
+            net::IpAddr::V4(_) => {
+                added4.extend(tracker::compact_peer(&addr).into_iter());
+                added4_f.push(flags);
+            },
+            net::IpAddr::V6(_) => {
+                added6.extend(tracker::compact_peer(&addr).into_iter());
+                added6_f.push(flags);
+            },
+        }
+    }
+
+    let mut dropped4 = Vec::new();
+    let mut dropped6 = Vec::new();
+    for addr in dropped.iter() {
+        match addr.ip() {
+            net::IpAddr::V4(_) => dropped4.extend(tracker::compact_peer(addr).into_iter()),
+            net::IpAddr::V6(_) => dropped6.extend(tracker::compact_peer(addr).into_iter()),
+        }
+    }
+
+    util::bencode_dict(vec![
+        ("added", Bencode::ByteString(added4)),
+        ("added.f", Bencode::ByteString(added4_f)),
+        ("added6", Bencode::ByteString(added6)),
+        ("added6.f", Bencode::ByteString(added6_f)),
+        ("dropped", Bencode::ByteString(dropped4)),
+        ("dropped6", Bencode::ByteString(dropped6)),
+    ]).to_bytes().unwrap()
+}
+
+impl ExtensionHandler for PeerExchange {
+    fn name(&self) -> &'static str {
+        NAME
+    }
+
+    fn on_handshake(&mut self, _hs: &ExtendedHandshake) {
+        self.enabled = true;
+    }
+
+    fn on_message(&mut self, payload: &[u8]) -> Result<Vec<Vec<u8>>, String> {
+        let map = match bencode::from_buffer(payload) {
+            Ok(Bencode::Dict(map)) => map,
+            Ok(_) => return Err(String::from("ut_pex message is not a dictionary")),
+            Err(e) => return Err(format!("Error decoding ut_pex message: {:?}", e)),
+        };
+
+        // anything past the per-message cap is dropped rather than trusted
+        let mut peers = Vec::new();
+        if let Some(Bencode::ByteString(v)) = util::maybe_get_field(&map, "added") {
+            peers.extend(tracker::parse_compact_peers(&v).into_iter()
+                                                         .take(MAX_PEERS_PER_MESSAGE));
+        }
+        if let Some(Bencode::ByteString(v)) = util::maybe_get_field(&map, "added6") {
+            peers.extend(tracker::parse_compact_peers6(&v).into_iter()
+                                                          .take(MAX_PEERS_PER_MESSAGE));
+        }
+
+        // a peer dropping someone doesn't mean we can't connect to them, so
+        // `dropped` is ignored
+        let peers: Vec<Peer> = peers.into_iter()
+                                    .filter(|a| *a != self.peer_addr)
+                                    .map(Peer::from_socketaddr)
+                                    .collect();
+        if !peers.is_empty() {
+            log!("ut_pex: {} sent us {} peers", self.peer_addr, peers.len());
+            (self.on_discovered)(peers);
+        }
+
+        Ok(Vec::new())
+    }
+
+    fn tick(&mut self) -> Vec<Vec<u8>> {
+        if !self.enabled {
+            return Vec::new();
+        }
+
+        let now = time::get_time().sec;
+        match self.last_sent {
+            Some(t) if now - t < INTERVAL_SECS => return Vec::new(),
+            _ => {},
+        }
+
+        match self.delta() {
+            Some(msg) => {
+                self.last_sent = Some(now);
+                vec![msg]
+            },
+            None => Vec::new(),
+        }
+    }
+}
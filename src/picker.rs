@@ -0,0 +1,210 @@
+use bitfield::Bitfield;
+use metainfo::MetaInfo;
+
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum FilePriority {
+    // don't download the file at all
+    Skip,
+    Low,
+    Normal,
+    High,
+}
+
+impl FilePriority {
+    // the piece priority the file gives its pieces; 0 means don't download
+    pub fn level(&self) -> u8 {
+        match *self {
+            FilePriority::Skip => 0,
+            FilePriority::Low => 1,
+            FilePriority::Normal => 2,
+            FilePriority::High => 3,
+        }
+    }
+
+    pub fn from_str(s: &str) -> Option<FilePriority> {
+        match s {
+            "skip" => Some(FilePriority::Skip),
+            "low" => Some(FilePriority::Low),
+            "normal" => Some(FilePriority::Normal),
+            "high" => Some(FilePriority::High),
+            _ => None,
+        }
+    }
+}
+
+// A piece gets the highest priority of the files it covers, so a piece
+// shared with a skipped file is still downloaded for its other file.
+pub fn piece_priorities(info: &MetaInfo, files: &[FilePriority]) -> Vec<u8> {
+    (0..info.num_pieces()).map(|index| {
+        info.map_piece(index)
+            .iter()
+            .map(|slice| files.get(slice.file).map_or(FilePriority::Normal.level(),
+                                                      |p| p.level()))
+            .max()
+            .unwrap_or(0)
+    }).collect()
+}
+
+// Decides which piece to download next from a given peer.
+pub struct Picker {
+    // pieces we have verified
+    have: Bitfield,
+
+    // pieces currently assigned to some peer connection
+    in_progress: Bitfield,
+
+    // number of connected peers that have each piece
+    availability: Vec<u32>,
+
+    // from `FilePriority::level`: higher is picked first, 0 never
+    priority: Vec<u8>,
+
+    // pick in piece order rather than rarest first
+    sequential: bool,
+
+    // pieces a reader is waiting for, with when they're needed by (in
+    // `time::precise_time_ns` nanoseconds). They're picked before anything
+    // else, soonest first, whatever their priority.
+    deadlines: HashMap<u32, u64>,
+}
+
+impl Picker {
+    pub fn new(num_pieces: u32) -> Picker {
+        Picker {
+            have: Bitfield::new(num_pieces),
+            in_progress: Bitfield::new(num_pieces),
+            availability: vec![0; num_pieces as usize],
+            priority: vec![FilePriority::Normal.level(); num_pieces as usize],
+            sequential: false,
+            deadlines: HashMap::new(),
+        }
+    }
+
+    pub fn set_priorities(&mut self, priority: Vec<u8>) {
+        self.priority = priority;
+    }
+
+    pub fn set_sequential(&mut self, sequential: bool) {
+        self.sequential = sequential;
+    }
+
+    // Asks for a piece by `deadline`. A piece that already has an earlier
+    // deadline keeps it.
+    pub fn set_deadline(&mut self, index: u32, deadline: u64) {
+        if self.have.has(index) {
+            return;
+        }
+        let d = self.deadlines.entry(index).or_insert(deadline);
+        if deadline < *d {
+            *d = deadline;
+        }
+    }
+
+    pub fn have(&self) -> &Bitfield {
+        &self.have
+    }
+
+    pub fn is_complete(&self) -> bool {
+        self.have.is_complete()
+    }
+
+    // whether we have every piece we want, which with skipped files may
+    // be less than all of them
+    pub fn is_finished(&self) -> bool {
+        (0..self.have.len()).all(|i| !self.wants(i))
+    }
+
+    fn wants(&self, index: u32) -> bool {
+        !self.have.has(index)
+            && (self.priority[index as usize] > 0 || self.deadlines.contains_key(&index))
+    }
+
+    pub fn peer_has(&mut self, index: u32) {
+        self.availability[index as usize] += 1;
+    }
+
+    pub fn peer_has_bitfield(&mut self, bf: &Bitfield) {
+        for i in bf.iter_set() {
+            self.availability[i as usize] += 1;
+        }
+    }
+
+    // a peer with bitfield `bf` disconnected
+    pub fn peer_gone(&mut self, bf: &Bitfield) {
+        for i in bf.iter_set() {
+            self.availability[i as usize] -= 1;
+        }
+    }
+
+    // Whether `peer` has anything we still want
+    pub fn wants_from(&self, peer: &Bitfield) -> bool {
+        peer.iter_set().any(|i| self.wants(i))
+    }
+
+    // Picks a piece the peer has that we neither have nor are downloading
+    // from someone else, and marks it in progress. If `allowed` is given,
+    // only pieces in it are considered (used while choked under the Fast
+    // extension). Pieces with a deadline come first, then suggested
+    // pieces, then the highest priority and, within that, the rarest (or
+    // the first, in sequential mode).
+    pub fn pick(&mut self, peer: &Bitfield, allowed: Option<&[u32]>,
+                suggested: &[u32]) -> Option<u32> {
+        let wanted = |picker: &Picker, i: u32| {
+            peer.has(i) && picker.wants(i) && !picker.in_progress.has(i)
+                && allowed.map_or(true, |a| a.contains(&i))
+        };
+
+        // (deadline, index), so that ties go to the earlier piece
+        let mut choice = self.deadlines.iter()
+                                       .filter(|&(&i, _)| wanted(self, i))
+                                       .map(|(&i, &d)| (d, i))
+                                       .min()
+                                       .map(|(_, i)| i);
+
+        if choice.is_none() {
+            choice = suggested.iter().cloned().find(|&i| wanted(self, i));
+        }
+
+        if choice.is_none() {
+            // (index, priority, availability)
+            let mut best: Option<(u32, u8, u32)> = None;
+            for i in peer.iter_set() {
+                if !wanted(self, i) {
+                    continue;
+                }
+                let prio = self.priority[i as usize];
+                let avail = self.availability[i as usize];
+                // pieces come in order, so sequential keeps the first
+                match best {
+                    Some((_, p, a)) if p > prio
+                                       || (p == prio && (self.sequential || a <= avail)) => {},
+                    _ => best = Some((i, prio, avail)),
+                }
+            }
+            choice = best.map(|(i, _, _)| i);
+        }
+
+        if let Some(i) = choice {
+            self.in_progress.set(i);
+        }
+        choice
+    }
+
+    // give up on a piece so another peer can pick it
+    pub fn abort(&mut self, index: u32) {
+        self.in_progress.unset(index);
+    }
+
+    pub fn complete(&mut self, index: u32) {
+        self.in_progress.unset(index);
+        self.have.set(index);
+        self.deadlines.remove(&index);
+    }
+
+    // replace what we have with the result of a recheck
+    pub fn reset_have(&mut self, have: Bitfield) {
+        self.have = have;
+    }
+}
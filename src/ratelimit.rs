@@ -0,0 +1,187 @@
+// Bandwidth limits, as token buckets. There are limits for the session,
+// for each torrent and for each peer, in each direction; traffic has to
+// get through all of the ones above it, so a peer can't go faster than its
+// torrent, or a torrent than the session.
+
+use std::io::{self, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
+use std::time::Duration;
+use time;
+
+struct Bucket {
+    // bytes we may send or receive now; negative when traffic has gone
+    // through that we haven't waited for yet
+    tokens: f64,
+
+    // when `tokens` was last topped up, in `time::precise_time_ns`
+    // nanoseconds
+    last: u64,
+}
+
+// One token bucket, filling at `rate` bytes a second and holding up to a
+// second's worth
+pub struct RateLimit {
+    // 0 for no limit. Shared with the buckets made by `same_rate`.
+    rate: Arc<AtomicUsize>,
+    bucket: Mutex<Bucket>,
+}
+
+impl RateLimit {
+    pub fn new(rate: usize) -> RateLimit {
+        RateLimit {
+            rate: Arc::new(AtomicUsize::new(rate)),
+            bucket: Mutex::new(Bucket {
+                tokens: 0.0,
+                last: time::precise_time_ns(),
+            }),
+        }
+    }
+
+    // A separate bucket that always has the same rate as this one
+    pub fn same_rate(&self) -> RateLimit {
+        RateLimit {
+            rate: self.rate.clone(),
+            bucket: Mutex::new(Bucket {
+                tokens: 0.0,
+                last: time::precise_time_ns(),
+            }),
+        }
+    }
+
+    pub fn rate(&self) -> usize {
+        self.rate.load(Ordering::SeqCst)
+    }
+
+    pub fn set_rate(&self, rate: usize) {
+        self.rate.store(rate, Ordering::SeqCst);
+    }
+
+    // Takes `n` bytes from the bucket, even if that leaves it in debt.
+    // Returns how long to wait, in nanoseconds, until it's out of debt.
+    fn take(&self, n: usize) -> u64 {
+        let rate = self.rate() as f64;
+        let mut bucket = self.bucket.lock().unwrap();
+        let now = time::precise_time_ns();
+        let elapsed = now.saturating_sub(bucket.last) as f64 / 1e9;
+        bucket.last = now;
+        if rate == 0.0 {
+            bucket.tokens = 0.0;
+            return 0;
+        }
+
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(rate) - n as f64;
+        if bucket.tokens >= 0.0 {
+            0
+        } else {
+            (-bucket.tokens / rate * 1e9) as u64
+        }
+    }
+}
+
+// A download and an upload limit
+#[derive(Clone)]
+pub struct RateLimits {
+    pub download: Arc<RateLimit>,
+    pub upload: Arc<RateLimit>,
+}
+
+impl RateLimits {
+    // rates in bytes a second, 0 for no limit
+    pub fn new(download: usize, upload: usize) -> RateLimits {
+        RateLimits {
+            download: Arc::new(RateLimit::new(download)),
+            upload: Arc::new(RateLimit::new(upload)),
+        }
+    }
+
+    pub fn set(&self, download: usize, upload: usize) {
+        self.download.set_rate(download);
+        self.upload.set_rate(upload);
+    }
+
+    // (download, upload)
+    pub fn get(&self) -> (usize, usize) {
+        (self.download.rate(), self.upload.rate())
+    }
+
+    // Limits for one peer, with their own buckets, that follow the rates
+    // set here. A torrent keeps one of these to hand out to its peers.
+    pub fn for_peer(&self) -> RateLimits {
+        RateLimits {
+            download: Arc::new(self.download.same_rate()),
+            upload: Arc::new(self.upload.same_rate()),
+        }
+    }
+}
+
+// The limits traffic in one direction goes through
+#[derive(Clone)]
+pub struct Throttle {
+    limits: Vec<Arc<RateLimit>>,
+}
+
+impl Throttle {
+    pub fn unlimited() -> Throttle {
+        Throttle { limits: Vec::new() }
+    }
+
+    // Counts `n` bytes against every limit, then waits for as long as the
+    // most overdrawn of them needs
+    pub fn pass(&self, n: usize) {
+        let wait = self.limits.iter().fold(0, |wait, limit| {
+            let w = limit.take(n);
+            if w > wait { w } else { wait }
+        });
+        if wait > 0 {
+            thread::sleep(Duration::new(wait / 1_000_000_000, (wait % 1_000_000_000) as u32));
+        }
+    }
+}
+
+// (download, upload) throttles going through each of `levels`, e.g. a
+// peer's limits, its torrent's and the session's
+pub fn throttles(levels: &[&RateLimits]) -> (Throttle, Throttle) {
+    (Throttle { limits: levels.iter().map(|l| l.download.clone()).collect() },
+     Throttle { limits: levels.iter().map(|l| l.upload.clone()).collect() })
+}
+
+// A stream whose reads and writes are held to the limits of its throttles.
+// Traffic is counted once it's happened, so a read that goes over the
+// limit makes the next one wait.
+pub struct Throttled<S> {
+    inner: S,
+    download: Throttle,
+    upload: Throttle,
+}
+
+impl<S> Throttled<S> {
+    pub fn new(inner: S, download: Throttle, upload: Throttle) -> Throttled<S> {
+        Throttled {
+            inner: inner,
+            download: download,
+            upload: upload,
+        }
+    }
+}
+
+impl<S: Read> Read for Throttled<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = try!(self.inner.read(buf));
+        self.download.pass(n);
+        Ok(n)
+    }
+}
+
+impl<S: Write> Write for Throttled<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = try!(self.inner.write(buf));
+        self.upload.pass(n);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
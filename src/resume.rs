@@ -0,0 +1,155 @@
+// Resume data: what we remember about a torrent between runs, so that it
+// can carry on without hashing everything again. It's kept with the
+// torrent's files, in .<name>.resume.
+
+use bitfield::Bitfield;
+use metainfo::MetaInfo;
+use storage;
+use util;
+
+use bencode::{self, Bencode};
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+pub struct ResumeData {
+    // where the files are
+    pub save_path: PathBuf,
+
+    // where each file is under `save_path`, after any renames
+    pub file_paths: Vec<PathBuf>,
+
+    // pieces that were verified, or None if the files no longer look the
+    // way they did and have to be rechecked
+    pub have: Option<Bitfield>,
+}
+
+fn resume_path(info: &MetaInfo, save_path: &Path) -> PathBuf {
+    save_path.join(format!(".{}.resume", info.info.name()))
+}
+
+// paths are kept as lists of components, like those in the torrent
+fn path_to_bencode(path: &Path) -> Bencode {
+    Bencode::List(path.iter()
+                      .map(|c| Bencode::ByteString(c.to_string_lossy().into_owned().into_bytes()))
+                      .collect())
+}
+
+fn path_from_bencode(b: &Bencode) -> Option<PathBuf> {
+    let components = match *b {
+        Bencode::List(ref components) => components,
+        _ => return None,
+    };
+    let mut path = PathBuf::new();
+    for c in components.iter() {
+        match *c {
+            Bencode::ByteString(ref c) => match String::from_utf8(c.clone()) {
+                Ok(c) => path.push(c),
+                Err(_) => return None,
+            },
+            _ => return None,
+        }
+    }
+    // a resume file that's been tampered with mustn't put files elsewhere
+    if storage::valid_path(&path) { Some(path) } else { None }
+}
+
+pub fn save(info: &MetaInfo, data: &ResumeData) -> Result<(), io::Error> {
+    let mut fields = vec![
+        ("info-hash", Bencode::ByteString(info.info_hash.clone())),
+        ("save-path", Bencode::ByteString(data.save_path.to_string_lossy()
+                                                        .into_owned()
+                                                        .into_bytes())),
+        ("file-paths", Bencode::List(data.file_paths.iter()
+                                                    .map(|p| path_to_bencode(p))
+                                                    .collect())),
+    ];
+    if let Some(ref have) = data.have {
+        fields.push(("pieces", Bencode::ByteString(have.as_bytes().to_vec())));
+    }
+    let bytes = try!(util::bencode_dict(fields).to_bytes()
+                         .map_err(|e| io::Error::new(io::ErrorKind::Other,
+                                                     format!("{:?}", e))));
+
+    // written to the side and renamed over, so that a crash can't leave
+    // half a file
+    let path = resume_path(info, &data.save_path);
+    let tmp = path.with_extension("resume.tmp");
+    {
+        let mut file = try!(File::create(&tmp));
+        try!(file.write_all(&bytes));
+    }
+    fs::rename(&tmp, &path)
+}
+
+// The resume data in `save_path`, if there is any for this torrent. Its
+// pieces are only trusted if the files still look the way it says; pieces
+// in skipped files aren't checked, since they're in the part file.
+pub fn load(info: &MetaInfo, save_path: &Path, skip: &[bool]) -> Option<ResumeData> {
+    let mut bytes = Vec::new();
+    match File::open(resume_path(info, save_path)) {
+        Ok(mut file) => if file.read_to_end(&mut bytes).is_err() {
+            return None;
+        },
+        Err(_) => return None,
+    }
+
+    let map = match bencode::from_buffer(&bytes) {
+        Ok(Bencode::Dict(map)) => map,
+        _ => return None,
+    };
+    match util::maybe_get_field(&map, "info-hash") {
+        Some(Bencode::ByteString(ref ih)) if *ih == info.info_hash => {},
+        _ => return None,
+    }
+
+    // older resume files don't have paths; nothing had been renamed then
+    let file_paths = match util::maybe_get_field(&map, "file-paths") {
+        Some(Bencode::List(paths)) => {
+            let paths: Vec<PathBuf> = paths.iter().filter_map(path_from_bencode).collect();
+            if paths.len() != info.files().len() {
+                return None;
+            }
+            paths
+        },
+        Some(_) => return None,
+        None => storage::default_paths(info),
+    };
+
+    let have = match util::maybe_get_field(&map, "pieces") {
+        Some(Bencode::ByteString(bytes)) => Bitfield::from_bytes(&bytes, info.num_pieces()).ok(),
+        _ => None,
+    };
+    let have = have.and_then(|have| {
+        if pieces_present(info, save_path, &file_paths, &have, skip) { Some(have) } else { None }
+    });
+
+    Some(ResumeData {
+        save_path: save_path.to_path_buf(),
+        file_paths: file_paths,
+        have: have,
+    })
+}
+
+// Whether every piece we had is still there, at least as far as file
+// lengths can tell
+fn pieces_present(info: &MetaInfo, save_path: &Path, file_paths: &[PathBuf], have: &Bitfield,
+                  skip: &[bool]) -> bool {
+    for index in have.iter_set() {
+        for slice in info.map_piece(index) {
+            if skip.get(slice.file).cloned().unwrap_or(false) {
+                continue;
+            }
+            let len = fs::metadata(save_path.join(&file_paths[slice.file])).map(|m| m.len())
+                                                                           .unwrap_or(0);
+            if len < slice.offset + slice.length {
+                return false;
+            }
+        }
+    }
+    true
+}
+
+pub fn remove(info: &MetaInfo, save_path: &Path) {
+    let _ = fs::remove_file(resume_path(info, save_path));
+}
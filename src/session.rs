@@ -0,0 +1,578 @@
+// A session runs any number of torrents, which share its listen port and
+// connection limit. Peers come from trackers, peer exchange and local
+// service discovery; there's no DHT, so trackerless torrents only find
+// peers on the local network or through ones they already know.
+
+use disk::{self, Disk, DiskPool};
+use download::{self, ManagerEvent};
+use httpserver::StreamServer;
+use lsd::LocalDiscovery;
+use metainfo::MetaInfo;
+use mse::EncryptionPolicy;
+use peerlist::{PeerInfo, PeerSource};
+use picker::FilePriority;
+use ratelimit::RateLimits;
+use storage::{self, Allocation, MoveConflict, StorageMode};
+use stream::{self, TorrentFile, VerifiedPieces};
+use tracker;
+use transport::Transport;
+use utp::UtpListener;
+
+use hyper;
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::net::{SocketAddr, TcpListener};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Sender, TryRecvError};
+use std::thread;
+use std::time::Duration;
+
+// maximum number of peer connections across all torrents
+pub const MAX_SESSION_CONNECTIONS: usize = 200;
+
+// maximum number of peers a torrent connects to at once, unless its
+// settings say otherwise
+pub const MAX_TORRENT_CONNECTIONS: usize = 30;
+
+// How long dropping a session waits for its torrents to wind down. They
+// only finish once their `stopped` announces have, and a tracker that
+// doesn't answer shouldn't hold up the program.
+const SHUTDOWN_TIMEOUT_SECS: u64 = 10;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum TorrentState {
+    // opening (and eventually verifying) the files on disk
+    Checking,
+    Downloading,
+    Seeding,
+    Paused,
+    Error(String),
+}
+
+impl fmt::Display for TorrentState {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TorrentState::Checking => write!(f, "checking"),
+            TorrentState::Downloading => write!(f, "downloading"),
+            TorrentState::Seeding => write!(f, "seeding"),
+            TorrentState::Paused => write!(f, "paused"),
+            TorrentState::Error(ref e) => write!(f, "error: {}", e),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum SessionError {
+    // a torrent with this info hash is already in the session
+    DuplicateTorrent,
+    UnknownTorrent,
+
+    // a file index past the end of the torrent's files
+    UnknownFile,
+
+    IoError(io::Error),
+}
+
+impl fmt::Display for SessionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SessionError::IoError(ref e) => write!(f, "{}", e),
+            _ => write!(f, "{}", self.description()),
+        }
+    }
+}
+
+impl Error for SessionError {
+    fn description(&self) -> &str {
+        match *self {
+            SessionError::DuplicateTorrent => "torrent is already in the session",
+            SessionError::UnknownTorrent => "no such torrent in the session",
+            SessionError::UnknownFile => "no such file in the torrent",
+            SessionError::IoError(_) => "I/O error",
+        }
+    }
+}
+
+// Connection slots shared by every torrent in a session
+pub struct ConnectionLimit {
+    max: AtomicUsize,
+    active: Mutex<usize>,
+}
+
+impl ConnectionLimit {
+    pub fn new(max: usize) -> ConnectionLimit {
+        ConnectionLimit {
+            max: AtomicUsize::new(max),
+            active: Mutex::new(0),
+        }
+    }
+
+    // Connections over a lowered limit are left alone; no new ones are
+    // made until enough have closed
+    pub fn set_max(&self, max: usize) {
+        self.max.store(max, Ordering::SeqCst);
+    }
+
+    pub fn acquire(&self) -> bool {
+        let mut active = self.active.lock().unwrap();
+        if *active >= self.max.load(Ordering::SeqCst) {
+            return false;
+        }
+        *active += 1;
+        true
+    }
+
+    pub fn release(&self) {
+        *self.active.lock().unwrap() -= 1;
+    }
+}
+
+// Per-torrent choices made when adding it to a session
+#[derive(Debug, Clone)]
+pub struct TorrentSettings {
+    // in `MetaInfo::files()` order; files past the end are Normal
+    pub file_priorities: Vec<FilePriority>,
+
+    // download pieces in order, for playing the files as they arrive
+    pub sequential: bool,
+
+    pub allocation: Allocation,
+    pub storage_mode: StorageMode,
+
+    // the directory the torrent's files go in
+    pub save_path: PathBuf,
+
+    // bandwidth limits in bytes a second, 0 for none: for the torrent as a
+    // whole, and for each of its peers and web seeds
+    pub download_limit: usize,
+    pub upload_limit: usize,
+    pub peer_download_limit: usize,
+    pub peer_upload_limit: usize,
+
+    // peers the torrent may be connected to at once, within the session's
+    // limit
+    pub max_connections: usize,
+}
+
+impl TorrentSettings {
+    pub fn new() -> TorrentSettings {
+        TorrentSettings {
+            file_priorities: Vec::new(),
+            sequential: false,
+            allocation: Allocation::Sparse,
+            storage_mode: StorageMode::Files,
+            save_path: PathBuf::from("."),
+            download_limit: 0,
+            upload_limit: 0,
+            peer_download_limit: 0,
+            peer_upload_limit: 0,
+            max_connections: MAX_TORRENT_CONNECTIONS,
+        }
+    }
+}
+
+// What the session keeps for each of its torrents, shared with the
+// torrent's own thread
+#[derive(Clone)]
+pub struct TorrentHandle {
+    pub info: Arc<MetaInfo>,
+    pub events: Sender<ManagerEvent>,
+    pub state: Arc<Mutex<TorrentState>>,
+    pub verified: Arc<VerifiedPieces>,
+
+    // changes when the torrent's storage is moved
+    pub save_path: Arc<Mutex<PathBuf>>,
+
+    // where each file is under `save_path`, which changes when files are
+    // renamed
+    pub file_paths: Arc<Mutex<Vec<PathBuf>>>,
+
+    // the torrent's storage, once its files are open and until it's removed
+    pub disk: Arc<Mutex<Option<Disk>>>,
+
+    // the torrent's bandwidth limits, and the ones each peer gets
+    pub rates: RateLimits,
+    pub peer_rates: RateLimits,
+}
+
+// the session's torrents, by info hash
+pub type Torrents = Arc<Mutex<HashMap<Vec<u8>, TorrentHandle>>>;
+
+pub struct Session {
+    torrents: Torrents,
+    peer_id: String,
+    policy: EncryptionPolicy,
+    limit: Arc<ConnectionLimit>,
+    rates: RateLimits,
+    disk_pool: Arc<DiskPool>,
+
+    // None if the LSD port couldn't be bound
+    lsd: Option<LocalDiscovery>,
+
+    // every torrent's thread, including removed ones that may still be
+    // winding down
+    threads: Mutex<Vec<thread::JoinHandle<()>>>,
+}
+
+impl Session {
+    // Starts listening for peers, over TCP and uTP on the same port. If the
+    // port is taken the session still works, it just only makes outgoing
+    // connections.
+    pub fn new(peer_id: String, policy: EncryptionPolicy) -> Session {
+        let torrents: Torrents = Arc::new(Mutex::new(HashMap::new()));
+
+        match TcpListener::bind(("0.0.0.0", tracker::LISTEN_PORT)) {
+            Ok(listener) => {
+                let torrents = torrents.clone();
+                thread::spawn(move || listen(listener, torrents, policy));
+            },
+            Err(e) => log!("not accepting connections, couldn't listen on port {}: {:?}",
+                           tracker::LISTEN_PORT, e),
+        }
+        let utp_addr = SocketAddr::new("0.0.0.0".parse().unwrap(), tracker::LISTEN_PORT);
+        match UtpListener::bind(utp_addr) {
+            Ok(listener) => {
+                log!("accepting uTP connections on {}", listener.local_addr());
+                let torrents = torrents.clone();
+                thread::spawn(move || listen_utp(listener, torrents, policy));
+            },
+            Err(e) => log!("not accepting uTP connections, couldn't listen on port {}: {:?}",
+                           tracker::LISTEN_PORT, e),
+        }
+        let lsd = match LocalDiscovery::start(tracker::LISTEN_PORT) {
+            Ok(lsd) => Some(lsd),
+            Err(e) => {
+                log!("local service discovery unavailable: {:?}", e);
+                None
+            },
+        };
+
+        Session {
+            torrents: torrents,
+            peer_id: peer_id,
+            policy: policy,
+            limit: Arc::new(ConnectionLimit::new(MAX_SESSION_CONNECTIONS)),
+            rates: RateLimits::new(0, 0),
+            disk_pool: DiskPool::new(disk::DISK_THREADS),
+            lsd: lsd,
+            threads: Mutex::new(Vec::new()),
+        }
+    }
+
+    // Adds a torrent and starts downloading it. Returns its info hash.
+    pub fn add(&self, info: MetaInfo) -> Result<Vec<u8>, SessionError> {
+        self.add_with_settings(info, TorrentSettings::new())
+    }
+
+    pub fn add_with_settings(&self, info: MetaInfo, settings: TorrentSettings)
+            -> Result<Vec<u8>, SessionError> {
+        let mut torrents = self.torrents.lock().unwrap();
+        if torrents.contains_key(&info.info_hash) {
+            return Err(SessionError::DuplicateTorrent);
+        }
+
+        let info_hash = info.info_hash.clone();
+        let (tx, rx) = mpsc::channel();
+        let torrent = TorrentHandle {
+            verified: Arc::new(VerifiedPieces::new(info.num_pieces())),
+            events: tx,
+            state: Arc::new(Mutex::new(TorrentState::Checking)),
+            save_path: Arc::new(Mutex::new(settings.save_path.clone())),
+            file_paths: Arc::new(Mutex::new(storage::default_paths(&info))),
+            disk: Arc::new(Mutex::new(None)),
+            info: Arc::new(info),
+            rates: RateLimits::new(settings.download_limit, settings.upload_limit),
+            peer_rates: RateLimits::new(settings.peer_download_limit,
+                                        settings.peer_upload_limit),
+        };
+        {
+            let torrent = torrent.clone();
+            let peer_id = self.peer_id.clone();
+            let policy = self.policy;
+            let limit = self.limit.clone();
+            let rates = self.rates.clone();
+            let disk_pool = self.disk_pool.clone();
+            let thread = thread::spawn(move || {
+                download::run_torrent(torrent, settings, peer_id, policy, limit, rates,
+                                      disk_pool, rx)
+            });
+            self.threads.lock().unwrap().push(thread);
+        }
+
+        // private torrents may only get peers from their tracker
+        if let Some(ref lsd) = self.lsd {
+            if !torrent.info.info.is_private() {
+                let events = torrent.events.clone();
+                lsd.add(info_hash.clone(), Box::new(move |peers: Vec<tracker::Peer>| {
+                    let _ = events.send(ManagerEvent::Discovered(PeerSource::Lsd, peers));
+                }));
+            }
+        }
+
+        torrents.insert(info_hash.clone(), torrent);
+        Ok(info_hash)
+    }
+
+    // Stops a torrent and forgets about it. Its files stay on disk.
+    pub fn remove(&self, info_hash: &[u8]) -> Result<(), SessionError> {
+        match self.torrents.lock().unwrap().remove(info_hash) {
+            Some(torrent) => {
+                if let Some(ref lsd) = self.lsd {
+                    lsd.remove(info_hash);
+                }
+                let _ = torrent.events.send(ManagerEvent::Remove);
+                Ok(())
+            },
+            None => Err(SessionError::UnknownTorrent),
+        }
+    }
+
+    pub fn pause(&self, info_hash: &[u8]) -> Result<(), SessionError> {
+        self.send(info_hash, ManagerEvent::Pause)
+    }
+
+    pub fn resume(&self, info_hash: &[u8]) -> Result<(), SessionError> {
+        self.send(info_hash, ManagerEvent::Resume)
+    }
+
+    // Hashes the torrent's data on disk again, e.g. after a crash. Pieces
+    // that fail are downloaded again.
+    pub fn recheck(&self, info_hash: &[u8]) -> Result<(), SessionError> {
+        self.send(info_hash, ManagerEvent::Recheck)
+    }
+
+    // Changes which of a torrent's files are downloaded, and in what order.
+    // Skipped files aren't created; files already on disk are left there.
+    pub fn set_file_priorities(&self, info_hash: &[u8], priorities: Vec<FilePriority>)
+            -> Result<(), SessionError> {
+        self.send(info_hash, ManagerEvent::SetFilePriorities(priorities))
+    }
+
+    // Switches between downloading pieces in order and rarest first
+    pub fn set_sequential(&self, info_hash: &[u8], sequential: bool)
+            -> Result<(), SessionError> {
+        self.send(info_hash, ManagerEvent::SetSequential(sequential))
+    }
+
+    // Moves a torrent's files to `path`, pausing its disk I/O while they
+    // move. Returns once they've moved.
+    pub fn move_storage(&self, info_hash: &[u8], path: &Path, conflict: MoveConflict)
+            -> Result<(), SessionError> {
+        let (tx, rx) = mpsc::channel();
+        try!(self.send(info_hash, ManagerEvent::MoveStorage(path.to_path_buf(), conflict, tx)));
+        match rx.recv() {
+            Ok(result) => result.map_err(SessionError::IoError),
+            // the torrent was removed before it got to it
+            Err(_) => Err(SessionError::UnknownTorrent),
+        }
+    }
+
+    pub fn save_path(&self, info_hash: &[u8]) -> Option<PathBuf> {
+        self.torrents.lock().unwrap()
+                     .get(info_hash)
+                     .map(|t| t.save_path.lock().unwrap().clone())
+    }
+
+    // Where a torrent's files are, relative to its save path, in
+    // `MetaInfo::files()` order
+    pub fn file_paths(&self, info_hash: &[u8]) -> Option<Vec<PathBuf>> {
+        self.torrents.lock().unwrap()
+                     .get(info_hash)
+                     .map(|t| t.file_paths.lock().unwrap().clone())
+    }
+
+    // Renames file `index` on disk to `path`, relative to the save path.
+    // The torrent itself is unchanged; only where the file is kept.
+    pub fn rename_file(&self, info_hash: &[u8], index: usize, path: &Path)
+            -> Result<(), SessionError> {
+        let num_files = match self.torrents.lock().unwrap().get(info_hash) {
+            Some(torrent) => torrent.info.files().len(),
+            None => return Err(SessionError::UnknownTorrent),
+        };
+        if index >= num_files {
+            return Err(SessionError::UnknownFile);
+        }
+        self.rename_files(info_hash, vec![(index, path.to_path_buf())])
+    }
+
+    // Renames a directory on disk, moving every file under `from` (relative
+    // to the save path) to the same place under `to`
+    pub fn rename_directory(&self, info_hash: &[u8], from: &Path, to: &Path)
+            -> Result<(), SessionError> {
+        let paths = match self.file_paths(info_hash) {
+            Some(paths) => paths,
+            None => return Err(SessionError::UnknownTorrent),
+        };
+        let renames: Vec<(usize, PathBuf)> = paths.iter().enumerate()
+            .filter_map(|(i, p)| p.strip_prefix(from).ok().map(|rest| (i, to.join(rest))))
+            .collect();
+        if renames.is_empty() {
+            return Err(SessionError::UnknownFile);
+        }
+        self.rename_files(info_hash, renames)
+    }
+
+    fn rename_files(&self, info_hash: &[u8], renames: Vec<(usize, PathBuf)>)
+            -> Result<(), SessionError> {
+        let (tx, rx) = mpsc::channel();
+        try!(self.send(info_hash, ManagerEvent::RenameFiles(renames, tx)));
+        match rx.recv() {
+            Ok(result) => result.map_err(SessionError::IoError),
+            Err(_) => Err(SessionError::UnknownTorrent),
+        }
+    }
+
+    // Asks for the pieces covering `length` bytes at `offset` in the torrent
+    // to be downloaded, ahead of anything else, within `within`
+    pub fn set_deadline(&self, info_hash: &[u8], offset: u64, length: u64, within: Duration)
+            -> Result<(), SessionError> {
+        let deadlines = match self.torrents.lock().unwrap().get(info_hash) {
+            Some(torrent) => stream::range_deadlines(&torrent.info, offset, length, within),
+            None => return Err(SessionError::UnknownTorrent),
+        };
+        self.send(info_hash, ManagerEvent::SetDeadlines(deadlines))
+    }
+
+    // A reader for file `index` (in `MetaInfo::files()` order) that blocks
+    // until the data it reads has been downloaded and verified
+    pub fn open_file(&self, info_hash: &[u8], index: usize) -> Result<TorrentFile, SessionError> {
+        match self.torrents.lock().unwrap().get(info_hash) {
+            Some(torrent) => TorrentFile::new(torrent, index).ok_or(SessionError::UnknownFile),
+            None => Err(SessionError::UnknownTorrent),
+        }
+    }
+
+    // Serves the files of every torrent in the session over HTTP on `port`,
+    // at /<info hash in hex>/<path in the torrent>, until the server is
+    // dropped
+    pub fn serve_http(&self, port: u16) -> Result<StreamServer, hyper::Error> {
+        StreamServer::start(self.torrents.clone(), port)
+    }
+
+    // Limits the bandwidth of all the session's torrents together, in bytes
+    // a second. 0 means no limit.
+    pub fn set_rate_limits(&self, download: usize, upload: usize) {
+        self.rates.set(download, upload);
+    }
+
+    // (download, upload)
+    pub fn rate_limits(&self) -> (usize, usize) {
+        self.rates.get()
+    }
+
+    // Limits the bandwidth of one torrent, in bytes a second
+    pub fn set_torrent_rate_limits(&self, info_hash: &[u8], download: usize, upload: usize)
+            -> Result<(), SessionError> {
+        match self.torrents.lock().unwrap().get(info_hash) {
+            Some(torrent) => Ok(torrent.rates.set(download, upload)),
+            None => Err(SessionError::UnknownTorrent),
+        }
+    }
+
+    // Limits the bandwidth of each of a torrent's peers and web seeds, in
+    // bytes a second. Connections already open are limited too.
+    pub fn set_peer_rate_limits(&self, info_hash: &[u8], download: usize, upload: usize)
+            -> Result<(), SessionError> {
+        match self.torrents.lock().unwrap().get(info_hash) {
+            Some(torrent) => Ok(torrent.peer_rates.set(download, upload)),
+            None => Err(SessionError::UnknownTorrent),
+        }
+    }
+
+    // How many peers all the session's torrents together may be connected
+    // to at once
+    pub fn set_max_connections(&self, max: usize) {
+        self.limit.set_max(max);
+    }
+
+    // How many peers one torrent may be connected to at once
+    pub fn set_torrent_max_connections(&self, info_hash: &[u8], max: usize)
+            -> Result<(), SessionError> {
+        self.send(info_hash, ManagerEvent::SetMaxConnections(max))
+    }
+
+    // Every peer the torrent knows of, where it heard of them and how
+    // connecting to them has gone
+    pub fn peers(&self, info_hash: &[u8]) -> Result<Vec<PeerInfo>, SessionError> {
+        let (tx, rx) = mpsc::channel();
+        try!(self.send(info_hash, ManagerEvent::Peers(tx)));
+        rx.recv().map_err(|_| SessionError::UnknownTorrent)
+    }
+
+    pub fn state(&self, info_hash: &[u8]) -> Option<TorrentState> {
+        self.torrents.lock().unwrap()
+                     .get(info_hash)
+                     .map(|t| t.state.lock().unwrap().clone())
+    }
+
+    // (info hash, name, state) for every torrent in the session
+    pub fn torrents(&self) -> Vec<(Vec<u8>, String, TorrentState)> {
+        self.torrents.lock().unwrap()
+                     .iter()
+                     .map(|(ih, t)| (ih.clone(), String::from(t.info.info.name()),
+                                     t.state.lock().unwrap().clone()))
+                     .collect()
+    }
+
+    fn send(&self, info_hash: &[u8], event: ManagerEvent) -> Result<(), SessionError> {
+        match self.torrents.lock().unwrap().get(info_hash) {
+            Some(torrent) => {
+                let _ = torrent.events.send(event);
+                Ok(())
+            },
+            None => Err(SessionError::UnknownTorrent),
+        }
+    }
+}
+
+impl Drop for Session {
+    // Removes every torrent and waits, for up to `SHUTDOWN_TIMEOUT_SECS`,
+    // for them to wind down, so that the disk threads get through
+    // everything they're given before they stop
+    fn drop(&mut self) {
+        for (_, torrent) in self.torrents.lock().unwrap().drain() {
+            let _ = torrent.events.send(ManagerEvent::Remove);
+        }
+        let threads: Vec<_> = self.threads.lock().unwrap().drain(..).collect();
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || {
+            for thread in threads {
+                let _ = thread.join();
+            }
+            let _ = tx.send(());
+        });
+        for _ in 0..SHUTDOWN_TIMEOUT_SECS * 10 {
+            match rx.try_recv() {
+                Err(TryRecvError::Empty) => thread::sleep(Duration::from_millis(100)),
+                _ => break,
+            }
+        }
+        self.disk_pool.shutdown();
+    }
+}
+
+// Accepts incoming connections, handing each to the torrent it asks for
+fn listen(listener: TcpListener, torrents: Torrents, policy: EncryptionPolicy) {
+    for stream in listener.incoming() {
+        match stream {
+            Ok(stream) => {
+                let torrents = torrents.clone();
+                thread::spawn(move || {
+                    download::accept_peer(Transport::Tcp(stream), torrents, policy)
+                });
+            },
+            Err(e) => log!("error accepting connection: {:?}", e),
+        }
+    }
+}
+
+fn listen_utp(listener: UtpListener, torrents: Torrents, policy: EncryptionPolicy) {
+    while let Ok(stream) = listener.accept() {
+        let torrents = torrents.clone();
+        thread::spawn(move || download::accept_peer(Transport::Utp(stream), torrents, policy));
+    }
+}
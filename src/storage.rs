@@ -0,0 +1,710 @@
+use metainfo::{FileEntry, FileSlice, MetaInfo};
+use mmap::Mmap;
+use util;
+
+use libc;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::collections::HashSet;
+use std::path::{Component, Path, PathBuf};
+
+// What to do when moving a torrent's files onto files that already exist
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MoveConflict {
+    // move nothing
+    Fail,
+    Overwrite,
+
+    // use the files already there, which then have to be rechecked. Our
+    // copies are left where they were.
+    KeepExisting,
+}
+
+// How a torrent's files are created
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Allocation {
+    // set to their full length up front, but only take space as they're
+    // written, which can leave them fragmented
+    Sparse,
+
+    // every byte reserved on disk before anything is downloaded
+    Full,
+}
+
+impl Allocation {
+    pub fn from_str(s: &str) -> Option<Allocation> {
+        match s {
+            "sparse" => Some(Allocation::Sparse),
+            "full" => Some(Allocation::Full),
+            _ => None,
+        }
+    }
+}
+
+// How a torrent's files are read and written
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StorageMode {
+    // with read() and write()
+    Files,
+
+    // through memory maps, so that blocks are served and pieces hashed
+    // straight from the page cache. Files that can't be mapped use read()
+    // and write() anyway.
+    Mmap,
+}
+
+impl StorageMode {
+    pub fn from_str(s: &str) -> Option<StorageMode> {
+        match s {
+            "files" => Some(StorageMode::Files),
+            "mmap" => Some(StorageMode::Mmap),
+            _ => None,
+        }
+    }
+}
+
+// One of the torrent's files, open for reading and writing
+enum StorageFile {
+    Plain(File),
+    Mapped(Mmap),
+}
+
+impl StorageFile {
+    // Maps `file` if asked to, falling back to using it as it is
+    fn new(file: File, entry: &FileEntry, mode: StorageMode) -> StorageFile {
+        if mode == StorageMode::Mmap && entry.length > 0 {
+            match Mmap::map(&file, entry.length) {
+                Ok(map) => return StorageFile::Mapped(map),
+                Err(e) => log!("couldn't map {}, using reads and writes: {:?}",
+                               entry.path.display(), e),
+            }
+        }
+        StorageFile::Plain(file)
+    }
+
+    fn write_at(&mut self, offset: u64, data: &[u8]) -> Result<(), io::Error> {
+        match *self {
+            StorageFile::Plain(ref mut file) => {
+                try!(file.seek(SeekFrom::Start(offset)));
+                file.write_all(data)
+            },
+            StorageFile::Mapped(ref mut map) => {
+                let range = try!(map_range(map.as_slice().len(), offset, data.len()));
+                util::copy_into(&mut map.as_mut_slice()[range.0..range.1], data);
+                Ok(())
+            },
+        }
+    }
+
+    fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), io::Error> {
+        match *self {
+            StorageFile::Plain(ref mut file) => {
+                try!(file.seek(SeekFrom::Start(offset)));
+                file.read_exact(buf)
+            },
+            StorageFile::Mapped(ref map) => {
+                let range = try!(map_range(map.as_slice().len(), offset, buf.len()));
+                util::copy_into(buf, &map.as_slice()[range.0..range.1]);
+                Ok(())
+            },
+        }
+    }
+}
+
+// (start, end) of `len` bytes at `offset` in a mapping `size` bytes long
+fn map_range(size: usize, offset: u64, len: usize) -> Result<(usize, usize), io::Error> {
+    if offset + len as u64 > size as u64 {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEOF, "past the end of the file"));
+    }
+    Ok((offset as usize, offset as usize + len))
+}
+
+// The files being downloaded, addressed by piece.
+pub struct Storage {
+    // None for files we're skipping
+    files: Vec<Option<StorageFile>>,
+    entries: Vec<FileEntry>,
+
+    // where each file is under `root`: its path in the torrent unless it's
+    // been renamed
+    paths: Vec<PathBuf>,
+    info: MetaInfo,
+    allocation: Allocation,
+    mode: StorageMode,
+
+    // the directory the torrent's paths are relative to
+    root: PathBuf,
+
+    // Pieces that straddle a skipped file and one we want are still
+    // downloaded whole. The skipped file's part of them goes here, at its
+    // offset in the torrent, so the file itself is never created.
+    part_file: Option<File>,
+}
+
+// The torrent's own paths for its files, before any renames
+pub fn default_paths(info: &MetaInfo) -> Vec<PathBuf> {
+    info.files().into_iter().map(|f| f.path).collect()
+}
+
+fn open_file(path: &Path, entry: &FileEntry, allocation: Allocation)
+        -> Result<File, io::Error> {
+    if let Some(dir) = path.parent() {
+        try!(fs::create_dir_all(dir));
+    }
+    let file = try!(OpenOptions::new().read(true)
+                                      .write(true)
+                                      .create(true)
+                                      .open(path));
+    try!(allocate(&file, entry.length, allocation));
+    Ok(file)
+}
+
+// Grows `file` to `length` bytes. Files are never shortened, so whatever
+// is already there can be checked.
+fn allocate(file: &File, length: u64, allocation: Allocation) -> Result<(), io::Error> {
+    let current = try!(file.metadata()).len();
+    if current >= length {
+        return Ok(());
+    }
+    match allocation {
+        Allocation::Sparse => file.set_len(length),
+        Allocation::Full => preallocate(file, current, length),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn preallocate(file: &File, from: u64, length: u64) -> Result<(), io::Error> {
+    use std::os::unix::io::AsRawFd;
+
+    let ret = unsafe {
+        libc::fallocate(file.as_raw_fd(), 0, from as libc::off_t, (length - from) as libc::off_t)
+    };
+    if ret == 0 {
+        return Ok(());
+    }
+    let err = io::Error::last_os_error();
+    match err.raw_os_error() {
+        // not every filesystem can do it
+        Some(libc::EOPNOTSUPP) => zero_fill(file, from, length),
+        _ => Err(err),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn preallocate(file: &File, from: u64, length: u64) -> Result<(), io::Error> {
+    zero_fill(file, from, length)
+}
+
+fn zero_fill(mut file: &File, from: u64, length: u64) -> Result<(), io::Error> {
+    let zeros = [0; 65536];
+    try!(file.seek(SeekFrom::Start(from)));
+    let mut left = length - from;
+    while left > 0 {
+        let n = if left < zeros.len() as u64 { left as usize } else { zeros.len() };
+        try!(file.write_all(&zeros[..n]));
+        left -= n as u64;
+    }
+    Ok(())
+}
+
+// Bytes available to us on the filesystem holding `dir`, if we can tell
+#[cfg(unix)]
+fn free_space(dir: &Path) -> Option<u64> {
+    use std::ffi::CString;
+    use std::mem;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = match CString::new(dir.as_os_str().as_bytes()) {
+        Ok(path) => path,
+        Err(_) => return None,
+    };
+    let mut stat: libc::statvfs = unsafe { mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+    Some(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+#[cfg(not(unix))]
+fn free_space(_: &Path) -> Option<u64> {
+    None
+}
+
+// Fails if `needed` more bytes won't fit on the filesystem holding `dir`
+fn check_free_space(dir: &Path, needed: u64) -> Result<(), io::Error> {
+    match free_space(dir) {
+        Some(free) if needed > free => {
+            Err(io::Error::new(io::ErrorKind::Other,
+                               format!("not enough free space: {} bytes needed, {} available",
+                                       needed, free)))
+        },
+        _ => Ok(()),
+    }
+}
+
+// The filesystem a file is on, where we can tell
+#[cfg(unix)]
+fn device(meta: &fs::Metadata) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    Some(meta.dev() as u64)
+}
+
+#[cfg(not(unix))]
+fn device(_: &fs::Metadata) -> Option<u64> {
+    None
+}
+
+// `path`, or the nearest directory above it that exists
+fn existing_ancestor(path: &Path) -> &Path {
+    let mut dir = path;
+    while !dir.exists() {
+        match dir.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => dir = parent,
+            _ => return Path::new("."),
+        }
+    }
+    dir
+}
+
+// Where the parts of skipped files that we still need are kept
+pub fn part_file_path(info: &MetaInfo, root: &Path) -> PathBuf {
+    root.join(format!(".{}.parts", info.info.name()))
+}
+
+// Renames `from` to `to`, or copies it if they're on different filesystems
+fn move_file(from: &Path, to: &Path) -> Result<(), io::Error> {
+    if let Some(dir) = to.parent() {
+        try!(fs::create_dir_all(dir));
+    }
+    if fs::rename(from, to).is_ok() {
+        return Ok(());
+    }
+    // a partial copy is no use to anyone
+    if let Err(e) = fs::copy(from, to) {
+        let _ = fs::remove_file(to);
+        return Err(e);
+    }
+    fs::remove_file(from)
+}
+
+// Removes the directories between `root` and the file at `path` that are
+// empty now that it's gone
+fn remove_empty_dirs(root: &Path, path: &Path) {
+    let mut dir = path.parent();
+    while let Some(d) = dir {
+        if d == root || fs::remove_dir(d).is_err() {
+            break;
+        }
+        dir = d.parent();
+    }
+}
+
+// A new name for a file must stay inside the torrent's directory
+pub fn valid_path(path: &Path) -> bool {
+    path.components().next().is_some() && path.components().all(|c| match c {
+        Component::Normal(_) => true,
+        _ => false,
+    })
+}
+
+impl Storage {
+    // Opens (creating if necessary) the torrent's files under `root`, at
+    // `paths`, except those marked in `skip`. Fails before creating
+    // anything if the files won't fit.
+    pub fn new(info: &MetaInfo, root: &Path, paths: Vec<PathBuf>, skip: &[bool],
+               allocation: Allocation, mode: StorageMode) -> Result<Storage, io::Error> {
+        try!(fs::create_dir_all(root));
+        let entries = info.files();
+        if paths.len() != entries.len() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "a path is needed for each of the torrent's files"));
+        }
+        let skipped = |i: usize| skip.get(i).cloned().unwrap_or(false);
+
+        // what's left of `num_file_bytes()` once skipped files and data
+        // already on disk are taken out
+        let mut needed = 0;
+        for (i, entry) in entries.iter().enumerate() {
+            if !skipped(i) {
+                let existing = fs::metadata(root.join(&paths[i])).map(|m| m.len())
+                                                                  .unwrap_or(0);
+                needed += entry.length.saturating_sub(existing);
+            }
+        }
+        try!(check_free_space(root, needed));
+
+        let mut files = Vec::new();
+        for (i, entry) in entries.iter().enumerate() {
+            if skipped(i) {
+                files.push(None);
+            } else {
+                let file = try!(open_file(&root.join(&paths[i]), entry, allocation));
+                files.push(Some(StorageFile::new(file, entry, mode)));
+            }
+        }
+        Ok(Storage {
+            files: files,
+            entries: entries,
+            paths: paths,
+            info: info.clone(),
+            allocation: allocation,
+            mode: mode,
+            root: root.to_path_buf(),
+            part_file: None,
+        })
+    }
+
+    // Moves the torrent's files, and its part file, under `root`. Nothing
+    // is read or written while they're moving, since that needs `&mut
+    // self`. Returns whether the data needs rechecking, which it does when
+    // files that were already there are kept. If a file can't be moved, the
+    // ones that were are moved back, so the torrent stays in one place.
+    pub fn move_to(&mut self, root: &Path, conflict: MoveConflict) -> Result<bool, io::Error> {
+        if root == self.root {
+            return Ok(false);
+        }
+
+        let mut moves: Vec<(PathBuf, PathBuf)> = self.paths.iter()
+                                                   .map(|p| (self.root.join(p), root.join(p)))
+                                                   .collect();
+        moves.push((part_file_path(&self.info, &self.root), part_file_path(&self.info, root)));
+        let moves: Vec<(PathBuf, PathBuf)> = moves.into_iter()
+                                                  .filter(|&(ref from, _)| from.exists())
+                                                  .collect();
+
+        let conflicts = moves.iter().any(|&(_, ref to)| to.exists());
+        if conflicts && conflict == MoveConflict::Fail {
+            return Err(io::Error::new(io::ErrorKind::AlreadyExists,
+                                      format!("files already exist in {}", root.display())));
+        }
+        let keep_existing = conflicts && conflict == MoveConflict::KeepExisting;
+        let moves: Vec<(PathBuf, PathBuf)> = moves.into_iter()
+                                                  .filter(|&(_, ref to)| {
+                                                      !(keep_existing && to.exists())
+                                                  })
+                                                  .collect();
+
+        // files on another filesystem than `root` are copied, so they have
+        // to fit there alongside the originals
+        let dest = existing_ancestor(root).to_path_buf();
+        let dest_device = device(&try!(fs::metadata(&dest)));
+        let mut needed = 0;
+        for &(ref from, _) in moves.iter() {
+            let meta = try!(fs::metadata(from));
+            if dest_device.is_none() || device(&meta) != dest_device {
+                needed += meta.len();
+            }
+        }
+        try!(check_free_space(&dest, needed));
+
+        // close everything first, since copied files are deleted after
+        let open: Vec<bool> = self.files.iter().map(|f| f.is_some()).collect();
+        for file in self.files.iter_mut() {
+            *file = None;
+        }
+        self.part_file = None;
+
+        let mut result = Ok(());
+        let mut moved = 0;
+        for &(ref from, ref to) in moves.iter() {
+            result = move_file(from, to);
+            if result.is_err() {
+                break;
+            }
+            moved += 1;
+        }
+
+        match result {
+            Ok(()) => {
+                // tidy up the directories the files were in, if they're empty now
+                for &(ref from, _) in moves.iter() {
+                    remove_empty_dirs(&self.root, from);
+                }
+                self.root = root.to_path_buf();
+            },
+            Err(_) => {
+                for &(ref from, ref to) in moves[..moved].iter().rev() {
+                    if let Err(e) = move_file(to, from) {
+                        log!("couldn't move {} back to {}: {:?}", to.display(),
+                             from.display(), e);
+                    }
+                    remove_empty_dirs(root, to);
+                }
+            },
+        }
+
+        // carry on with the files wherever they are now
+        for (i, &was_open) in open.iter().enumerate() {
+            if was_open {
+                self.files[i] = Some(try!(self.open(i)));
+            }
+        }
+        result.map(|_| keep_existing)
+    }
+
+    pub fn paths(&self) -> &[PathBuf] {
+        &self.paths
+    }
+
+    // `length` bytes at `offset` in the torrent, as slices of the mapped
+    // files they're in, or None if any of them isn't mapped
+    pub fn mapped_range(&self, offset: u64, length: u64) -> Option<Vec<&[u8]>> {
+        let mut slices = Vec::new();
+        for slice in self.info.map_range(offset, length) {
+            match self.files[slice.file] {
+                Some(StorageFile::Mapped(ref map)) => {
+                    let start = slice.offset as usize;
+                    let end = start + slice.length as usize;
+                    if end > map.as_slice().len() {
+                        return None;
+                    }
+                    slices.push(&map.as_slice()[start..end]);
+                },
+                _ => return None,
+            }
+        }
+        Some(slices)
+    }
+
+    pub fn mapped_piece(&self, index: u32) -> Option<Vec<&[u8]>> {
+        self.mapped_range(self.info.piece_offset(index), self.info.piece_size(index) as u64)
+    }
+
+    // Gives files new paths under the root, given as (file index, path),
+    // moving any that are already on disk. This only changes where the
+    // files are kept; the torrent is the same.
+    pub fn rename(&mut self, renames: &[(usize, PathBuf)]) -> Result<(), io::Error> {
+        let mut paths = self.paths.clone();
+        for &(index, ref path) in renames.iter() {
+            if index >= paths.len() {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                          format!("no file {} in the torrent", index)));
+            }
+            if !valid_path(path) {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                          format!("{} isn't a relative path in the torrent's \
+                                                   directory", path.display())));
+            }
+            paths[index] = path.clone();
+        }
+        {
+            let mut seen = HashSet::new();
+            for path in paths.iter() {
+                if !seen.insert(path) {
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                              format!("more than one file would be at {}",
+                                                      path.display())));
+                }
+            }
+        }
+
+        let changed: Vec<usize> = (0..paths.len()).filter(|&i| paths[i] != self.paths[i])
+                                                  .collect();
+        for &i in changed.iter() {
+            let to = self.root.join(&paths[i]);
+            if to.exists() && !self.paths.contains(&paths[i]) {
+                return Err(io::Error::new(io::ErrorKind::AlreadyExists,
+                                          format!("{} already exists", to.display())));
+            }
+        }
+
+        // Files are moved out of the way first and then into place, so
+        // that two files can swap names
+        let mut reopen = Vec::new();
+        for &i in changed.iter() {
+            if self.files[i].take().is_some() {
+                reopen.push(i);
+            }
+        }
+        let mut result = Ok(());
+        let mut moved = Vec::new();
+        for &i in changed.iter() {
+            let from = self.root.join(&self.paths[i]);
+            if from.exists() {
+                let tmp = self.root.join(format!(".{}.{}.renaming", self.info.info.name(), i));
+                result = fs::rename(&from, &tmp);
+                if result.is_err() {
+                    break;
+                }
+                remove_empty_dirs(&self.root, &from);
+                moved.push((i, tmp));
+            }
+        }
+        let mut placed = 0;
+        if result.is_ok() {
+            for &(i, ref tmp) in moved.iter() {
+                result = move_file(tmp, &self.root.join(&paths[i]));
+                if result.is_err() {
+                    break;
+                }
+                placed += 1;
+            }
+        }
+
+        match result {
+            Ok(()) => self.paths = paths,
+            // put everything back under its old name: out of the way
+            // again first, for the same reason
+            Err(_) => {
+                for &(i, ref tmp) in moved[..placed].iter() {
+                    let at = self.root.join(&paths[i]);
+                    if let Err(e) = move_file(&at, tmp) {
+                        log!("couldn't move {} back: {:?}", at.display(), e);
+                    }
+                    remove_empty_dirs(&self.root, &at);
+                }
+                for &(i, ref tmp) in moved.iter() {
+                    let from = self.root.join(&self.paths[i]);
+                    if let Err(e) = move_file(tmp, &from) {
+                        log!("couldn't move {} back to {}: {:?}", tmp.display(),
+                             from.display(), e);
+                    }
+                }
+            },
+        }
+
+        // whichever names the files have now
+        for &i in reopen.iter() {
+            self.files[i] = Some(try!(self.open(i)));
+        }
+        result
+    }
+
+    pub fn write_piece(&mut self, index: u32, data: &[u8]) -> Result<(), io::Error> {
+        let mut pos = 0;
+        for slice in self.info.map_piece(index) {
+            let end = pos + slice.length as usize;
+            try!(self.write_slice(&slice, &data[pos..end]));
+            pos = end;
+        }
+        Ok(())
+    }
+
+    // Writes `data` at `offset` bytes into the torrent
+    pub fn write_at(&mut self, offset: u64, data: &[u8]) -> Result<(), io::Error> {
+        let mut pos = 0;
+        for slice in self.info.map_range(offset, data.len() as u64) {
+            let end = pos + slice.length as usize;
+            try!(self.write_slice(&slice, &data[pos..end]));
+            pos = end;
+        }
+        Ok(())
+    }
+
+    pub fn read_block(&mut self, index: u32, begin: u32, length: u32)
+            -> Result<Vec<u8>, io::Error> {
+        let offset = self.info.piece_offset(index) + begin as u64;
+        let mut buf = vec![0; length as usize];
+        let mut pos = 0;
+        for slice in self.info.map_range(offset, length as u64) {
+            let end = pos + slice.length as usize;
+            try!(self.read_slice(&slice, &mut buf[pos..end]));
+            pos = end;
+        }
+        Ok(buf)
+    }
+
+    // Changes which files are skipped. A file we start wanting is created,
+    // with whatever the part file holds for it; one we stop wanting has its
+    // boundary pieces saved to the part file and is left alone on disk.
+    pub fn set_skipped(&mut self, skip: &[bool]) -> Result<(), io::Error> {
+        for i in 0..self.entries.len() {
+            let skipped = skip.get(i).cloned().unwrap_or(false);
+            if skipped && self.files[i].is_some() {
+                for (start, end) in self.boundary_ranges(i) {
+                    let slice = self.slice_at(i, start, end);
+                    let mut buf = vec![0; (end - start) as usize];
+                    if self.read_slice(&slice, &mut buf).is_ok() {
+                        try!(self.write_part(start, &buf));
+                    }
+                }
+                self.files[i] = None;
+            } else if !skipped && self.files[i].is_none() {
+                self.files[i] = Some(try!(self.open(i)));
+                for (start, end) in self.boundary_ranges(i) {
+                    let slice = self.slice_at(i, start, end);
+                    let mut buf = vec![0; (end - start) as usize];
+                    if self.read_part(start, &mut buf).is_ok() {
+                        try!(self.write_slice(&slice, &buf));
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn open(&self, file: usize) -> Result<StorageFile, io::Error> {
+        let entry = &self.entries[file];
+        let opened = try!(open_file(&self.root.join(&self.paths[file]), entry, self.allocation));
+        Ok(StorageFile::new(opened, entry, self.mode))
+    }
+
+    // the slice of file `file` between torrent offsets `start` and `end`
+    fn slice_at(&self, file: usize, start: u64, end: u64) -> FileSlice {
+        FileSlice {
+            file: file,
+            offset: start - self.entries[file].offset,
+            length: end - start,
+        }
+    }
+
+    // Parts of a file (in torrent offsets) that share a piece with the
+    // files before or after it: the only parts that can be in the part file
+    fn boundary_ranges(&self, file: usize) -> Vec<(u64, u64)> {
+        let entry = &self.entries[file];
+        if entry.length == 0 {
+            return Vec::new();
+        }
+        let piece_length = self.info.info.piece_length() as u64;
+        let start = entry.offset;
+        let end = entry.offset + entry.length;
+
+        let first_end = (start / piece_length + 1) * piece_length;
+        let last_start = (end - 1) / piece_length * piece_length;
+        if first_end >= end || last_start <= start {
+            return vec![(start, end)];
+        }
+        vec![(start, first_end), (last_start, end)]
+    }
+
+    fn part_file(&mut self) -> Result<&mut File, io::Error> {
+        if self.part_file.is_none() {
+            let file = try!(OpenOptions::new().read(true)
+                                              .write(true)
+                                              .create(true)
+                                              .open(part_file_path(&self.info, &self.root)));
+            self.part_file = Some(file);
+        }
+        Ok(self.part_file.as_mut().unwrap())
+    }
+
+    fn write_part(&mut self, offset: u64, data: &[u8]) -> Result<(), io::Error> {
+        let file = try!(self.part_file());
+        try!(file.seek(SeekFrom::Start(offset)));
+        file.write_all(data)
+    }
+
+    fn read_part(&mut self, offset: u64, buf: &mut [u8]) -> Result<(), io::Error> {
+        let file = try!(self.part_file());
+        try!(file.seek(SeekFrom::Start(offset)));
+        file.read_exact(buf)
+    }
+
+    fn write_slice(&mut self, slice: &FileSlice, data: &[u8]) -> Result<(), io::Error> {
+        match self.files[slice.file] {
+            Some(ref mut file) => return file.write_at(slice.offset, data),
+            None => {},
+        }
+        let offset = self.entries[slice.file].offset + slice.offset;
+        self.write_part(offset, data)
+    }
+
+    fn read_slice(&mut self, slice: &FileSlice, buf: &mut [u8]) -> Result<(), io::Error> {
+        match self.files[slice.file] {
+            Some(ref mut file) => return file.read_at(slice.offset, buf),
+            None => {},
+        }
+        let offset = self.entries[slice.file].offset + slice.offset;
+        self.read_part(offset, buf)
+    }
+}
@@ -0,0 +1,204 @@
+// Reading a torrent's files while they download: readers wait for the
+// pieces they need and give them deadlines so that they come first.
+
+use bitfield::Bitfield;
+use disk::Disk;
+use download::ManagerEvent;
+use metainfo::{FileEntry, MetaInfo};
+use session::TorrentHandle;
+use util;
+
+use std::cmp;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::mpsc::Sender;
+use std::time::Duration;
+use time;
+
+// pieces past the one being read that are asked for in advance
+pub const READ_AHEAD_PIECES: u32 = 4;
+
+// how much later each read-ahead piece is needed than the one before it
+const READ_AHEAD_STEP_MS: u64 = 1000;
+
+struct Verified {
+    have: Bitfield,
+
+    // the torrent has stopped, so nothing more will arrive
+    closed: bool,
+}
+
+// The pieces of a torrent that have passed their hash check, for readers
+// to wait on
+pub struct VerifiedPieces {
+    verified: Mutex<Verified>,
+    changed: Condvar,
+}
+
+impl VerifiedPieces {
+    pub fn new(num_pieces: u32) -> VerifiedPieces {
+        VerifiedPieces {
+            verified: Mutex::new(Verified {
+                have: Bitfield::new(num_pieces),
+                closed: false,
+            }),
+            changed: Condvar::new(),
+        }
+    }
+
+    // after a recheck
+    pub fn set(&self, have: Bitfield) {
+        self.verified.lock().unwrap().have = have;
+        self.changed.notify_all();
+    }
+
+    pub fn add(&self, index: u32) {
+        self.verified.lock().unwrap().have.set(index);
+        self.changed.notify_all();
+    }
+
+    pub fn close(&self) {
+        self.verified.lock().unwrap().closed = true;
+        self.changed.notify_all();
+    }
+
+    pub fn has(&self, index: u32) -> bool {
+        self.verified.lock().unwrap().have.has(index)
+    }
+
+    // Blocks until piece `index` is verified. False if the torrent stopped
+    // first.
+    pub fn wait_for(&self, index: u32) -> bool {
+        let mut verified = self.verified.lock().unwrap();
+        while !verified.have.has(index) {
+            if verified.closed {
+                return false;
+            }
+            verified = self.changed.wait(verified).unwrap();
+        }
+        true
+    }
+}
+
+// (piece, deadline) for the pieces covering `length` bytes at `offset` in
+// the torrent, all needed within `within`
+pub fn range_deadlines(info: &MetaInfo, offset: u64, length: u64, within: Duration)
+        -> Vec<(u32, u64)> {
+    if length == 0 {
+        return Vec::new();
+    }
+    let deadline = time::precise_time_ns() + within.as_secs() * 1_000_000_000
+                   + within.subsec_nanos() as u64;
+    let piece_length = info.info.piece_length() as u64;
+    let first = offset / piece_length;
+    let last = cmp::min((offset + length - 1) / piece_length, info.num_pieces() as u64 - 1);
+    (first..last + 1).map(|i| (i as u32, deadline)).collect()
+}
+
+// One of a torrent's files, readable while the torrent downloads. Reads
+// block until the data they cover has been verified, then go through the
+// torrent's disk, so they work for skipped files, moves and renames.
+pub struct TorrentFile {
+    info: Arc<MetaInfo>,
+    entry: FileEntry,
+    verified: Arc<VerifiedPieces>,
+    events: Sender<ManagerEvent>,
+    disk: Arc<Mutex<Option<Disk>>>,
+    pos: u64,
+
+    // the piece we last asked to have read ahead from
+    requested: Option<u32>,
+}
+
+impl TorrentFile {
+    pub fn new(torrent: &TorrentHandle, index: usize) -> Option<TorrentFile> {
+        let entry = match torrent.info.files().into_iter().nth(index) {
+            Some(entry) => entry,
+            None => return None,
+        };
+        Some(TorrentFile {
+            info: torrent.info.clone(),
+            entry: entry,
+            verified: torrent.verified.clone(),
+            events: torrent.events.clone(),
+            disk: torrent.disk.clone(),
+            pos: 0,
+            requested: None,
+        })
+    }
+
+    pub fn len(&self) -> u64 {
+        self.entry.length
+    }
+
+    pub fn entry(&self) -> &FileEntry {
+        &self.entry
+    }
+
+    // Gives the pieces from `index` on, as far as the read-ahead goes,
+    // deadlines a step apart
+    fn read_ahead(&mut self, index: u32) {
+        if self.requested == Some(index) {
+            return;
+        }
+        self.requested = Some(index);
+
+        let end = (self.entry.offset + self.entry.length - 1)
+                  / self.info.info.piece_length() as u64;
+        let last = cmp::min(index as u64 + READ_AHEAD_PIECES as u64, end) as u32;
+        let now = time::precise_time_ns();
+        let deadlines = (index..last + 1)
+            .filter(|&i| !self.verified.has(i))
+            .map(|i| (i, now + (i - index) as u64 * READ_AHEAD_STEP_MS * 1_000_000))
+            .collect::<Vec<_>>();
+        if !deadlines.is_empty() {
+            let _ = self.events.send(ManagerEvent::SetDeadlines(deadlines));
+        }
+    }
+}
+
+impl Read for TorrentFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos >= self.entry.length || buf.is_empty() {
+            return Ok(0);
+        }
+
+        // don't read past the end of the piece, which is all we wait for
+        let piece_length = self.info.info.piece_length() as u64;
+        let offset = self.entry.offset + self.pos;
+        let index = (offset / piece_length) as u32;
+        let in_piece = (index as u64 + 1) * piece_length - offset;
+        let len = cmp::min(cmp::min(buf.len() as u64, self.entry.length - self.pos), in_piece);
+
+        self.read_ahead(index);
+        if !self.verified.wait_for(index) {
+            return Err(io::Error::new(io::ErrorKind::Other, "torrent stopped"));
+        }
+
+        let disk = match *self.disk.lock().unwrap() {
+            Some(ref disk) => disk.clone(),
+            None => return Err(io::Error::new(io::ErrorKind::Other, "torrent stopped")),
+        };
+        let begin = (offset - index as u64 * piece_length) as u32;
+        let block = try!(disk.read_block(index, begin, len as u32));
+        util::copy_into(&mut buf[..len as usize], &block);
+        self.pos += len;
+        Ok(len as usize)
+    }
+}
+
+impl Seek for TorrentFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new = match pos {
+            SeekFrom::Start(n) => n as i64,
+            SeekFrom::End(n) => self.entry.length as i64 + n,
+            SeekFrom::Current(n) => self.pos as i64 + n,
+        };
+        if new < 0 {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      "seek to before the start of the file"));
+        }
+        self.pos = new as u64;
+        Ok(self.pos)
+    }
+}
@@ -1,16 +1,27 @@
 use metainfo::MetaInfo;
+use peer_id;
 use util;
 
 use bencode::{self, FromBencode, Bencode};
+use bencode::util::ByteString;
 use hyper::{self, Client};
 use hyper::header::Connection;
+use std::collections::BTreeMap;
+use std::error::Error;
+use std::fmt;
 use std::io::{self, Read};
 use std::net;
 use url::percent_encoding::{percent_encode, FORM_URLENCODED_ENCODE_SET};
 
 type Sha1Hash = Vec<u8>;
 
-enum EventType {
+// port we tell trackers (and peers) we accept connections on
+pub const LISTEN_PORT: u16 = 4567;
+
+// Why we're announcing: the start or end of a run, finishing the download,
+// or (`Empty`) just a regular update
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum EventType {
     Started,
     Stopped,
     Completed,
@@ -18,17 +29,30 @@ enum EventType {
 }
 
 impl EventType {
-    fn as_str(&self) -> &str {
+    fn as_str(&self) -> Option<&str> {
         use self::EventType::*;
         match *self {
-            Started => "started",
-            Stopped => "stopped",
-            Completed => "completed",
-            Empty => unimplemented!()
+            Started => Some("started"),
+            Stopped => Some("stopped"),
+            Completed => Some("completed"),
+            Empty => None,
         }
     }
 }
 
+// What we tell the trackers about our progress
+#[derive(Debug, Clone)]
+pub struct Announce {
+    // payload bytes since the last `Started`
+    pub uploaded: u64,
+    pub downloaded: u64,
+
+    // bytes we still want
+    pub left: u64,
+
+    pub event: EventType,
+}
+
 struct TrackerRequest {
     // sha1 hash of the value of "info key from the Metainfo file". value will be a dict?
     info_hash: Sha1Hash,
@@ -55,12 +79,12 @@ struct TrackerRequest {
     // ignored if `compact` is true
     no_peer_id: Option<bool>,
 
-    event: Option<EventType>,
+    event: EventType,
 }
 
 impl TrackerRequest {
     fn new(peer_id: String, port: u16, ul: u64, dl: u64,
-           left: u64, info_hash: Sha1Hash, event: Option<EventType>) -> TrackerRequest {
+           left: u64, info_hash: Sha1Hash, event: EventType) -> TrackerRequest {
         TrackerRequest {
             info_hash: info_hash,
             peer_id: peer_id,
@@ -87,9 +111,8 @@ impl TrackerRequest {
         v.push(format!("{}={}", "downloaded", self.downloaded));
         v.push(format!("{}={}", "port", self.port));
         v.push(format!("{}={}", "peer_id", self.peer_id));
-        if self.event.is_some() {
-            v.push(format!("{}={}", "event", self.event.as_ref().unwrap()
-                                                       .as_str()));
+        if let Some(event) = self.event.as_str() {
+            v.push(format!("{}={}", "event", event));
         }
         if self.compact.is_some() {
             v.push(format!("{}={}", "compact", if self.compact.unwrap() {1} else {0}));
@@ -98,7 +121,7 @@ impl TrackerRequest {
     }
 }
 
-// error for the tracker GET request that sends event `started`
+// error for a tracker announce or scrape
 #[derive(Debug)]
 pub enum TrackerError {
     DecodeError(String),
@@ -106,18 +129,53 @@ pub enum TrackerError {
     HyperError(hyper::Error),
 }
 
-// sends GET to tracker, obtaining a list of peers
-pub fn get_tracker(metainfo: &MetaInfo, peer_id: String) -> Result<Vec<Peer>, TrackerError> {
-    let req = TrackerRequest::new(peer_id, 4567, 0, 0,
-                                  metainfo.num_file_bytes() as u64, metainfo.info_hash.clone(),
-                                  Some(EventType::Started));
+impl fmt::Display for TrackerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TrackerError::DecodeError(ref e) => write!(f, "bad tracker response: {}", e),
+            TrackerError::IoError(ref e) => write!(f, "error reading tracker response: {}", e),
+            TrackerError::HyperError(ref e) => write!(f, "tracker request failed: {}", e),
+        }
+    }
+}
 
-    let query_string = req.get_query_string();
-    println!("TrackerRequest: {:?}", query_string);
+impl Error for TrackerError {
+    fn description(&self) -> &str {
+        match *self {
+            TrackerError::DecodeError(_) => "bad tracker response",
+            TrackerError::IoError(_) => "error reading tracker response",
+            TrackerError::HyperError(_) => "tracker request failed",
+        }
+    }
+}
 
-    let url = format!("{}?{}", metainfo.announce, query_string);
+// Announces to the torrent's trackers in order (BEP 12), returning the
+// peers from the first one that answers. For private torrents these are
+// the only peers we may use.
+pub fn get_tracker(metainfo: &MetaInfo, peer_id: String, stats: &Announce)
+                   -> Result<Vec<Peer>, TrackerError> {
+    let mut last_err = None;
+    for url in metainfo.trackers() {
+        match announce(&url, metainfo, peer_id.clone(), stats) {
+            Ok(peers) => return Ok(peers),
+            Err(e) => {
+                log!("tracker {} failed: {:?}", url, e);
+                last_err = Some(e);
+            },
+        }
+    }
+    Err(last_err.unwrap_or(TrackerError::DecodeError(format!("torrent has no trackers"))))
+}
 
-    println!("get_tracker, url = {:?}", url);
+// sends GET to tracker, obtaining a list of peers
+fn announce(announce: &str, metainfo: &MetaInfo, peer_id: String, stats: &Announce)
+        -> Result<Vec<Peer>, TrackerError> {
+    let req = TrackerRequest::new(peer_id, LISTEN_PORT, stats.uploaded, stats.downloaded,
+                                  stats.left, metainfo.info_hash.clone(), stats.event);
+
+    let query_string = req.get_query_string();
+    let sep = if announce.contains('?') { "&" } else { "?" };
+    let url = format!("{}{}{}", announce, sep, query_string);
 
     // Create a client.
     let client = Client::new();
@@ -143,17 +201,120 @@ pub fn get_tracker(metainfo: &MetaInfo, peer_id: String) -> Result<Vec<Peer>, Tr
         Err(e) => return Err(TrackerError::DecodeError(e)),
     };
 
-    println!("TrackerResponse = {:?}", resp);
-
     Ok(resp.peers)
 }
 
 
 
+// What a tracker knows about a torrent's swarm
+#[derive(Debug, Clone)]
+pub struct ScrapeInfo {
+    // peers with the whole torrent
+    pub complete: i64,
+
+    // completed downloads the tracker has seen
+    pub downloaded: i64,
+
+    // peers still downloading
+    pub incomplete: i64,
+}
+
+// By convention a tracker's scrape URL is its announce URL with the last
+// path component's "announce" replaced by "scrape". Trackers whose URLs
+// don't look like that don't support scraping.
+pub fn scrape_url(announce: &str) -> Option<String> {
+    let slash = match announce.rfind('/') {
+        Some(i) => i,
+        None => return None,
+    };
+    let (base, last) = announce.split_at(slash + 1);
+    if last.starts_with("announce") {
+        Some(format!("{}scrape{}", base, &last["announce".len()..]))
+    } else {
+        None
+    }
+}
+
+// Asks the torrent's trackers, in order, for swarm statistics
+pub fn scrape(metainfo: &MetaInfo) -> Result<ScrapeInfo, TrackerError> {
+    let mut last_err = None;
+    for announce in metainfo.trackers() {
+        let url = match scrape_url(&announce) {
+            Some(url) => url,
+            None => continue,
+        };
+        match scrape_one(&url, &metainfo.info_hash) {
+            Ok(info) => return Ok(info),
+            Err(e) => {
+                log!("scrape of {} failed: {:?}", url, e);
+                last_err = Some(e);
+            },
+        }
+    }
+    Err(last_err.unwrap_or(TrackerError::DecodeError(format!("no tracker supports scraping"))))
+}
+
+fn scrape_one(url: &str, info_hash: &[u8]) -> Result<ScrapeInfo, TrackerError> {
+    let sep = if url.contains('?') { "&" } else { "?" };
+    let url = format!("{}{}info_hash={}", url, sep,
+                      percent_encode(info_hash, FORM_URLENCODED_ENCODE_SET));
+
+    let client = Client::new();
+    let mut res = match client.get(&url).header(Connection::close()).send() {
+        Ok(res) => res,
+        Err(e) => return Err(TrackerError::HyperError(e)),
+    };
+    let mut body = Vec::new();
+    if let Err(e) = res.read_to_end(&mut body) {
+        return Err(TrackerError::IoError(e));
+    }
+
+    let bencode = match bencode::from_buffer(&body) {
+        Ok(b) => b,
+        Err(e) => return Err(TrackerError::DecodeError(format!("{:?}", e))),
+    };
+    let files = match bencode {
+        Bencode::Dict(ref m) => match m.get(&ByteString::from_str("files")) {
+            Some(&Bencode::Dict(ref files)) => files.clone(),
+            _ => return Err(TrackerError::DecodeError(format!("scrape response has no files"))),
+        },
+        _ => return Err(TrackerError::DecodeError(format!("scrape response isn't a dictionary"))),
+    };
+    let stats = match files.get(&ByteString::from_slice(info_hash)) {
+        Some(&Bencode::Dict(ref stats)) => stats.clone(),
+        _ => return Err(TrackerError::DecodeError(format!("tracker doesn't know the torrent"))),
+    };
+
+    let number = |key: &str| number_field(&stats, key).map(|n| n.unwrap_or(0))
+                                                      .map_err(TrackerError::DecodeError);
+    Ok(ScrapeInfo {
+        complete: try!(number("complete")),
+        downloaded: try!(number("downloaded")),
+        incomplete: try!(number("incomplete")),
+    })
+}
+
+// Fields of a tracker's dictionaries, which are checked rather than
+// unwrapped: whatever a tracker sends mustn't bring the torrent down
+
+fn number_field(map: &BTreeMap<ByteString, Bencode>, key: &str) -> Result<Option<i64>, String> {
+    match util::maybe_get_field(map, key) {
+        Some(Bencode::Number(n)) => Ok(Some(n)),
+        Some(_) => Err(format!("{} isn't a number", key)),
+        None => Ok(None),
+    }
+}
+
+fn string_field(map: &BTreeMap<ByteString, Bencode>, key: &str) -> Result<Option<String>, String> {
+    match util::maybe_get_field(map, key) {
+        Some(Bencode::ByteString(bytes)) => Ok(Some(String::from_utf8_lossy(&bytes).into_owned())),
+        Some(_) => Err(format!("{} isn't a string", key)),
+        None => Ok(None),
+    }
+}
+
 #[derive(Debug)]
 struct TrackerResponse {
-    pub failure_reason: Option<String>,
-
     // seconds a client should wait before sending requests to the tracker
     pub interval: Option<i64>,
 
@@ -173,8 +334,6 @@ struct TrackerResponse {
 
 impl TrackerResponse {
     fn from_bytes(bytes: &[u8]) -> Result<TrackerResponse, String> {
-        println!("TrackerResponse::from_bytes, bytes = {:?}", bytes);
-        util::bytes_try_show_ascii(bytes);
         let bencode = match bencode::from_buffer(&bytes) {
             Ok(b) => b,
             Err(e) => return Err(format!("Error creating Bencoded value from bytes: {:?}", e)),
@@ -182,118 +341,200 @@ impl TrackerResponse {
 
         <TrackerResponse>::from_bencode(&bencode)
     }
+}
 
-    fn parse_peers_bytes(buf: &[u8]) -> Vec<net::SocketAddr>{
-        assert!(buf.len() % 6 == 0);
-        let mut v = Vec::new();
-        for i in 0..(buf.len() / 6) {
-            let ip = net::IpAddr::V4(net::Ipv4Addr::new(buf[i], buf[i+1],
-                                                        buf[i+2], buf[i+3]));
-            let port = ((buf[i+4] as u16) << 8) | (buf[i+5] as u16);
-            v.push(net::SocketAddr::new(ip, port));
+// Parses the compact peer format: 4 bytes of IPv4 address followed by 2
+// bytes of port for each peer. Trailing partial entries are ignored.
+pub fn parse_compact_peers(buf: &[u8]) -> Vec<net::SocketAddr> {
+    let mut v = Vec::new();
+    for c in buf.chunks(6).filter(|c| c.len() == 6) {
+        let ip = net::IpAddr::V4(net::Ipv4Addr::new(c[0], c[1], c[2], c[3]));
+        let port = util::u16_from_be_bytes(&c[4..6]);
+        v.push(net::SocketAddr::new(ip, port));
+    }
+    v
+}
+
+// Same as `parse_compact_peers`, with 16 byte IPv6 addresses
+pub fn parse_compact_peers6(buf: &[u8]) -> Vec<net::SocketAddr> {
+    let mut v = Vec::new();
+    for c in buf.chunks(18).filter(|c| c.len() == 18) {
+        let mut segments = [0u16; 8];
+        for i in 0..8 {
+            segments[i] = util::u16_from_be_bytes(&c[i * 2..i * 2 + 2]);
         }
-        v
+        let ip = net::IpAddr::V6(net::Ipv6Addr::new(segments[0], segments[1],
+                                                    segments[2], segments[3],
+                                                    segments[4], segments[5],
+                                                    segments[6], segments[7]));
+        let port = util::u16_from_be_bytes(&c[16..18]);
+        v.push(net::SocketAddr::new(ip, port));
     }
+    v
+}
+
+// the compact encoding of a single address, 6 or 18 bytes
+pub fn compact_peer(addr: &net::SocketAddr) -> Vec<u8> {
+    let mut v = Vec::new();
+    match addr.ip() {
+        net::IpAddr::V4(ip) => v.extend(ip.octets().iter()),
+        net::IpAddr::V6(ip) => {
+            for seg in ip.segments().iter() {
+                v.extend(util::u16_to_be_bytes(*seg).iter());
+            }
+        },
+    }
+    v.extend(util::u16_to_be_bytes(addr.port()).iter());
+    v
 }
 
 #[derive(Debug, Clone)]
 pub struct Peer {
-    peer_id: Option<String>,
+    peer_id: Option<Vec<u8>>,
     pub addr: net::SocketAddr,
 }
 
 impl Peer {
-    fn from_socketaddr(addr: net::SocketAddr) -> Peer {
+    pub fn from_socketaddr(addr: net::SocketAddr) -> Peer {
         Peer {
             peer_id: None,
             addr: addr,
         }
     }
+
+    // the client this peer is running, if its peer id is known
+    pub fn client(&self) -> Option<peer_id::Client> {
+        self.peer_id.as_ref().and_then(|id| peer_id::parse(id))
+    }
+
+    // record the peer id the peer sent in its handshake
+    pub fn set_peer_id(&mut self, id: &[u8]) {
+        self.peer_id = Some(id.to_vec());
+    }
 }
 
-/*
-impl FromBencode for Peer {
-    type Err = String;
-    fn from_bencode(b: &Bencode) -> Result<Peer, Self::Err> {
-        match *b {
-            Bencode::Dict(ref map) => {
-                let peer_id = util::get_field(map, "peer id");
-                let ip = util::get_field(map, "ip");
-                let port = util::bencode_unwrap_number(
-                               util::get_field(map, "port")
-                           ) as u16;
-                println!("ip: {:?}", ip);
-                //let ip = net::IpAddr::V4(net::Ipv4Addr::new(a, b, c, d));
-                unimplemented!()
-                /*
-                Ok(Peer {
-                    peer_id: util::bencode_string_unwrap_string(peer_id),
-                    addr: net::SocketAddr::new(ip, port),
-                })
-                */
-            },
-            _ => Err(String::from("Cannot convert to Peer, not a dictionary")),
+impl fmt::Display for Peer {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self.peer_id {
+            Some(ref id) => write!(f, "{} ({})", self.addr, peer_id::describe(id)),
+            None => write!(f, "{}", self.addr),
         }
     }
 }
 
-fn bencode_to_vec_rpi_dict(peers: Option<Bencode>) -> Option<Vec<Peer>> {
-    fn vec_bencode_to_rpi(v: Vec<Bencode>) -> Vec<Peer> {
-        v.into_iter()
-         .map(|b| match <Peer>::from_bencode(&b) {
-                  Ok(rpi) => rpi,
-                  Err(e) => return panic!("Error converting to RPI: {:?}", e),
-              }).collect()
+// A peer in the original, non-compact format: a dictionary with its
+// address and, unless we asked for no_peer_id, its peer id
+impl FromBencode for Peer {
+    type Err = String;
+    fn from_bencode(b: &Bencode) -> Result<Peer, Self::Err> {
+        let map = match *b {
+            Bencode::Dict(ref map) => map,
+            _ => return Err(String::from("Peer isn't a dictionary")),
+        };
+        // trackers may also send a DNS name, which we don't look up
+        let ip = match try!(string_field(map, "ip")) {
+            Some(ip) => match ip.parse::<net::IpAddr>() {
+                Ok(ip) => ip,
+                Err(_) => return Err(format!("Peer has a bad address: {:?}", ip)),
+            },
+            None => return Err(String::from("Peer has no address")),
+        };
+        let port = match try!(number_field(map, "port")) {
+            Some(port) if port > 0 && port <= 0xffff => port as u16,
+            _ => return Err(String::from("Peer has no valid port")),
+        };
+        let peer_id = match util::maybe_get_field(map, "peer id") {
+            Some(Bencode::ByteString(ref id)) if id.len() == 20 => Some(id.clone()),
+            _ => None,
+        };
+        Ok(Peer {
+            peer_id: peer_id,
+            addr: net::SocketAddr::new(ip, port),
+        })
     }
-    // looking for a list of dictionaries
-    let peers_vec = peers.map(|b| util::bencode_unwrap_list(b));
-
-    peers_vec.map(|v| vec_bencode_to_rpi(v))
 }
-*/
 
 impl FromBencode for TrackerResponse {
     type Err = String;
     fn from_bencode(b: &Bencode) -> Result<TrackerResponse, Self::Err> {
-        match *b {
-            Bencode::Dict(ref map) => {
-                let failure_reason = util::maybe_get_field(map, "failure reason");
-                let interval = util::maybe_get_field(map, "interval");
-                let tracker_id = util::maybe_get_field(map, "tracker id");
-                let complete = util::maybe_get_field(map, "complete");
-                let incomplete = util::maybe_get_field(map, "incomplete");
-                let peers = util::maybe_get_field(map, "peers");
-
-                println!("failure reason = {:?},\n\
-                          interval = {:?},\n\
-                          tracker id = {:?},\n\
-                          complete = {:?},\n\
-                          incomplete = {:?},\n\
-                          peers = {:?}",
-                          failure_reason,
-                          //failure_reason.map(|b| String::from_utf8(b.as_slice())),
-                          interval, tracker_id,
-                          complete, incomplete, peers);
-
-                println!("------------------------------");
-
-                let vec_peers = peers.map(|b| {
-                    let v = util::bencode_string_unwrap_bytes(b);
-                    let addrs = TrackerResponse::parse_peers_bytes(&v);
-                    addrs.into_iter().map(|addr| Peer::from_socketaddr(addr)).collect::<Vec<_>>()
-                });
-
-                let resp = TrackerResponse {
-                    failure_reason: failure_reason.map(|b| util::bencode_string_unwrap_string(b)),
-                    interval: interval.map(|b| util::bencode_unwrap_number(b)),
-                    tracker_id: tracker_id.map(|b| util::bencode_string_unwrap_string(b)),
-                    complete: complete.map(|b| util::bencode_unwrap_number(b)),
-                    incomplete: incomplete.map(|b| util::bencode_unwrap_number(b)),
-                    peers: vec_peers.unwrap_or(Vec::new()),
-                };
-                Ok(resp)
-            },
-            _ => Err(String::from("Bencoded value is not a dictionary.")),
+        let map = match *b {
+            Bencode::Dict(ref map) => map,
+            _ => return Err(String::from("Bencoded value is not a dictionary.")),
+        };
+        if let Some(reason) = try!(string_field(map, "failure reason")) {
+            return Err(format!("tracker refused the announce: {}", reason));
         }
+
+        // compact responses pack the peers into one string; the original
+        // format is a list of dictionaries, of which we keep the good ones
+        let peers = match util::maybe_get_field(map, "peers") {
+            Some(Bencode::ByteString(ref v)) => {
+                parse_compact_peers(v).into_iter().map(Peer::from_socketaddr).collect()
+            },
+            Some(Bencode::List(ref v)) => {
+                v.iter().filter_map(|b| match Peer::from_bencode(b) {
+                    Ok(peer) => Some(peer),
+                    Err(e) => {
+                        log!("ignoring peer from tracker: {}", e);
+                        None
+                    },
+                }).collect()
+            },
+            Some(_) => return Err(String::from("peers isn't a string or a list")),
+            None => Vec::new(),
+        };
+
+        Ok(TrackerResponse {
+            interval: try!(number_field(map, "interval")),
+            tracker_id: try!(string_field(map, "tracker id")),
+            complete: try!(number_field(map, "complete")),
+            incomplete: try!(number_field(map, "incomplete")),
+            peers: peers,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TrackerResponse;
+
+    use std::net::SocketAddr;
+
+    fn addrs(resp: &TrackerResponse) -> Vec<SocketAddr> {
+        resp.peers.iter().map(|p| p.addr).collect()
+    }
+
+    #[test]
+    fn compact_peers() {
+        let resp = TrackerResponse::from_bytes(
+            b"d8:intervali1800e5:peers12:\x0a\x00\x00\x01\x1a\xe1\x0a\x00\x00\x02\x00\x50e")
+            .unwrap();
+        assert_eq!(resp.interval, Some(1800));
+        assert_eq!(addrs(&resp), vec!["10.0.0.1:6881".parse().unwrap(),
+                                      "10.0.0.2:80".parse().unwrap()]);
+    }
+
+    #[test]
+    fn dictionary_peers() {
+        let resp = TrackerResponse::from_bytes(
+            concat!("d5:peersl",
+                    "d2:ip8:10.0.0.17:peer id20:-DE0001-0123456789ab4:porti6881ee",
+                    "d2:ip3:::14:porti80ee",
+                    "d2:ip4:nope4:porti1ee",
+                    "d2:ip8:10.0.0.3e",
+                    "ee").as_bytes())
+            .unwrap();
+        assert_eq!(addrs(&resp), vec!["10.0.0.1:6881".parse().unwrap(),
+                                      "[::1]:80".parse().unwrap()]);
+        assert!(resp.peers[0].client().is_some());
+    }
+
+    #[test]
+    fn bad_responses_are_errors() {
+        assert!(TrackerResponse::from_bytes(b"d8:intervalli1eee").is_err());
+        assert!(TrackerResponse::from_bytes(b"d5:peersi3ee").is_err());
+        assert!(TrackerResponse::from_bytes(b"d14:failure reason4:nopee").is_err());
+        assert!(TrackerResponse::from_bytes(b"li1ee").is_err());
+        assert!(TrackerResponse::from_bytes(b"d8:intervali").is_err());
     }
 }
@@ -0,0 +1,80 @@
+use utp::UtpStream;
+
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::time::Duration;
+
+// How long a peer gets to answer a uTP SYN before we try TCP. Most peers
+// that speak uTP answer well within this, and the ones that don't only
+// lose this much time.
+const UTP_CONNECT_TIMEOUT_MS: u64 = 1000;
+
+// The connection under a peer session, so that it can run over TCP or uTP
+pub enum Transport {
+    Tcp(TcpStream),
+    Utp(UtpStream),
+}
+
+impl Transport {
+    // Tries uTP first, since it backs off when the network is busy, then
+    // falls back to TCP if the peer doesn't answer in time.
+    pub fn connect(addr: SocketAddr) -> io::Result<Transport> {
+        let timeout = Duration::from_millis(UTP_CONNECT_TIMEOUT_MS);
+        match UtpStream::connect_timeout(addr, timeout) {
+            Ok(stream) => Ok(Transport::Utp(stream)),
+            Err(e) => {
+                log!("uTP connection to {:?} failed, trying TCP: {:?}", addr, e);
+                TcpStream::connect(addr).map(Transport::Tcp)
+            },
+        }
+    }
+
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        match *self {
+            Transport::Tcp(ref s) => s.set_read_timeout(timeout),
+            Transport::Utp(ref mut s) => {
+                s.set_read_timeout(timeout);
+                Ok(())
+            },
+        }
+    }
+
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        match *self {
+            Transport::Tcp(ref s) => s.peer_addr(),
+            Transport::Utp(ref s) => Ok(s.peer_addr()),
+        }
+    }
+
+    pub fn is_utp(&self) -> bool {
+        match *self {
+            Transport::Utp(_) => true,
+            Transport::Tcp(_) => false,
+        }
+    }
+}
+
+impl Read for Transport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Transport::Tcp(ref mut s) => s.read(buf),
+            Transport::Utp(ref mut s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Transport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Transport::Tcp(ref mut s) => s.write(buf),
+            Transport::Utp(ref mut s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Transport::Tcp(ref mut s) => s.flush(),
+            Transport::Utp(ref mut s) => s.flush(),
+        }
+    }
+}
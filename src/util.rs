@@ -1,43 +1,43 @@
 use bencode::{self, Bencode};
+use bencode::streaming::{BencodeEvent, StreamingParser};
 use std::collections::BTreeMap;
 
-pub fn bencode_unwrap_number(b: Bencode) -> i64 {
+pub fn bencode_unwrap_number(b: Bencode) -> Result<i64, String> {
     match b {
-        Bencode::Number(x) => x,
-        _ => panic!("Failed to unwrap, Bencoded value is not a Number."),
+        Bencode::Number(x) => Ok(x),
+        _ => Err(format!("Bencoded value is not a Number.")),
     }
 }
 
-pub fn bencode_unwrap_list(b: Bencode) -> Vec<Bencode> {
+pub fn bencode_unwrap_list(b: Bencode) -> Result<Vec<Bencode>, String> {
     match b {
-        Bencode::List(v) => v,
-        _ => panic!("Failed to unwrap, Bencoded value is not a List."),
+        Bencode::List(v) => Ok(v),
+        _ => Err(format!("Bencoded value is not a List.")),
     }
 }
 
-pub fn bencode_string_unwrap_bytes(b: Bencode) -> Vec<u8> {
+pub fn bencode_string_unwrap_bytes(b: Bencode) -> Result<Vec<u8>, String> {
     match b {
-        Bencode::ByteString(v) => v,
-        _ => panic!("Failed to unwrap, Bencoded value is not a ByteString."),
+        Bencode::ByteString(v) => Ok(v),
+        _ => Err(format!("Bencoded value is not a ByteString.")),
     }
 }
 
-pub fn bencode_string_unwrap_string(b: Bencode) -> String {
-    let bytes = bencode_string_unwrap_bytes(b);
+pub fn bencode_string_unwrap_string(b: Bencode) -> Result<String, String> {
+    let bytes = try!(bencode_string_unwrap_bytes(b));
     match String::from_utf8(bytes) {
-        Ok(s) => s,
-        Err(e) => panic!("Could not unwrap ByteString to String: {:?}", e),
+        Ok(s) => Ok(s),
+        Err(e) => Err(format!("Could not unwrap ByteString to String: {:?}", e)),
     }
 }
 
-
-// panics if key isn't found
 pub fn get_field(map: &BTreeMap<bencode::util::ByteString, Bencode>,
              key: &str)
-        -> Bencode {
-    map.get(&bencode::util::ByteString::from_str(key))
-       .unwrap()
-       .clone()
+        -> Result<Bencode, String> {
+    match map.get(&bencode::util::ByteString::from_str(key)) {
+        Some(b) => Ok(b.clone()),
+        None => Err(format!("Missing field: {}", key)),
+    }
 }
 
 pub fn maybe_get_field(map: &BTreeMap<bencode::util::ByteString, Bencode>,
@@ -47,10 +47,94 @@ pub fn maybe_get_field(map: &BTreeMap<bencode::util::ByteString, Bencode>,
        .map(|b| b.clone())
 }
 
+// bencode::from_buffer panics when a list or dict is still open at the end
+// of the buffer, so check that they all close before decoding
+pub fn decode(buf: &[u8]) -> Result<Bencode, bencode::streaming::Error> {
+    let mut depth = 0;
+    for event in StreamingParser::new(buf.iter().map(|b| *b)) {
+        match event {
+            BencodeEvent::ListStart | BencodeEvent::DictStart => depth += 1,
+            BencodeEvent::ListEnd | BencodeEvent::DictEnd => depth -= 1,
+            BencodeEvent::ParseError(e) => return Err(e),
+            _ => {},
+        }
+    }
+    if depth != 0 {
+        return Err(bencode::streaming::Error {
+            pos: buf.len() as u32,
+            msg: format!("Unexpected end of input"),
+        });
+    }
+    bencode::from_buffer(buf)
+}
+
+// builds a dictionary from (key, value) pairs
+pub fn bencode_dict(pairs: Vec<(&str, Bencode)>) -> Bencode {
+    let mut map = BTreeMap::new();
+    for (k, v) in pairs.into_iter() {
+        map.insert(bencode::util::ByteString::from_str(k), v);
+    }
+    Bencode::Dict(map)
+}
+
+// big-endian integers, as used throughout the peer wire protocol
+pub fn u32_from_be_bytes(buf: &[u8]) -> u32 {
+    ((buf[0] as u32) << 24) | ((buf[1] as u32) << 16) |
+    ((buf[2] as u32) << 8) | (buf[3] as u32)
+}
+
+pub fn u16_from_be_bytes(buf: &[u8]) -> u16 {
+    ((buf[0] as u16) << 8) | (buf[1] as u16)
+}
+
+pub fn u32_to_be_bytes(x: u32) -> [u8; 4] {
+    [(x >> 24) as u8, (x >> 16) as u8, (x >> 8) as u8, x as u8]
+}
+
+pub fn u16_to_be_bytes(x: u16) -> [u8; 2] {
+    [(x >> 8) as u8, x as u8]
+}
 
-pub fn bytes_try_show_ascii(buf: &[u8]) {
-    for &b in buf.iter() {
-        print!("{}", b as char);
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect::<Vec<_>>().concat()
+}
+
+// Copies as much of `src` as fits into `dst`
+pub fn copy_into(dst: &mut [u8], src: &[u8]) {
+    for (d, s) in dst.iter_mut().zip(src.iter()) {
+        *d = *s;
     }
-    println!("");
+}
+
+// An HTTP server for tests: answers one connection per response. Returns
+// the URL of `path` on it, and a thread that gives back the head of each
+// request, a line at a time.
+#[cfg(test)]
+pub fn serve_http(path: &str, responses: Vec<&'static str>)
+        -> (String, ::std::thread::JoinHandle<Vec<Vec<String>>>) {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}{}", listener.local_addr().unwrap(), path);
+    let server = thread::spawn(move || {
+        let mut requests = Vec::new();
+        for response in responses {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut head = Vec::new();
+            {
+                let mut reader = BufReader::new(&mut stream);
+                let mut line = String::new();
+                while reader.read_line(&mut line).unwrap() > 0 && line != "\r\n" {
+                    head.push(line.trim().to_string());
+                    line.clear();
+                }
+            }
+            stream.write_all(response.as_bytes()).unwrap();
+            requests.push(head);
+        }
+        requests
+    });
+    (url, server)
 }
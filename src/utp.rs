@@ -0,0 +1,1004 @@
+// Micro Transport Protocol (uTP, BEP 29): a reliable, ordered stream over
+// UDP that uses LEDBAT congestion control to get out of the way of other
+// traffic. Each outgoing stream gets its own UDP socket and a background
+// thread that handles acks and retransmissions; incoming streams share the
+// listener's socket and thread.
+
+use util;
+
+use rand::{self, Rng};
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, Read, Write};
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::thread;
+use std::time::Duration;
+use time;
+
+const VERSION: u8 = 1;
+
+const ST_DATA: u8 = 0;
+const ST_FIN: u8 = 1;
+const ST_STATE: u8 = 2;
+const ST_RESET: u8 = 3;
+const ST_SYN: u8 = 4;
+
+const EXT_SELECTIVE_ACK: u8 = 1;
+
+const HEADER_LEN: usize = 20;
+
+// keeps packets under a typical MTU once IP and UDP headers are added
+const MAX_PAYLOAD: usize = 1400 - HEADER_LEN;
+
+// LEDBAT: the queuing delay we aim for, and how fast we grow towards it
+const TARGET_DELAY_US: i64 = 100000;
+const MAX_CWND_INCREASE_BYTES_PER_RTT: i64 = 3000;
+const MIN_WINDOW: u32 = MAX_PAYLOAD as u32;
+const INITIAL_WINDOW: u32 = 2 * MAX_PAYLOAD as u32;
+
+// how long a base delay measurement is trusted for
+const BASE_DELAY_WINDOW_US: u64 = 2 * 60 * 1000000;
+
+const INITIAL_TIMEOUT_MS: u64 = 1000;
+const MIN_TIMEOUT_MS: u64 = 500;
+const MAX_TRANSMISSIONS: u32 = 5;
+
+// packets selectively acked after a missing one before we resend it
+const DUPLICATE_ACKS_BEFORE_RESEND: u32 = 3;
+
+// bytes we'll buffer for the reader before advertising a zero window
+const RECV_BUFFER_SIZE: usize = 1 << 20;
+
+// out of order packets we'll hold on to
+const MAX_REORDER: usize = 512;
+
+const CONNECT_TIMEOUT_MS: u64 = 3000;
+
+// how often the background thread wakes up to check timers
+const TICK_MS: u64 = 50;
+
+fn now_us() -> u64 {
+    time::precise_time_ns() / 1000
+}
+
+// whether a comes before b, allowing for wraparound
+fn seq_lt(a: u16, b: u16) -> bool {
+    a != b && b.wrapping_sub(a) < 0x8000
+}
+
+#[derive(Debug, Clone)]
+struct Packet {
+    ty: u8,
+    conn_id: u16,
+    timestamp_us: u32,
+    timestamp_diff_us: u32,
+    wnd_size: u32,
+    seq_nr: u16,
+    ack_nr: u16,
+
+    // selective ack bitmask: bit i means ack_nr + 2 + i was received
+    sack: Option<Vec<u8>>,
+
+    payload: Vec<u8>,
+}
+
+impl Packet {
+    fn encode(&self) -> Vec<u8> {
+        let mut v = Vec::with_capacity(HEADER_LEN + self.payload.len());
+        v.push((self.ty << 4) | VERSION);
+        v.push(if self.sack.is_some() { EXT_SELECTIVE_ACK } else { 0 });
+        v.extend(util::u16_to_be_bytes(self.conn_id).iter());
+        v.extend(util::u32_to_be_bytes(self.timestamp_us).iter());
+        v.extend(util::u32_to_be_bytes(self.timestamp_diff_us).iter());
+        v.extend(util::u32_to_be_bytes(self.wnd_size).iter());
+        v.extend(util::u16_to_be_bytes(self.seq_nr).iter());
+        v.extend(util::u16_to_be_bytes(self.ack_nr).iter());
+        if let Some(ref mask) = self.sack {
+            v.push(0);
+            v.push(mask.len() as u8);
+            v.extend(mask.iter());
+        }
+        v.extend(self.payload.iter());
+        v
+    }
+
+    fn decode(buf: &[u8]) -> Result<Packet, String> {
+        if buf.len() < HEADER_LEN {
+            return Err(format!("uTP packet too short: {} bytes", buf.len()));
+        }
+        if buf[0] & 0x0f != VERSION {
+            return Err(format!("Unknown uTP version {}", buf[0] & 0x0f));
+        }
+        let ty = buf[0] >> 4;
+        if ty > ST_SYN {
+            return Err(format!("Unknown uTP packet type {}", ty));
+        }
+
+        // walk the extension chain, keeping only selective acks
+        let mut sack = None;
+        let mut ext = buf[1];
+        let mut i = HEADER_LEN;
+        while ext != 0 {
+            if i + 2 > buf.len() {
+                return Err(String::from("Truncated uTP extension"));
+            }
+            let next = buf[i];
+            let len = buf[i + 1] as usize;
+            if i + 2 + len > buf.len() {
+                return Err(String::from("Truncated uTP extension"));
+            }
+            if ext == EXT_SELECTIVE_ACK {
+                sack = Some(buf[i + 2..i + 2 + len].to_vec());
+            }
+            ext = next;
+            i += 2 + len;
+        }
+
+        Ok(Packet {
+            ty: ty,
+            conn_id: util::u16_from_be_bytes(&buf[2..4]),
+            timestamp_us: util::u32_from_be_bytes(&buf[4..8]),
+            timestamp_diff_us: util::u32_from_be_bytes(&buf[8..12]),
+            wnd_size: util::u32_from_be_bytes(&buf[12..16]),
+            seq_nr: util::u16_from_be_bytes(&buf[16..18]),
+            ack_nr: util::u16_from_be_bytes(&buf[18..20]),
+            sack: sack,
+            payload: buf[i..].to_vec(),
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum State {
+    SynSent,
+    Connected,
+    // we sent a FIN and are waiting for everything to be acked
+    FinSent,
+    Closed,
+}
+
+// a packet we've sent that hasn't been acked
+struct SentPacket {
+    ty: u8,
+    seq_nr: u16,
+    payload: Vec<u8>,
+    sent_at_us: u64,
+    transmissions: u32,
+
+    // number of later packets selectively acked while this one wasn't
+    skipped: u32,
+}
+
+struct Connection {
+    socket: UdpSocket,
+    peer: SocketAddr,
+    state: State,
+
+    // we receive packets with recv_id and send them with send_id
+    recv_id: u16,
+    send_id: u16,
+
+    // next sequence number to send
+    seq_nr: u16,
+
+    // last sequence number received in order
+    ack_nr: u16,
+
+    unacked: VecDeque<SentPacket>,
+
+    // packets received ahead of a gap, by sequence number
+    reorder: HashMap<u16, Vec<u8>>,
+
+    // data ready for the reader
+    incoming: VecDeque<u8>,
+
+    // the peer's FIN sequence number, once seen
+    fin_nr: Option<u16>,
+    eof: bool,
+    error: Option<io::ErrorKind>,
+
+    // congestion control, in bytes
+    max_window: u32,
+    their_window: u32,
+    bytes_in_flight: u32,
+
+    // delay between the peer sending its last packet and us receiving it,
+    // echoed back in timestamp_diff_us
+    reply_micro: u32,
+
+    // lowest one-way delay seen recently, and when that window started
+    base_delay_us: Option<u32>,
+    base_delay_since: u64,
+
+    // round trip estimates, in milliseconds
+    rtt_ms: u64,
+    rtt_var_ms: u64,
+    timeout_ms: u64,
+
+    // when we last halved the window, so one loss event only counts once
+    last_decay_us: u64,
+}
+
+impl Connection {
+    fn new(socket: UdpSocket, peer: SocketAddr) -> Connection {
+        let recv_id: u16 = rand::thread_rng().gen();
+        Connection {
+            socket: socket,
+            peer: peer,
+            state: State::SynSent,
+            recv_id: recv_id,
+            send_id: recv_id.wrapping_add(1),
+            seq_nr: 1,
+            ack_nr: 0,
+            unacked: VecDeque::new(),
+            reorder: HashMap::new(),
+            incoming: VecDeque::new(),
+            fin_nr: None,
+            eof: false,
+            error: None,
+            max_window: INITIAL_WINDOW,
+            their_window: INITIAL_WINDOW,
+            bytes_in_flight: 0,
+            reply_micro: 0,
+            base_delay_us: None,
+            base_delay_since: now_us(),
+            rtt_ms: 0,
+            rtt_var_ms: 0,
+            timeout_ms: INITIAL_TIMEOUT_MS,
+            last_decay_us: 0,
+        }
+    }
+
+    // The other end of a connection `syn` asked for. It picked the ids: we
+    // answer on the one it sent with and take the next one for ourselves.
+    fn accepted(socket: UdpSocket, peer: SocketAddr, syn: &Packet) -> Connection {
+        let mut conn = Connection::new(socket, peer);
+        conn.state = State::Connected;
+        conn.recv_id = syn.conn_id.wrapping_add(1);
+        conn.send_id = syn.conn_id;
+        conn.seq_nr = rand::thread_rng().gen();
+        conn.ack_nr = syn.seq_nr;
+        conn.their_window = syn.wnd_size;
+        conn.reply_micro = (now_us() as u32).wrapping_sub(syn.timestamp_us);
+        conn
+    }
+
+    fn recv_window(&self) -> u32 {
+        RECV_BUFFER_SIZE.saturating_sub(self.incoming.len()) as u32
+    }
+
+    fn packet(&self, ty: u8, seq_nr: u16, payload: Vec<u8>) -> Packet {
+        Packet {
+            ty: ty,
+            // the SYN is the one packet sent with our receive id
+            conn_id: if ty == ST_SYN { self.recv_id } else { self.send_id },
+            timestamp_us: now_us() as u32,
+            timestamp_diff_us: self.reply_micro,
+            wnd_size: self.recv_window(),
+            seq_nr: seq_nr,
+            ack_nr: self.ack_nr,
+            sack: None,
+            payload: payload,
+        }
+    }
+
+    fn send_packet(&self, p: &Packet) {
+        // losses are dealt with by retransmission
+        let _ = self.socket.send_to(&p.encode(), self.peer);
+    }
+
+    // Sends a packet that takes up a sequence number and must be acked
+    fn send_reliable(&mut self, ty: u8, payload: Vec<u8>) {
+        let seq_nr = self.seq_nr;
+        self.seq_nr = self.seq_nr.wrapping_add(1);
+
+        let p = self.packet(ty, seq_nr, payload.clone());
+        self.send_packet(&p);
+
+        self.bytes_in_flight += payload.len() as u32;
+        self.unacked.push_back(SentPacket {
+            ty: ty,
+            seq_nr: seq_nr,
+            payload: payload,
+            sent_at_us: now_us(),
+            transmissions: 1,
+            skipped: 0,
+        });
+    }
+
+    fn resend(&mut self, i: usize) {
+        let p = {
+            let sp = &self.unacked[i];
+            self.packet(sp.ty, sp.seq_nr, sp.payload.clone())
+        };
+        self.send_packet(&p);
+        let sp = &mut self.unacked[i];
+        sp.sent_at_us = now_us();
+        sp.transmissions += 1;
+        sp.skipped = 0;
+    }
+
+    // Acks what we've received, with a selective ack for anything held
+    // past a gap
+    fn send_ack(&self) {
+        let mut p = self.packet(ST_STATE, self.seq_nr, Vec::new());
+        if !self.reorder.is_empty() {
+            let mut mask = vec![0u8; 4];
+            for &seq in self.reorder.keys() {
+                let bit = seq.wrapping_sub(self.ack_nr).wrapping_sub(2) as usize;
+                if bit >= mask.len() * 8 {
+                    if bit >= 32 * 8 {
+                        continue;
+                    }
+                    let len = (bit / 32 + 1) * 4;
+                    mask.resize(len, 0);
+                }
+                mask[bit / 8] |= 1 << (bit % 8);
+            }
+            p.sack = Some(mask);
+        }
+        self.send_packet(&p);
+    }
+
+    fn send_reset(&self) {
+        let p = self.packet(ST_RESET, self.seq_nr, Vec::new());
+        self.send_packet(&p);
+    }
+
+    fn window(&self) -> u32 {
+        cmp::min(self.max_window, self.their_window)
+    }
+
+    // how many payload bytes we may send right now
+    fn send_allowance(&self) -> usize {
+        let window = self.window();
+        if self.bytes_in_flight >= window {
+            // always allow one packet so a zero window can't stall us forever
+            if self.bytes_in_flight == 0 { MAX_PAYLOAD } else { 0 }
+        } else {
+            (window - self.bytes_in_flight) as usize
+        }
+    }
+
+    fn update_rtt(&mut self, sample_ms: u64) {
+        if self.rtt_ms == 0 {
+            self.rtt_ms = sample_ms;
+            self.rtt_var_ms = sample_ms / 2;
+        } else {
+            let delta = if self.rtt_ms > sample_ms {
+                self.rtt_ms - sample_ms
+            } else {
+                sample_ms - self.rtt_ms
+            };
+            self.rtt_var_ms = (3 * self.rtt_var_ms + delta) / 4;
+            self.rtt_ms = (7 * self.rtt_ms + sample_ms) / 8;
+        }
+        self.timeout_ms = cmp::max(self.rtt_ms + 4 * self.rtt_var_ms, MIN_TIMEOUT_MS);
+    }
+
+    // LEDBAT: grow or shrink the window in proportion to how far the
+    // queuing delay is from the target
+    fn update_window(&mut self, their_delay_us: u32, acked_bytes: u32) {
+        if acked_bytes == 0 || their_delay_us == 0 {
+            return;
+        }
+
+        let now = now_us();
+        if now - self.base_delay_since > BASE_DELAY_WINDOW_US {
+            self.base_delay_us = None;
+            self.base_delay_since = now;
+        }
+        let base = match self.base_delay_us {
+            Some(base) if base <= their_delay_us => base,
+            _ => {
+                self.base_delay_us = Some(their_delay_us);
+                their_delay_us
+            },
+        };
+
+        let queuing_delay = (their_delay_us - base) as i64;
+        let off_target = TARGET_DELAY_US - queuing_delay;
+        let window_factor = acked_bytes as i64 * 1000 / cmp::max(self.max_window, 1) as i64;
+        let gain = MAX_CWND_INCREASE_BYTES_PER_RTT * off_target / TARGET_DELAY_US
+                   * window_factor / 1000;
+
+        let window = self.max_window as i64 + gain;
+        self.max_window = cmp::max(window, MIN_WINDOW as i64) as u32;
+    }
+
+    // halve the window, at most once per round trip
+    fn on_loss(&mut self) {
+        let now = now_us();
+        if now - self.last_decay_us > self.rtt_ms * 1000 {
+            self.max_window = cmp::max(self.max_window / 2, MIN_WINDOW);
+            self.last_decay_us = now;
+        }
+    }
+
+    fn remove_acked(&mut self, i: usize, acked_bytes: &mut u32) {
+        let sp = self.unacked.remove(i).unwrap();
+        self.bytes_in_flight -= sp.payload.len() as u32;
+        *acked_bytes += sp.payload.len() as u32;
+        // Karn's algorithm: only time packets that weren't resent
+        if sp.transmissions == 1 {
+            let sample = (now_us() - sp.sent_at_us) / 1000;
+            self.update_rtt(sample);
+        }
+    }
+
+    fn process_acks(&mut self, p: &Packet) {
+        let mut acked_bytes = 0;
+
+        // cumulative ack
+        while !self.unacked.is_empty() && !seq_lt(p.ack_nr, self.unacked[0].seq_nr) {
+            self.remove_acked(0, &mut acked_bytes);
+        }
+
+        if let Some(ref mask) = p.sack {
+            let mut highest_sacked = None;
+            for bit in 0..mask.len() * 8 {
+                if mask[bit / 8] & (1 << (bit % 8)) == 0 {
+                    continue;
+                }
+                let seq = p.ack_nr.wrapping_add(2).wrapping_add(bit as u16);
+                highest_sacked = Some(seq);
+                if let Some(i) = self.unacked.iter().position(|sp| sp.seq_nr == seq) {
+                    self.remove_acked(i, &mut acked_bytes);
+                }
+            }
+
+            // anything still unacked before the highest selectively acked
+            // packet has been skipped over
+            if let Some(highest) = highest_sacked {
+                let mut lost = Vec::new();
+                for (i, sp) in self.unacked.iter_mut().enumerate() {
+                    if seq_lt(sp.seq_nr, highest) {
+                        sp.skipped += 1;
+                        if sp.skipped == DUPLICATE_ACKS_BEFORE_RESEND {
+                            lost.push(i);
+                        }
+                    }
+                }
+                if !lost.is_empty() {
+                    self.on_loss();
+                }
+                for i in lost {
+                    self.resend(i);
+                }
+            }
+        }
+
+        self.update_window(p.timestamp_diff_us, acked_bytes);
+    }
+
+    fn deliver(&mut self, payload: Vec<u8>) {
+        self.incoming.extend(payload.into_iter());
+        self.ack_nr = self.ack_nr.wrapping_add(1);
+
+        loop {
+            let next = self.ack_nr.wrapping_add(1);
+            match self.reorder.remove(&next) {
+                Some(payload) => {
+                    self.incoming.extend(payload.into_iter());
+                    self.ack_nr = next;
+                },
+                None => break,
+            }
+        }
+
+        if let Some(fin) = self.fin_nr {
+            if self.ack_nr == fin.wrapping_sub(1) {
+                self.ack_nr = fin;
+                self.eof = true;
+            }
+        }
+    }
+
+    fn on_packet(&mut self, p: Packet) {
+        if p.conn_id != self.recv_id {
+            return;
+        }
+
+        self.reply_micro = (now_us() as u32).wrapping_sub(p.timestamp_us);
+        self.their_window = p.wnd_size;
+
+        if p.ty == ST_RESET {
+            self.state = State::Closed;
+            self.error = Some(io::ErrorKind::ConnectionReset);
+            return;
+        }
+
+        if self.state == State::SynSent {
+            if p.ty != ST_STATE {
+                return;
+            }
+            // the peer's first data packet will reuse this sequence number
+            self.ack_nr = p.seq_nr.wrapping_sub(1);
+            self.state = State::Connected;
+        }
+
+        self.process_acks(&p);
+
+        match p.ty {
+            ST_DATA => {
+                let expected = self.ack_nr.wrapping_add(1);
+                if p.seq_nr == expected {
+                    self.deliver(p.payload);
+                } else if seq_lt(expected, p.seq_nr) && self.reorder.len() < MAX_REORDER {
+                    self.reorder.insert(p.seq_nr, p.payload);
+                }
+                self.send_ack();
+            },
+            ST_FIN => {
+                self.fin_nr = Some(p.seq_nr);
+                if p.seq_nr == self.ack_nr.wrapping_add(1) {
+                    self.ack_nr = p.seq_nr;
+                    self.eof = true;
+                }
+                self.send_ack();
+            },
+            _ => {},
+        }
+
+        if self.state == State::FinSent && self.unacked.is_empty() {
+            self.state = State::Closed;
+        }
+    }
+
+    fn check_timeouts(&mut self) {
+        if self.unacked.is_empty() {
+            return;
+        }
+
+        let now = now_us();
+        if now - self.unacked[0].sent_at_us < self.timeout_ms * 1000 {
+            return;
+        }
+
+        if self.unacked[0].transmissions >= MAX_TRANSMISSIONS {
+            self.state = State::Closed;
+            self.error = Some(io::ErrorKind::TimedOut);
+            return;
+        }
+
+        // a timeout means the path is badly congested: start over
+        self.max_window = MIN_WINDOW;
+        self.last_decay_us = now;
+        self.timeout_ms = cmp::min(self.timeout_ms * 2, 60000);
+        self.resend(0);
+    }
+}
+
+type Shared = Arc<(Mutex<Connection>, Condvar)>;
+
+// Hands a packet, if one came in, to a connection and checks its timers.
+// Returns whether the connection is finished with.
+fn service(shared: &Shared, packet: Option<Packet>) -> bool {
+    let &(ref lock, ref cvar) = &**shared;
+    let mut conn = lock.lock().unwrap();
+    if let Some(p) = packet {
+        conn.on_packet(p);
+    }
+    conn.check_timeouts();
+    cvar.notify_all();
+
+    if conn.state == State::Closed {
+        return true;
+    }
+    // the stream was dropped without a FIN getting through
+    if Arc::strong_count(shared) == 1 && conn.state != State::FinSent {
+        conn.state = State::Closed;
+        return true;
+    }
+    false
+}
+
+// Runs an outgoing stream, which has its socket to itself
+fn run_background(shared: Shared, socket: UdpSocket, peer: SocketAddr) {
+    let mut buf = [0; 65536];
+    loop {
+        let packet = match socket.recv_from(&mut buf) {
+            Ok((n, from)) if from == peer => Packet::decode(&buf[..n]).ok(),
+            _ => None,
+        };
+        if service(&shared, packet) {
+            return;
+        }
+    }
+}
+
+// Runs a listener's socket: answers SYNs, passing the new streams on to
+// `accepted`, and routes everything else to its stream by address and id.
+// Once `stopped` is set it takes no new streams, and returns, closing the
+// socket, when the ones it has are finished.
+fn run_listener(socket: UdpSocket, accepted: Sender<UtpStream>, stopped: Arc<AtomicBool>) {
+    let mut conns: HashMap<(SocketAddr, u16), Shared> = HashMap::new();
+    let mut accepting = true;
+    let mut last_tick = now_us();
+    let mut buf = [0; 65536];
+    while accepting || !conns.is_empty() {
+        if stopped.load(Ordering::SeqCst) {
+            accepting = false;
+        }
+        if let Ok((n, from)) = socket.recv_from(&mut buf) {
+            if let Ok(p) = Packet::decode(&buf[..n]) {
+                if p.ty == ST_SYN {
+                    let key = (from, p.conn_id.wrapping_add(1));
+                    if let Some(shared) = conns.get(&key) {
+                        // our answer to the first SYN got lost
+                        shared.0.lock().unwrap().send_ack();
+                    }
+                    if accepting && !conns.contains_key(&key) {
+                        match socket.try_clone() {
+                            Ok(socket) => {
+                                let conn = Connection::accepted(socket, from, &p);
+                                conn.send_ack();
+                                let shared = Arc::new((Mutex::new(conn), Condvar::new()));
+                                let stream = UtpStream {
+                                    shared: shared.clone(),
+                                    peer_addr: from,
+                                    read_timeout: None,
+                                };
+                                if accepted.send(stream).is_ok() {
+                                    conns.insert(key, shared);
+                                } else {
+                                    // the listener's gone
+                                    accepting = false;
+                                }
+                            },
+                            Err(e) => log!("couldn't accept uTP connection from {:?}: {:?}",
+                                           from, e),
+                        }
+                    }
+                } else {
+                    let key = (from, p.conn_id);
+                    let finished = match conns.get(&key) {
+                        Some(shared) => service(shared, Some(p)),
+                        None => false,
+                    };
+                    if finished {
+                        conns.remove(&key);
+                    }
+                }
+            }
+        }
+
+        if now_us() - last_tick >= TICK_MS * 1000 {
+            last_tick = now_us();
+            let finished: Vec<(SocketAddr, u16)> = conns.iter()
+                                                        .filter(|&(_, shared)| {
+                                                            service(shared, None)
+                                                        })
+                                                        .map(|(key, _)| *key)
+                                                        .collect();
+            for key in finished {
+                conns.remove(&key);
+            }
+        }
+    }
+}
+
+// Accepts uTP connections on a UDP port
+pub struct UtpListener {
+    local_addr: SocketAddr,
+    incoming: Receiver<UtpStream>,
+    stopped: Arc<AtomicBool>,
+}
+
+impl UtpListener {
+    pub fn bind(addr: SocketAddr) -> io::Result<UtpListener> {
+        let socket = try!(UdpSocket::bind(addr));
+        try!(socket.set_read_timeout(Some(Duration::from_millis(TICK_MS))));
+        let local_addr = try!(socket.local_addr());
+
+        let (tx, rx) = mpsc::channel();
+        let stopped = Arc::new(AtomicBool::new(false));
+        let thread_stopped = stopped.clone();
+        thread::spawn(move || run_listener(socket, tx, thread_stopped));
+        Ok(UtpListener {
+            local_addr: local_addr,
+            incoming: rx,
+            stopped: stopped,
+        })
+    }
+
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    // Waits for the next connection
+    pub fn accept(&self) -> io::Result<UtpStream> {
+        self.incoming.recv().map_err(|_| {
+            io::Error::new(io::ErrorKind::BrokenPipe, "uTP listener stopped")
+        })
+    }
+}
+
+impl Drop for UtpListener {
+    fn drop(&mut self) {
+        self.stopped.store(true, Ordering::SeqCst);
+    }
+}
+
+pub struct UtpStream {
+    shared: Shared,
+    peer_addr: SocketAddr,
+    read_timeout: Option<Duration>,
+}
+
+impl UtpStream {
+    pub fn connect(addr: SocketAddr) -> io::Result<UtpStream> {
+        UtpStream::connect_timeout(addr, Duration::from_millis(CONNECT_TIMEOUT_MS))
+    }
+
+    // Like `connect`, but gives up if the peer hasn't answered by `timeout`
+    pub fn connect_timeout(addr: SocketAddr, timeout: Duration) -> io::Result<UtpStream> {
+        let bind_addr: SocketAddr = match addr {
+            SocketAddr::V4(_) => "0.0.0.0:0".parse().unwrap(),
+            SocketAddr::V6(_) => "[::]:0".parse().unwrap(),
+        };
+        let socket = try!(UdpSocket::bind(bind_addr));
+        try!(socket.set_read_timeout(Some(Duration::from_millis(TICK_MS))));
+        let background_socket = try!(socket.try_clone());
+
+        let shared: Shared = Arc::new((Mutex::new(Connection::new(socket, addr)),
+                                       Condvar::new()));
+        {
+            let mut conn = shared.0.lock().unwrap();
+            conn.send_reliable(ST_SYN, Vec::new());
+        }
+
+        let bg = shared.clone();
+        thread::spawn(move || run_background(bg, background_socket, addr));
+
+        let stream = UtpStream {
+            shared: shared,
+            peer_addr: addr,
+            read_timeout: None,
+        };
+
+        let deadline = now_us() + timeout.as_secs() * 1000000 +
+                       timeout.subsec_nanos() as u64 / 1000;
+        {
+            let &(ref lock, ref cvar) = &*stream.shared;
+            let mut conn = lock.lock().unwrap();
+            while conn.state == State::SynSent {
+                let now = now_us();
+                if now >= deadline {
+                    conn.state = State::Closed;
+                    return Err(io::Error::new(io::ErrorKind::TimedOut,
+                                              "uTP connection timed out"));
+                }
+                conn = cvar.wait_timeout(conn, Duration::from_millis(TICK_MS)).unwrap().0;
+            }
+            if let Some(kind) = conn.error {
+                return Err(io::Error::new(kind, "uTP connection failed"));
+            }
+        }
+
+        Ok(stream)
+    }
+
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+
+    pub fn set_read_timeout(&mut self, timeout: Option<Duration>) {
+        self.read_timeout = timeout;
+    }
+
+    // Sends a FIN, which the background thread keeps resending until it's
+    // acked, or resets a connection that never got going
+    fn close(&mut self) {
+        let mut conn = self.shared.0.lock().unwrap();
+        match conn.state {
+            State::Connected => {
+                conn.send_reliable(ST_FIN, Vec::new());
+                conn.state = State::FinSent;
+            },
+            State::SynSent => {
+                conn.send_reset();
+                conn.state = State::Closed;
+            },
+            _ => {},
+        }
+    }
+}
+
+impl Read for UtpStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let deadline = self.read_timeout.map(|t| {
+            now_us() + t.as_secs() * 1000000 + t.subsec_nanos() as u64 / 1000
+        });
+
+        let &(ref lock, ref cvar) = &*self.shared;
+        let mut conn = lock.lock().unwrap();
+        loop {
+            if !conn.incoming.is_empty() {
+                let n = cmp::min(buf.len(), conn.incoming.len());
+                for (dst, src) in buf.iter_mut().zip(conn.incoming.drain(..n)) {
+                    *dst = src;
+                }
+                // let the peer know our window opened back up
+                if conn.incoming.len() + n >= RECV_BUFFER_SIZE {
+                    conn.send_ack();
+                }
+                return Ok(n);
+            }
+            if conn.eof {
+                return Ok(0);
+            }
+            if let Some(kind) = conn.error {
+                return Err(io::Error::new(kind, "uTP connection failed"));
+            }
+            if conn.state == State::Closed {
+                return Ok(0);
+            }
+            if let Some(deadline) = deadline {
+                if now_us() >= deadline {
+                    return Err(io::Error::new(io::ErrorKind::TimedOut, "uTP read timed out"));
+                }
+            }
+            conn = cvar.wait_timeout(conn, Duration::from_millis(TICK_MS)).unwrap().0;
+        }
+    }
+}
+
+impl Write for UtpStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let &(ref lock, ref cvar) = &*self.shared;
+        let mut conn = lock.lock().unwrap();
+        loop {
+            if let Some(kind) = conn.error {
+                return Err(io::Error::new(kind, "uTP connection failed"));
+            }
+            if conn.state != State::Connected {
+                return Err(io::Error::new(io::ErrorKind::NotConnected, "uTP stream is closed"));
+            }
+
+            let allowance = conn.send_allowance();
+            if allowance > 0 {
+                let mut written = 0;
+                while written < buf.len() && written < allowance {
+                    let len = cmp::min(cmp::min(MAX_PAYLOAD, buf.len() - written),
+                                       allowance - written);
+                    conn.send_reliable(ST_DATA, buf[written..written + len].to_vec());
+                    written += len;
+                }
+                return Ok(written);
+            }
+
+            conn = cvar.wait_timeout(conn, Duration::from_millis(TICK_MS)).unwrap().0;
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Drop for UtpStream {
+    fn drop(&mut self) {
+        self.close();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{UtpListener, UtpStream};
+
+    use std::collections::VecDeque;
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, UdpSocket};
+    use std::thread;
+    use std::time::Duration;
+    use time;
+
+    // Relays packets between a client and `server`, dropping every
+    // `drop_every`th one (none if 0) and holding each for `delay_ms`.
+    // Returns the address for the client to connect to.
+    fn relay(server: SocketAddr, drop_every: usize, delay_ms: u64) -> SocketAddr {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(Duration::from_millis(1))).unwrap();
+        let addr = socket.local_addr().unwrap();
+        thread::spawn(move || {
+            let mut client = None;
+            let mut queue: VecDeque<(u64, SocketAddr, Vec<u8>)> = VecDeque::new();
+            let mut count = 0;
+            let mut buf = [0; 65536];
+            loop {
+                if let Ok((n, from)) = socket.recv_from(&mut buf) {
+                    let to = if from == server {
+                        client
+                    } else {
+                        client = Some(from);
+                        Some(server)
+                    };
+                    count += 1;
+                    if let Some(to) = to {
+                        if drop_every == 0 || count % drop_every != 0 {
+                            let at = time::precise_time_ns() + delay_ms * 1000000;
+                            queue.push_back((at, to, buf[..n].to_vec()));
+                        }
+                    }
+                }
+                let now = time::precise_time_ns();
+                while queue.front().map_or(false, |&(at, _, _)| at <= now) {
+                    let (_, to, packet) = queue.pop_front().unwrap();
+                    let _ = socket.send_to(&packet, to);
+                }
+            }
+        });
+        addr
+    }
+
+    // Sends `len` bytes one way through a relay and a reply the other,
+    // then checks the stream ends cleanly
+    fn transfer(drop_every: usize, delay_ms: u64, len: usize) {
+        let listener = UtpListener::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let relay = relay(listener.local_addr(), drop_every, delay_ms);
+
+        let data: Vec<u8> = (0..len).map(|i| (i * 7 % 251) as u8).collect();
+        let sent = data.clone();
+        let client = thread::spawn(move || {
+            let mut stream = UtpStream::connect(relay).unwrap();
+            stream.write_all(&sent).unwrap();
+            let mut reply = [0; 4];
+            stream.read_exact(&mut reply).unwrap();
+            reply
+        });
+
+        let mut stream = listener.accept().unwrap();
+        let mut received = vec![0; len];
+        stream.read_exact(&mut received).unwrap();
+        assert!(received == data);
+        stream.write_all(b"done").unwrap();
+
+        assert_eq!(&client.join().unwrap(), b"done");
+        assert_eq!(stream.read(&mut [0; 1]).unwrap(), 0);
+    }
+
+    #[test]
+    fn loopback() {
+        transfer(0, 0, 256 * 1024);
+    }
+
+    #[test]
+    fn lossy() {
+        transfer(10, 0, 64 * 1024);
+    }
+
+    #[test]
+    fn delayed() {
+        transfer(0, 50, 64 * 1024);
+    }
+
+    #[test]
+    fn lossy_and_delayed() {
+        transfer(10, 30, 64 * 1024);
+    }
+
+    #[test]
+    fn listener_closes_socket_when_dropped() {
+        let listener = UtpListener::bind("127.0.0.1:0".parse().unwrap()).unwrap();
+        let addr = listener.local_addr();
+        assert!(UdpSocket::bind(addr).is_err());
+        drop(listener);
+        thread::sleep(Duration::from_millis(200));
+        assert!(UdpSocket::bind(addr).is_ok());
+    }
+
+    #[test]
+    fn connect_timeout() {
+        // a socket that never answers the SYN
+        let silent = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let start = time::precise_time_ns();
+        let result = UtpStream::connect_timeout(silent.local_addr().unwrap(),
+                                                Duration::from_millis(200));
+        assert!(result.is_err());
+        assert!(time::precise_time_ns() - start < 1000000000);
+    }
+}
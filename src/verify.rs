@@ -0,0 +1,303 @@
+// Checks data already on disk against the torrent's piece hashes.
+
+use bitfield::Bitfield;
+use metainfo::{FileEntry, MetaInfo};
+use storage;
+
+use openssl::crypto::hash as openssl_hash;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
+
+// hashing threads used when the caller doesn't say
+pub const DEFAULT_THREADS: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum PieceStatus {
+    Valid,
+
+    // some of the piece's files are missing or too short
+    Missing,
+
+    // the data is all there but doesn't match the hash
+    Corrupt,
+}
+
+// Bytes of a file that belong to pieces that failed their hash check
+#[derive(Debug, Clone, PartialEq)]
+pub struct CorruptRange {
+    // index into `MetaInfo::files()`
+    pub file: usize,
+    pub offset: u64,
+    pub length: u64,
+}
+
+pub struct Recheck {
+    // pieces that passed
+    pub have: Bitfield,
+
+    pub missing: u32,
+    pub corrupt: Vec<CorruptRange>,
+}
+
+// Hashes every piece of the torrent, with its files at `paths` (in
+// `MetaInfo::files()` order), using `threads` threads. Files marked in
+// `skip` are read from `part_file` instead, the way `Storage` keeps them.
+// `progress` is called with (pieces checked, total pieces) as pieces finish.
+pub fn recheck(info: &MetaInfo, paths: &[PathBuf], skip: &[bool], part_file: &Path,
+               threads: usize, progress: &mut FnMut(u32, u32))
+        -> Result<Recheck, io::Error> {
+    let info = Arc::new(info.clone());
+    let num_pieces = info.num_pieces();
+    let next = Arc::new(AtomicUsize::new(0));
+    let (tx, rx) = mpsc::channel();
+
+    let threads = if threads == 0 { 1 } else { threads };
+    for _ in 0..threads {
+        let info = info.clone();
+        let paths = paths.to_vec();
+        let skip = skip.to_vec();
+        let part_file = part_file.to_path_buf();
+        let next = next.clone();
+        let tx = tx.clone();
+        thread::spawn(move || check_pieces(info, paths, skip, part_file, next, tx));
+    }
+    drop(tx);
+
+    let mut statuses = vec![PieceStatus::Missing; num_pieces as usize];
+    let mut done = 0;
+    for result in rx.iter() {
+        let (index, status) = try!(result);
+        statuses[index as usize] = status;
+        done += 1;
+        progress(done, num_pieces);
+    }
+    if done < num_pieces {
+        return Err(io::Error::new(io::ErrorKind::Other, "a hashing thread died"));
+    }
+
+    let mut have = Bitfield::new(num_pieces);
+    let mut missing = 0;
+    let mut corrupt: Vec<CorruptRange> = Vec::new();
+    for (index, status) in statuses.into_iter().enumerate() {
+        let index = index as u32;
+        match status {
+            PieceStatus::Valid => have.set(index),
+            PieceStatus::Missing => missing += 1,
+            PieceStatus::Corrupt => {
+                for slice in info.map_piece(index) {
+                    // pieces are in order, so a range can only grow at its end
+                    if let Some(last) = corrupt.last_mut() {
+                        if last.file == slice.file && last.offset + last.length == slice.offset {
+                            last.length += slice.length;
+                            continue;
+                        }
+                    }
+                    corrupt.push(CorruptRange {
+                        file: slice.file,
+                        offset: slice.offset,
+                        length: slice.length,
+                    });
+                }
+            },
+        }
+    }
+
+    Ok(Recheck {
+        have: have,
+        missing: missing,
+        corrupt: corrupt,
+    })
+}
+
+// The torrent's files under `root`, as they're named in the torrent
+pub fn file_paths(info: &MetaInfo, root: &Path) -> Vec<PathBuf> {
+    info.files().iter().map(|f| root.join(&f.path)).collect()
+}
+
+// Where the parts of skipped files that are needed are kept, under `root`
+pub fn part_file_path(info: &MetaInfo, root: &Path) -> PathBuf {
+    storage::part_file_path(info, root)
+}
+
+// Just the bitfield of pieces that are on disk and valid
+pub fn verify(info: &MetaInfo, root: &Path) -> Result<Bitfield, io::Error> {
+    recheck(info, &file_paths(info, root), &[], &part_file_path(info, root),
+            DEFAULT_THREADS, &mut |_, _| {}).map(|r| r.have)
+}
+
+// A hashing thread: takes pieces in turn until there are none left
+fn check_pieces(info: Arc<MetaInfo>, paths: Vec<PathBuf>, skip: Vec<bool>, part_file: PathBuf,
+                next: Arc<AtomicUsize>,
+                results: mpsc::Sender<Result<(u32, PieceStatus), io::Error>>) {
+    let skipped = |i: usize| skip.get(i).cloned().unwrap_or(false);
+    let mut files: Vec<Option<File>> = paths.iter().enumerate().map(|(i, p)| {
+        if skipped(i) { None } else { File::open(p).ok() }
+    }).collect();
+    let mut part = File::open(&part_file).ok();
+    let entries = info.files();
+    loop {
+        let index = next.fetch_add(1, Ordering::SeqCst) as u32;
+        if index >= info.num_pieces() {
+            return;
+        }
+
+        let result = read_piece(&info, &entries, &mut files, &skip, &mut part, index)
+                         .map(|data| {
+            match data {
+                Some(data) => {
+                    let hash = openssl_hash::hash(openssl_hash::Type::SHA1, &data[..]);
+                    if hash == info.info.pieces()[index as usize] {
+                        (index, PieceStatus::Valid)
+                    } else {
+                        (index, PieceStatus::Corrupt)
+                    }
+                },
+                None => (index, PieceStatus::Missing),
+            }
+        });
+
+        let failed = result.is_err();
+        if results.send(result).is_err() || failed {
+            return;
+        }
+    }
+}
+
+// The data for piece `index`, or None if any of it is missing. The parts in
+// skipped files come from the part file, at their offset in the torrent.
+fn read_piece(info: &MetaInfo, entries: &[FileEntry], files: &mut [Option<File>], skip: &[bool],
+              part: &mut Option<File>, index: u32) -> Result<Option<Vec<u8>>, io::Error> {
+    let skipped = |i: usize| skip.get(i).cloned().unwrap_or(false);
+    let slices = info.map_piece(index);
+    // the part file only has pieces that are partly in a file we want
+    if slices.iter().all(|s| skipped(s.file)) {
+        return Ok(None);
+    }
+
+    let mut data = Vec::with_capacity(info.piece_size(index) as usize);
+    for slice in slices {
+        let (file, offset) = if skipped(slice.file) {
+            (part.as_mut(), entries[slice.file].offset + slice.offset)
+        } else {
+            (files[slice.file].as_mut(), slice.offset)
+        };
+        let file = match file {
+            Some(file) => file,
+            None => return Ok(None),
+        };
+        try!(file.seek(SeekFrom::Start(offset)));
+        let n = try!(file.by_ref().take(slice.length).read_to_end(&mut data));
+        if (n as u64) < slice.length {
+            return Ok(None);
+        }
+    }
+    Ok(Some(data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{file_paths, part_file_path, recheck, CorruptRange};
+    use create::{self, TorrentOptions};
+    use metainfo::{self, MetaInfo};
+
+    use std::env;
+    use std::fs::{self, File, OpenOptions};
+    use std::io::{Seek, SeekFrom, Write};
+    use std::path::PathBuf;
+    use time;
+
+    // A torrent of two 10 byte files in 4 byte pieces, so that piece 2
+    // straddles them. Returns the directory the torrent's directory is in.
+    fn make_torrent(name: &str) -> (PathBuf, MetaInfo) {
+        let root = env::temp_dir().join(format!("deluge-{}-{}", name, time::precise_time_ns()));
+        let dir = root.join("t");
+        fs::create_dir_all(&dir).unwrap();
+        File::create(dir.join("a")).unwrap().write_all(b"0123456789").unwrap();
+        File::create(dir.join("b")).unwrap().write_all(b"abcdefghij").unwrap();
+
+        let mut options = TorrentOptions::new();
+        options.piece_length = 4;
+        options.trackers = vec![String::from("http://tracker.invalid/announce")];
+        let torrent = create::create_torrent(&dir, &options).unwrap();
+        (root, metainfo::from_bytes(&torrent).unwrap())
+    }
+
+    fn corrupt(root: &PathBuf, file: &str, offset: u64) {
+        let mut f = OpenOptions::new().write(true).open(root.join("t").join(file)).unwrap();
+        f.seek(SeekFrom::Start(offset)).unwrap();
+        f.write_all(b"X").unwrap();
+    }
+
+    fn range(file: usize, offset: u64, length: u64) -> CorruptRange {
+        CorruptRange { file: file, offset: offset, length: length }
+    }
+
+    #[test]
+    fn corrupt_ranges_are_merged() {
+        let (root, info) = make_torrent("recheck-corrupt");
+        // pieces 0, 1 and 2 are adjacent; piece 4 is on its own
+        corrupt(&root, "a", 1);
+        corrupt(&root, "a", 5);
+        corrupt(&root, "b", 0);
+        corrupt(&root, "b", 9);
+
+        let mut calls = 0;
+        let result = recheck(&info, &file_paths(&info, &root), &[], &part_file_path(&info, &root),
+                             2, &mut |done, total| { calls += 1; assert!(done <= total); })
+                         .unwrap();
+        assert_eq!(calls, 5);
+        assert_eq!(result.missing, 0);
+        assert_eq!((0..5).filter(|&i| result.have.has(i)).collect::<Vec<u32>>(), vec![3]);
+        assert_eq!(result.corrupt, vec![range(0, 0, 10), range(1, 0, 2), range(1, 6, 4)]);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn missing_files() {
+        let (root, info) = make_torrent("recheck-missing");
+        fs::remove_file(root.join("t").join("b")).unwrap();
+        corrupt(&root, "a", 0);
+
+        let result = recheck(&info, &file_paths(&info, &root), &[], &part_file_path(&info, &root),
+                             1, &mut |_, _| {}).unwrap();
+        assert_eq!(result.missing, 3);
+        assert_eq!((0..5).filter(|&i| result.have.has(i)).collect::<Vec<u32>>(), vec![1]);
+        assert_eq!(result.corrupt, vec![range(0, 0, 4)]);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn skipped_file_from_part_file() {
+        let (root, info) = make_torrent("recheck-parts");
+        // b is skipped, so all that's kept of it is its share of piece 2,
+        // at its offset in the torrent
+        fs::remove_file(root.join("t").join("b")).unwrap();
+        let part_file = part_file_path(&info, &root);
+        let mut f = File::create(&part_file).unwrap();
+        f.seek(SeekFrom::Start(10)).unwrap();
+        f.write_all(b"ab").unwrap();
+        drop(f);
+
+        let paths = file_paths(&info, &root);
+        let result = recheck(&info, &paths, &[false, true], &part_file, 1, &mut |_, _| {})
+                         .unwrap();
+        assert_eq!(result.missing, 2);
+        assert_eq!((0..5).filter(|&i| result.have.has(i)).collect::<Vec<u32>>(), vec![0, 1, 2]);
+        assert_eq!(result.corrupt, vec![]);
+
+        // without the part file, piece 2 is missing too
+        fs::remove_file(&part_file).unwrap();
+        let result = recheck(&info, &paths, &[false, true], &part_file, 1, &mut |_, _| {})
+                         .unwrap();
+        assert_eq!(result.missing, 3);
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,252 @@
+// Web seeding (BEP 19): downloading pieces from plain HTTP servers that
+// have a copy of the torrent's files, using Range requests.
+
+use metainfo::{FileEntry, Info, MetaInfo};
+use ratelimit::{Throttle, Throttled};
+
+use hyper::{self, Client};
+use hyper::header::{Connection, Headers};
+use hyper::status::StatusCode;
+use std::io::{self, Read};
+use time;
+use url::percent_encoding::{percent_encode, DEFAULT_ENCODE_SET};
+
+// first wait after a failure; doubled for each failure in a row
+const BACKOFF_SECS: i64 = 15;
+
+const MAX_BACKOFF_SECS: i64 = 60 * 60;
+
+// failures in a row before we stop using a seed
+pub const MAX_FAILURES: u32 = 8;
+
+#[derive(Debug)]
+pub enum WebSeedError {
+    HyperError(hyper::Error),
+    IoError(io::Error),
+
+    // the server answered, but not with what we asked for
+    HttpError(String),
+
+    // the server is busy and asked us to come back in this many seconds
+    RetryAfter(i64),
+
+    // the server ignores Range requests, so getting anything past the start
+    // of a file means downloading everything before it, for every piece
+    RangesUnsupported,
+}
+
+impl From<hyper::Error> for WebSeedError {
+    fn from(err: hyper::Error) -> WebSeedError {
+        WebSeedError::HyperError(err)
+    }
+}
+
+impl From<io::Error> for WebSeedError {
+    fn from(err: io::Error) -> WebSeedError {
+        WebSeedError::IoError(err)
+    }
+}
+
+// An HTTP server we download whole pieces from
+pub trait Seed: Send {
+    fn url(&self) -> &str;
+
+    fn backoff(&mut self) -> &mut Backoff;
+
+    // Downloads piece `index`, as fast as `throttle` allows. The caller
+    // checks the hash.
+    fn fetch_piece(&mut self, info: &MetaInfo, index: u32, throttle: &Throttle)
+                   -> Result<Vec<u8>, WebSeedError>;
+}
+
+// When a seed that's been failing may be tried again
+pub struct Backoff {
+    // failures since the last successful piece
+    failures: u32,
+
+    // time (seconds) before which we shouldn't use the seed again
+    retry_at: i64,
+}
+
+impl Backoff {
+    pub fn new() -> Backoff {
+        Backoff {
+            failures: 0,
+            retry_at: 0,
+        }
+    }
+
+    // seconds to wait before the next request, 0 if we can go ahead
+    pub fn wait_secs(&self) -> i64 {
+        let now = time::get_time().sec;
+        if self.retry_at > now { self.retry_at - now } else { 0 }
+    }
+
+    // whether the seed has failed too often to keep trying
+    pub fn is_dead(&self) -> bool {
+        self.failures >= MAX_FAILURES
+    }
+
+    pub fn failed(&mut self) {
+        self.failures += 1;
+        let mut backoff = BACKOFF_SECS;
+        for _ in 1..self.failures {
+            backoff *= 2;
+            if backoff >= MAX_BACKOFF_SECS {
+                backoff = MAX_BACKOFF_SECS;
+                break;
+            }
+        }
+        self.retry_at = time::get_time().sec + backoff;
+    }
+
+    pub fn succeeded(&mut self) {
+        self.failures = 0;
+        self.retry_at = 0;
+    }
+
+    // the server asked us to wait; this doesn't count as a failure
+    pub fn retry_in(&mut self, secs: i64) {
+        let secs = if secs > MAX_BACKOFF_SECS { MAX_BACKOFF_SECS } else { secs };
+        let secs = if secs < 0 { 0 } else { secs };
+        self.retry_at = time::get_time().sec + secs;
+    }
+}
+
+pub struct WebSeed {
+    url: String,
+    backoff: Backoff,
+}
+
+impl WebSeed {
+    pub fn new(url: String) -> WebSeed {
+        WebSeed {
+            url: url,
+            backoff: Backoff::new(),
+        }
+    }
+
+    // The URL of one of the torrent's files. For single-file torrents the
+    // URL names the file itself, unless it ends in '/'; for multi-file
+    // torrents it's the directory the torrent's directory is in.
+    pub fn file_url(&self, info: &MetaInfo, file: &FileEntry) -> String {
+        match info.info {
+            Info::SingleFile(_) if !self.url.ends_with('/') => self.url.clone(),
+            _ => {
+                let mut url = self.url.clone();
+                if !url.ends_with('/') {
+                    url.push('/');
+                }
+                let path: Vec<String> = file.components
+                                            .iter()
+                                            .map(|c| percent_encode(c.as_bytes(),
+                                                                    DEFAULT_ENCODE_SET))
+                                            .collect();
+                url.push_str(&path.connect("/"));
+                url
+            },
+        }
+    }
+}
+
+impl Seed for WebSeed {
+    fn url(&self) -> &str {
+        &self.url
+    }
+
+    fn backoff(&mut self) -> &mut Backoff {
+        &mut self.backoff
+    }
+
+    // one Range request per file the piece spans
+    fn fetch_piece(&mut self, info: &MetaInfo, index: u32, throttle: &Throttle)
+                   -> Result<Vec<u8>, WebSeedError> {
+        let files = info.files();
+        let mut data = Vec::with_capacity(info.piece_size(index) as usize);
+        for slice in info.map_piece(index) {
+            let url = self.file_url(info, &files[slice.file]);
+            let mut part = try!(get_range(&url, slice.offset, slice.length, throttle));
+            data.append(&mut part);
+        }
+        Ok(data)
+    }
+}
+
+fn get_range(url: &str, offset: u64, length: u64, throttle: &Throttle)
+             -> Result<Vec<u8>, WebSeedError> {
+    let mut headers = Headers::new();
+    headers.set_raw("Range",
+                    vec![format!("bytes={}-{}", offset, offset + length - 1).into_bytes()]);
+
+    let client = Client::new();
+    let res = try!(client.get(url)
+                         .headers(headers)
+                         .header(Connection::close())
+                         .send());
+
+    let status = res.status;
+    let mut res = Throttled::new(res, throttle.clone(), Throttle::unlimited());
+    let mut body = Vec::new();
+    match status {
+        StatusCode::PartialContent => {
+            try!(res.by_ref().take(length).read_to_end(&mut body));
+        },
+        // the server ignored the range and sent the whole file. That's what
+        // we wanted if the range starts at the beginning; otherwise the seed
+        // is no use to us.
+        StatusCode::Ok if offset == 0 => {
+            try!(res.by_ref().take(length).read_to_end(&mut body));
+        },
+        StatusCode::Ok => return Err(WebSeedError::RangesUnsupported),
+        status => return Err(WebSeedError::HttpError(format!("{} from {}", status, url))),
+    }
+
+    if body.len() as u64 != length {
+        return Err(WebSeedError::HttpError(format!("short response from {}: {} of {} bytes",
+                                                   url, body.len(), length)));
+    }
+    Ok(body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{get_range, WebSeedError};
+    use ratelimit::Throttle;
+    use util;
+
+    // The Range header of a request, if it had one
+    fn range(head: &[String]) -> Option<String> {
+        head.iter()
+            .find(|line| line.to_lowercase().starts_with("range:"))
+            .map(|line| line[6..].trim().to_string())
+    }
+
+    #[test]
+    fn partial_content() {
+        let (url, server) = util::serve_http("/file", vec![
+            "HTTP/1.1 206 Partial Content\r\nContent-Length: 3\r\n\r\n456"]);
+        let body = get_range(&url, 4, 3, &Throttle::unlimited()).unwrap();
+        assert_eq!(body, b"456".to_vec());
+        assert_eq!(range(&server.join().unwrap()[0]), Some("bytes=4-6".to_string()));
+    }
+
+    #[test]
+    fn whole_file_from_the_start() {
+        let (url, server) = util::serve_http("/file", vec![
+            "HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\n0123456789"]);
+        let body = get_range(&url, 0, 3, &Throttle::unlimited()).unwrap();
+        assert_eq!(body, b"012".to_vec());
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn whole_file_past_the_start() {
+        let (url, server) = util::serve_http("/file", vec![
+            "HTTP/1.1 200 OK\r\nContent-Length: 10\r\n\r\n0123456789"]);
+        match get_range(&url, 4, 3, &Throttle::unlimited()) {
+            Err(WebSeedError::RangesUnsupported) => {},
+            other => panic!("expected RangesUnsupported, got {:?}", other),
+        }
+        server.join().unwrap();
+    }
+}